@@ -96,13 +96,22 @@ expand_linked() {
     done
 }
 
+# routes to IPv6 destinations or over IPv6 gateways need -inet6,
+# `default` alone would be an IPv4 route
+route_family() {
+    case "$*" in
+        *:*) echo "-inet6" ;;
+        *) echo "-inet" ;;
+    esac
+}
+
 read_routes() {
     # we run the interface routes first
     while read route gw
     do
         if ifconfig "${gw}" 2> /dev/null
         then
-            /sbin/route add "${route}" -iface "${gw}"
+            /sbin/route add $(route_family "${route}") "${route}" -iface "${gw}"
             echo "route: $route"
             echo "gw: $gw"
         fi
@@ -113,8 +122,77 @@ read_routes() {
     do
         if ! ifconfig "${gw}" 2> /dev/null
         then
-            /sbin/route add "${route}" "${gw}"
+            /sbin/route add $(route_family "${route}" "${gw}") "${route}" "${gw}"
         fi
 
     done < "/config/routes"
 }
+
+# jail(8) parameters of the inner jail, one per line, written by vmadm on start
+read_jail_params() {
+    jail_params=""
+    if [ -f "/config/jail_params" ]
+    then
+        jail_params=$(cat "/config/jail_params")
+    fi
+}
+
+# mounts the entries of /config/filesystems below the inner jail root and
+# records them in /config/mounted for unmount_filesystems
+mount_filesystems() {
+    jail_root=$1
+    if [ ! -f "${jail_root}/root/config/filesystems" ]
+    then
+        return
+    fi
+    while read -r fstype source target options
+    do
+        case "${target}" in
+            */../*|*/..)
+                echo "mount point outside of the jail: ${target}"
+                continue
+                ;;
+        esac
+        mountpoint="${jail_root}/root/jail${target}"
+        # the jail could have replaced a part of the path with a symlink
+        # out of it, so nothing is created before the deepest existing
+        # parent is known to be inside the jail
+        parent="${mountpoint}"
+        while [ ! -e "${parent}" ] && [ ! -L "${parent}" ]
+        do
+            parent=$(dirname "${parent}")
+        done
+        case "$(realpath "${parent}" 2>/dev/null)" in
+            "${jail_root}/root/jail"|"${jail_root}/root/jail"/*) ;;
+            *)
+                echo "mount point outside of the jail: ${target}"
+                continue
+                ;;
+        esac
+        mkdir -p "${mountpoint}"
+        case "$(realpath "${mountpoint}" 2>/dev/null)" in
+            "${jail_root}/root/jail"/*) ;;
+            *)
+                echo "mount point outside of the jail: ${target}"
+                continue
+                ;;
+        esac
+        if mount -t "${fstype}" -o "${options}" "${source}" "${mountpoint}"
+        then
+            echo "${mountpoint}" >> "${jail_root}/root/config/mounted"
+        fi
+    done < "${jail_root}/root/config/filesystems"
+}
+
+# unmounts what mount_filesystems mounted, nested mounts first
+unmount_filesystems() {
+    jail_root=$1
+    if [ -f "${jail_root}/root/config/mounted" ]
+    then
+        for mountpoint in $(tail -r "${jail_root}/root/config/mounted")
+        do
+            umount "${mountpoint}"
+        done
+        rm "${jail_root}/root/config/mounted"
+    fi
+}
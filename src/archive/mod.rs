@@ -0,0 +1,244 @@
+//! Export archives to move a jail between hosts
+//!
+//! An archive is a single line of JSON, the `Header`, followed by a
+//! `zfs send` stream of the jail's root dataset.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde_json;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+use config::Config;
+use errors::{GenericError, ValidationErrors};
+use images;
+use images::Image;
+use jail_config::JailConfig;
+use jdb::{JDB, IdxEntry, JailState};
+use lock;
+use zfs;
+
+/// Version of the archive format written by this vmadm
+pub const VERSION: u32 = 1;
+
+/// Everything about a jail but its data
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    /// archive format version
+    pub version: u32,
+    /// pool the jail was exported from
+    pub pool: String,
+    /// name of the snapshot the stream was sent from
+    pub snapshot: String,
+    /// config of the jail
+    pub config: JailConfig,
+    /// index entry of the jail
+    pub entry: IdxEntry,
+    /// manifest of the image, if it was installed on the exporting host
+    pub image: Option<Image>,
+}
+
+/// Writes a jail to an archive at `path`, the root dataset is sent
+/// from a snapshot that is removed again afterwards. Jails with a
+/// delegated dataset can't be exported, it is not part of the stream.
+pub fn export(config: &Config, uuid: &Uuid, path: &str) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(config, uuid)?;
+    let db = JDB::open(config)?;
+    let jail = db.get(uuid)?;
+    if jail.config.delegate_dataset {
+        return Err(GenericError::bx(
+            "jails with a delegated dataset can't be exported",
+        ));
+    }
+    let image = images::installed(config)?.into_iter().find(
+        |i| i.uuid == jail.config.image_uuid,
+    );
+    if image.is_none() {
+        warn!("Image is not installed, exporting without its manifest";
+              "vm" => uuid.hyphenated().to_string(),
+              "image" => jail.config.image_uuid.hyphenated().to_string());
+    }
+    let name = format!("export-{}", config.req_id.simple());
+    let snapshot = zfs::snapshot(config, jail.idx.root.as_str(), name.as_str())?;
+    let header = Header {
+        version: VERSION,
+        pool: config.settings.pool.clone(),
+        snapshot: name,
+        config: jail.config.clone(),
+        entry: jail.idx.clone(),
+        image: image,
+    };
+    let res = write(config, Path::new(path), &header, snapshot.as_str());
+    if let Err(e) = zfs::destroy(config, snapshot.as_str()) {
+        warn!("Failed to remove export snapshot {}: {}", snapshot, e);
+    }
+    res
+}
+
+fn write(config: &Config, path: &Path, header: &Header, snapshot: &str) -> Result<i32, Box<Error>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // a failed export leaves no partial archive behind
+    let mut file = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut file, header)?;
+    file.write_all(b"\n")?;
+    zfs::send(config, snapshot, &mut file)?;
+    file.flush()?;
+    file.sync_all()?;
+    file.persist(path)?;
+    Ok(0)
+}
+
+/// Restores a jail from an archive, its root dataset is received into
+/// the pool of this host.
+pub fn import(config: &Config, path: &str) -> Result<i32, Box<Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = read_header(&mut reader)?;
+    let uuid = header.config.uuid;
+    // checks the nic_tags exist on this host as well
+    if let Some(errors) = header.config.errors(config) {
+        return Err(ValidationErrors::bx(errors));
+    }
+    match header.image {
+        Some(ref image) => {
+            if !images::installed(config)?.iter().any(|i| i.uuid == image.uuid) {
+                warn!("Image {} ({} {}) is not installed on this host",
+                      image.uuid, image.name, image.version);
+            }
+        }
+        None => warn!("Archive has no image manifest"),
+    }
+    let root = format!("{}/{}", config.settings.pool, uuid.hyphenated());
+    let _lock = lock::jail(config, &uuid)?;
+    if zfs::is_present(config, root.as_str()) {
+        return Err(GenericError::bx(
+            format!("dataset {} already exists", root).as_str(),
+        ));
+    }
+    info!("Importing jail {}", uuid; "from" => header.entry.root.clone(), "to" => root.clone());
+    JDB::open(config)?.insert(header.config.clone())?;
+    match receive(config, &mut reader, &header, root.as_str()) {
+        Ok(_) => {
+            let state = match header.entry.state {
+                JailState::Installed => JailState::Installed,
+                _ => JailState::Stopped,
+            };
+            JDB::open(config)?.set_state(&uuid, state)?;
+            Ok(0)
+        }
+        Err(e) => {
+            // like a failed create the entry is kept until deleted
+            JDB::open(config)?.set_state(&uuid, JailState::Failed)?;
+            Err(e)
+        }
+    }
+}
+
+fn receive<R: BufRead>(
+    config: &Config,
+    reader: &mut R,
+    header: &Header,
+    root: &str,
+) -> Result<i32, Box<Error>> {
+    zfs::receive(config, root, reader)?;
+    // properties are not part of the stream
+    zfs::quota(config, root, header.config.quota)?;
+    let props = header.config.zfs_properties();
+    if !props.is_empty() {
+        zfs::set(config, root, &props)?;
+    }
+    zfs::destroy(config, format!("{}@{}", root, header.snapshot).as_str())
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<Header, Box<Error>> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    match serde_json::from_slice::<Header>(&line) {
+        Ok(ref header) if header.version > VERSION => Err(GenericError::bx(
+            format!(
+                "archive version {} is newer than the supported version {}",
+                header.version,
+                VERSION
+            ).as_str(),
+        )),
+        Ok(header) => Ok(header),
+        Err(_) => Err(GenericError::bx("not a vmadm export archive")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use archive::*;
+    use config::TestConfig;
+    use executor::RecordingExecutor;
+    use jails;
+
+    static UUID: &'static str = "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53";
+
+    fn setup() -> (RecordingExecutor, TestConfig) {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec.clone()));
+        fs::create_dir_all(format!("/{}/{}/root", conf.settings.pool, UUID)).unwrap();
+        (rec, conf)
+    }
+
+    #[test]
+    fn export_import() {
+        let (rec, source) = setup();
+        let jail = JailConfig::from_reader(File::open("examples/example.json").unwrap()).unwrap();
+        jails::create(&source, jail).unwrap();
+        rec.clear();
+        rec.reply("zfs send", "STREAM");
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let path = format!("/{}/jail.export", source.settings.pool);
+        export(&source, &uuid, path.as_str()).unwrap();
+        let snapshot = format!("{}/{}@export-{}", source.settings.pool, UUID, source.req_id.simple());
+        assert!(rec.calls().contains(&format!("zfs send {}", snapshot)));
+        assert!(rec.calls().contains(&format!("zfs destroy {}", snapshot)));
+        let mut archive = String::new();
+        File::open(path.as_str()).unwrap().read_to_string(&mut archive).unwrap();
+        assert!(archive.ends_with("}\nSTREAM"));
+
+        let (rec, target) = setup();
+        import(&target, path.as_str()).unwrap();
+        let root = format!("{}/{}", target.settings.pool, UUID);
+        let calls = rec.calls();
+        assert!(calls.contains(&format!("zfs receive {}", root)));
+        assert!(calls.contains(&format!("zfs set quota=100G {}", root)));
+        assert!(calls.contains(&format!("zfs set compression=lz4 {}", root)));
+        assert!(calls.contains(&format!("zfs destroy {}@export-{}", root, source.req_id.simple())));
+        {
+            let db = JDB::open(&target).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(root, jail.idx.root);
+            assert_eq!(JailState::Installed, jail.idx.state);
+        }
+        // the jail exists now
+        assert!(import(&target, path.as_str()).is_err());
+
+        // the delegated dataset would be missing from the archive
+        let (rec, delegating) = setup();
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap()).unwrap();
+        jail.delegate_dataset = true;
+        jails::create(&delegating, jail).unwrap();
+        rec.clear();
+        let path = format!("/{}/jail.export", delegating.settings.pool);
+        assert!(export(&delegating, &uuid, path.as_str()).is_err());
+        assert!(!rec.calls().iter().any(|c| c.starts_with("zfs snapshot")));
+        let path = format!("/{}/jail.export", source.settings.pool);
+
+        let (_, mut untagged) = setup();
+        untagged.settings.networks = BTreeMap::new();
+        assert!(import(&untagged, path.as_str()).is_err());
+        assert!(JDB::open(&untagged).unwrap().get(&uuid).is_err());
+    }
+}
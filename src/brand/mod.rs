@@ -1,17 +1,22 @@
+//! Jail brands, the scripts that install, boot and halt a jail
+
 use std;
 use std::io::Read;
 use std::fs::File;
 use std::path::PathBuf;
-use std::process::{Command, Output};
+use std::process::{ExitStatus, Output};
 
 use toml;
 
 use jails::Jail;
 use config::Config;
 
+/// A single command of a brand
 #[derive(Debug, Deserialize)]
 pub struct Step {
+    /// Command to execute, can contain template parameters
     pub cmd: String,
+    /// Arguments to the command, can contain template parameters
     pub args: Vec<String>
 }
 fn expand(fmt: String, jail: &Jail, conf: &Config) -> String {
@@ -47,18 +52,7 @@ impl Step {
             |arg| expand(arg, jail, conf)
         ).collect()
     }
-    #[cfg(not(target_os = "freebsd"))]
-    pub fn output(&self, jail: &Jail, conf: &Config) -> Result<Output, std::io::Error> {
-        let command = self.cmd(jail, conf);
-        let args = self.args(jail, conf);
-        debug!("[BRAND] Running command";
-               "command" => command.clone(),
-               "args" => args.clone().join(" "),
-               "scope" => "brand",
-               "brand" => jail.config.brand.as_str());
-        Command::new("echo").args(args).output()
-    }
-    #[cfg(target_os = "freebsd")]
+    /// Runs the step for a jail and captures its output
     pub fn output(&self, jail: &Jail, conf: &Config) -> Result<Output, std::io::Error> {
         let command = self.cmd(jail, conf);
         let args = self.args(jail, conf);
@@ -67,22 +61,12 @@ impl Step {
                "args" => args.clone().join(" "),
                "scope" => "brand",
                "brand" => jail.config.brand.as_str());
-        Command::new(command).args(args).output()
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        conf.executor.output(command.as_str(), &args)
     }
 
-    #[cfg(not(target_os = "freebsd"))]
-    pub fn spawn(&self, jail: &Jail, conf: &Config) -> Result<std::process::Child, std::io::Error> {
-        let command = self.cmd(jail, conf);
-        let args = self.args(jail, conf);
-        debug!("[BRAND] Running command";
-               "command" => command.clone(),
-               "args" => args.clone().join(" "),
-               "scope" => "brand",
-               "brand" => jail.config.brand.as_str());
-        Command::new("echo").args(args).spawn()
-    }
-    #[cfg(target_os = "freebsd")]
-    pub fn spawn(&self, jail: &Jail, conf: &Config) -> Result<std::process::Child, std::io::Error> {
+    /// Runs the step for a jail attached to the current terminal
+    pub fn status(&self, jail: &Jail, conf: &Config) -> Result<ExitStatus, std::io::Error> {
         let command = self.cmd(jail, conf);
         let args = self.args(jail, conf);
         debug!("[BRAND] Running command";
@@ -90,8 +74,10 @@ impl Step {
                "args" => args.clone().join(" "),
                "scope" => "brand",
                "brand" => jail.config.brand.as_str());
-        Command::new(command).args(args).spawn()
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        conf.executor.status(command.as_str(), &args)
     }
+    /// Renders the step as a shell command line
     pub fn to_string(&self, jail: &Jail, conf: &Config) -> String {
         let mut cmd = self.cmd(jail, conf);
         cmd.push(' ');
@@ -101,14 +87,21 @@ impl Step {
         cmd
     }
 }
+/// A brand as described in its `config.toml`
 #[derive(Debug, Deserialize)]
 pub struct Brand {
     modname: String,
+    /// executed after cloning the image
     pub install: Step,
+    /// executed on the host before booting
     pub init: Step,
+    /// executed in the outer jail to boot the inner jail
     pub boot: Step,
+    /// executed on the host before the jail is torn down
     pub halt: Step,
+    /// executed on the host after the outer jail was stopped
     pub halted: Step,
+    /// executed to get a console in the jail
     pub login: Step,
 }
 
@@ -123,6 +116,7 @@ impl Brand {
         let result: Brand = toml::from_str(contents.as_str())?;
         Ok(result)
     }
+    /// Loads a brand by name from the brand directory
     pub fn load(brand: &str, conf: &Config) -> Result<Self, Box<std::error::Error>> {
         let mut brand_root = PathBuf::from(conf.settings.brand_dir.as_str());
         brand_root.push(brand);
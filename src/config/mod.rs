@@ -1,30 +1,56 @@
+//! vmadm configuration (`/usr/local/etc/vmadm.toml`)
+
 use std::io::Read;
 use std::error::Error;
 use std::fs::File;
 use std::collections::BTreeMap as Map;
+#[cfg(test)]
+use std::ops::{Deref, DerefMut};
 
 
 use toml;
+use uuid::Uuid;
 extern crate slog;
 
+use executor;
+use executor::{Executor, DryRunExecutor};
+
 static CONFIG: &'static str = "/usr/local/etc/vmadm.toml";
 
 /// Global settings
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Settings {
+    /// ZFS dataset images and jails live in
     pub pool: String,
 
+    /// URL of the image repository
     #[serde(default = "default_repo")]
     pub repo: String,
+    /// folder of the JDB
     #[serde(default = "default_conf_dir")]
     pub conf_dir: String,
+    /// folder of the image manifests
     #[serde(default = "default_image_dir")]
     pub image_dir: String,
+    /// folder of the brands
     #[serde(default = "default_brand_dir")]
     pub brand_dir: String,
+    /// folder for runtime files, like the sockets of the metadata agents
+    #[serde(default = "default_run_dir")]
+    pub run_dir: String,
+    /// devfs ruleset for jails
     #[serde(default = "devfs_ruleset")]
     pub devfs_ruleset: u32,
+    /// how addresses are probed on the network after they were checked
+    /// against the JDB
+    #[serde(default = "default_ip_probe")]
+    pub ip_probe: IpProbe,
+    /// jail(8) parameters of all jails, a jail's own `jail_params` and
+    /// toggles take precedence
+    #[serde(default = "default_jail_params")]
+    pub jail_params: Map<String, String>,
 
+    /// nic_tag to bridge mapping
     pub networks: Map<String, String>,
 }
 
@@ -32,10 +58,44 @@ fn devfs_ruleset() -> u32 {
     4
 }
 
+fn default_jail_params() -> Map<String, String> {
+    let mut params = Map::new();
+    params.insert(String::from("securelevel"), String::from("2"));
+    params.insert(String::from("sysvmsg"), String::from("new"));
+    params.insert(String::from("sysvsem"), String::from("new"));
+    params.insert(String::from("sysvshm"), String::from("new"));
+    params.insert(String::from("allow.raw_sockets"), String::from("true"));
+    params
+}
+
+/// Second stage of the ip address conflict check
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpProbe {
+    /// only the JDB is checked
+    None,
+    /// the address is taken if it answers a ping
+    Ping,
+    /// the address is taken if it resolves to a MAC, this finds hosts
+    /// that filter ICMP as well
+    Arp,
+}
+
+fn default_ip_probe() -> IpProbe {
+    IpProbe::None
+}
+
 /// Config object
 #[derive(Debug)]
 pub struct Config {
+    /// settings from the config file
     pub settings: Settings,
+    /// Runner for all host commands
+    pub executor: Box<Executor>,
+    /// Only print what would be done, see `Config::dry_run`
+    pub dry_run: bool,
+    /// ID of this vmadm invocation, logged and kept in the config history
+    pub req_id: Uuid,
 }
 
 fn default_conf_dir() -> String {
@@ -46,6 +106,10 @@ fn default_brand_dir() -> String {
     "/usr/local/lib/brand".to_string()
 }
 
+fn default_run_dir() -> String {
+    "/var/run/vmadm".to_string()
+}
+
 fn default_image_dir() -> String {
     "/var/imgadm/images".to_string()
 }
@@ -64,7 +128,89 @@ impl Config {
             "Failed to read config file.",
         );
         let settings: Settings = toml::from_str(contents.as_str())?;
-        Ok(Config { settings: settings })
+        Ok(Config {
+            settings: settings,
+            executor: executor::default(),
+            dry_run: false,
+            req_id: Uuid::new_v4(),
+        })
+    }
+    /// Switches into dry run mode, commands changing the system are
+    /// printed instead of executed and the JDB is not written to.
+    pub fn dry_run(&mut self) {
+        self.executor = Box::new(DryRunExecutor::new(executor::default()));
+        self.dry_run = true;
+    }
+}
+
+/// Config of a test, its folder is removed when it goes out of scope,
+/// also when an assertion failed
+#[cfg(test)]
+pub struct TestConfig {
+    config: Config,
+}
+
+#[cfg(test)]
+impl Deref for TestConfig {
+    type Target = Config;
+    fn deref(&self) -> &Config {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+impl DerefMut for TestConfig {
+    fn deref_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestConfig {
+    fn drop(&mut self) {
+        use std::fs;
+        let _ = fs::remove_dir_all(format!("/{}", self.config.settings.pool));
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Config for tests, all data lives in a fresh folder under the
+    /// temp dir and all commands are handed to `executor`.
+    pub fn test(executor: Box<Executor>) -> TestConfig {
+        use std::env;
+        use std::fs;
+        let mut root = env::temp_dir();
+        root.push(format!("vmadm-{}", Uuid::new_v4().simple()));
+        let mut conf_dir = root.clone();
+        conf_dir.push("etc");
+        fs::create_dir_all(&conf_dir).unwrap();
+        let mut image_dir = root.clone();
+        image_dir.push("images");
+        fs::create_dir_all(&image_dir).unwrap();
+        let mut run_dir = root.clone();
+        run_dir.push("run");
+        let mut networks = Map::new();
+        networks.insert(String::from("admin"), String::from("bridge0"));
+        let settings = Settings {
+            pool: root.to_string_lossy().trim_left_matches('/').to_string(),
+            repo: default_repo(),
+            conf_dir: conf_dir.to_string_lossy().into_owned(),
+            image_dir: image_dir.to_string_lossy().into_owned(),
+            run_dir: run_dir.to_string_lossy().into_owned(),
+            brand_dir: format!("{}/rel/pkgng/deploy/usr/local/lib/brand", env!("CARGO_MANIFEST_DIR")),
+            devfs_ruleset: devfs_ruleset(),
+            ip_probe: default_ip_probe(),
+            jail_params: default_jail_params(),
+            networks: networks,
+        };
+        TestConfig {
+            config: Config {
+                settings: settings,
+                executor: executor,
+                dry_run: false,
+                req_id: Uuid::new_v4(),
+            },
+        }
     }
-    // add code here
 }
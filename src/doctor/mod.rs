@@ -0,0 +1,479 @@
+//! Consistency checks between the JDB and the host
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use config::Config;
+use errors::GenericError;
+use jails;
+use jdb::JDB;
+use lock;
+use zfs;
+
+static RCTL: &'static str = "rctl";
+static JAIL: &'static str = "jail";
+static IFCONFIG: &'static str = "/sbin/ifconfig";
+static VNIC_DESC: &'static str = "VNic from jail ";
+
+/// An inconsistency found by `check`
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// index entry without a config file
+    MissingConfig(Uuid),
+    /// config file without an index entry
+    OrphanedConfig(Uuid),
+    /// jail in the index whose dataset is gone
+    MissingDataset(Uuid, String),
+    /// dataset that belongs to neither a jail nor an image
+    OrphanedDataset(String),
+    /// image snapshot for a jail that does not exist
+    OrphanedSnapshot(String),
+    /// running jail that is not in the index but has a dataset or
+    /// config file of vmadm
+    OrphanedJail(String),
+    /// rctl rules of a vmadm jail that is not running
+    LeakedRctl(String),
+    /// epair interface of a jail that is not running
+    LeakedEpair(String, String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::MissingConfig(ref uuid) => write!(f, "jail {} has no config file", uuid),
+            Problem::OrphanedConfig(ref uuid) => {
+                write!(f, "config file for {} is not in the index", uuid)
+            }
+            Problem::MissingDataset(ref uuid, ref root) => {
+                write!(f, "jail {} has no dataset {}", uuid, root)
+            }
+            Problem::OrphanedDataset(ref dataset) => {
+                write!(f, "dataset {} belongs to no jail or image", dataset)
+            }
+            Problem::OrphanedSnapshot(ref snap) => {
+                write!(f, "snapshot {} belongs to no jail", snap)
+            }
+            Problem::OrphanedJail(ref name) => write!(f, "running jail {} is not in the index", name),
+            Problem::LeakedRctl(ref name) => write!(f, "rctl rules for stopped jail {}", name),
+            Problem::LeakedEpair(ref iface, ref uuid) => {
+                write!(f, "interface {} of stopped jail {}", iface, uuid)
+            }
+        }
+    }
+}
+
+/// Compares the index, the config files, the datasets below the pool,
+/// the running jails, rctl rules and jail epairs.
+pub fn check(config: &Config) -> Result<Vec<Problem>, Box<Error>> {
+    let mut problems = Vec::new();
+    let db = JDB::open(config)?;
+    let conf_dir = PathBuf::from(config.settings.conf_dir.as_str());
+    let pool = config.settings.pool.as_str();
+
+    let indexed: HashSet<Uuid> = db.iter().map(|e| e.uuid).collect();
+    let configs = uuid_files(&conf_dir)?;
+    let images = uuid_files(Path::new(config.settings.image_dir.as_str()))?;
+    let datasets = zfs::children(config, pool)?;
+    let running: HashSet<String> = jails::list(config)?
+        .keys()
+        .filter_map(|name| name.split('.').next())
+        .map(String::from)
+        .collect();
+
+    for entry in db.iter() {
+        if !configs.contains(&entry.uuid) {
+            problems.push(Problem::MissingConfig(entry.uuid));
+        }
+        if !datasets.contains(&entry.root) {
+            problems.push(Problem::MissingDataset(entry.uuid, entry.root.clone()));
+        }
+    }
+    for uuid in configs.iter().filter(|u| !indexed.contains(u)) {
+        problems.push(Problem::OrphanedConfig(*uuid));
+    }
+    for dataset in datasets.iter() {
+        match dataset_uuid(pool, dataset) {
+            Some(uuid) if !indexed.contains(&uuid) && !images.contains(&uuid) => {
+                problems.push(Problem::OrphanedDataset(dataset.clone()))
+            }
+            _ => (),
+        }
+    }
+    for snap in zfs::snapshots(config, pool)? {
+        let mut parts = snap.splitn(2, '@');
+        let dataset = parts.next().unwrap_or("");
+        let name = parts.next().unwrap_or("");
+        if dataset_uuid(pool, dataset).is_none() {
+            continue;
+        }
+        match Uuid::parse_str(name) {
+            Ok(uuid) if !indexed.contains(&uuid) => problems.push(Problem::OrphanedSnapshot(snap.clone())),
+            _ => (),
+        }
+    }
+    // jails and rules that are not named after a jail vmadm knows of
+    // belong to someone else and are never touched
+    let owned = |name: &str| match Uuid::parse_str(name) {
+        Ok(uuid) => {
+            indexed.contains(&uuid) || configs.contains(&uuid) ||
+                datasets.contains(&format!("{}/{}", pool, uuid.hyphenated()))
+        }
+        Err(_) => false,
+    };
+    for name in running.iter() {
+        match Uuid::parse_str(name) {
+            Ok(uuid) if indexed.contains(&uuid) => (),
+            _ if owned(name) => problems.push(Problem::OrphanedJail(name.clone())),
+            _ => (),
+        }
+    }
+    for name in rctl_jails(config)? {
+        if !running.contains(&name) && owned(name.as_str()) {
+            problems.push(Problem::LeakedRctl(name));
+        }
+    }
+    for (iface, uuid) in vnics(config)? {
+        if !running.contains(&uuid) {
+            problems.push(Problem::LeakedEpair(iface, uuid));
+        }
+    }
+    Ok(problems)
+}
+
+/// Cleans up after a problem. Datasets are only destroyed when they are
+/// clones, orphaned configs are moved to `orphaned` in the config folder.
+pub fn fix(config: &Config, problem: &Problem) -> Result<i32, Box<Error>> {
+    match *problem {
+        Problem::MissingConfig(ref uuid) => {
+            let _lock = lock::jail(config, uuid)?;
+            let mut db = JDB::open(config)?;
+            db.remove(uuid)?;
+            Ok(0)
+        }
+        Problem::MissingDataset(ref uuid, ref root) => {
+            // a create in progress holds the lock until the clone exists
+            let _lock = lock::jail(config, uuid)?;
+            if zfs::is_present(config, root) {
+                return Err(GenericError::bx("dataset exists now"));
+            }
+            let mut db = JDB::open(config)?;
+            db.remove(uuid)?;
+            Ok(0)
+        }
+        Problem::OrphanedConfig(ref uuid) => {
+            let mut from = PathBuf::from(config.settings.conf_dir.as_str());
+            from.push(uuid.hyphenated().to_string());
+            from.set_extension("json");
+            let mut to = PathBuf::from(config.settings.conf_dir.as_str());
+            to.push("orphaned");
+            if config.dry_run {
+                println!("[dry-run] move {} to {}", from.display(), to.display());
+                return Ok(0);
+            }
+            fs::create_dir_all(&to)?;
+            to.push(uuid.hyphenated().to_string());
+            to.set_extension("json");
+            fs::rename(from, to)?;
+            Ok(0)
+        }
+        Problem::OrphanedDataset(ref dataset) => {
+            match zfs::origin(config, dataset) {
+                Ok(ref origin) if origin != "-" => zfs::destroy(config, dataset),
+                _ => Err(GenericError::bx("dataset is not a clone, not destroying it")),
+            }
+        }
+        Problem::OrphanedSnapshot(ref snap) => zfs::destroy(config, snap),
+        // a create or start in progress holds the lock, the problem is
+        // looked at again once it is done
+        Problem::OrphanedJail(ref name) => {
+            let uuid = Uuid::parse_str(name)?;
+            let _lock = lock::jail(config, &uuid)?;
+            if JDB::open(config)?.get(&uuid).is_ok() {
+                return Err(GenericError::bx("jail is in the index now"));
+            }
+            if !running(config, name)? {
+                return Err(GenericError::bx("jail is not running anymore"));
+            }
+            run(config, JAIL, &["-r", name.as_str()])
+        }
+        Problem::LeakedRctl(ref name) => {
+            let uuid = Uuid::parse_str(name)?;
+            let _lock = lock::jail(config, &uuid)?;
+            if running(config, name)? {
+                return Err(GenericError::bx("jail is running now"));
+            }
+            let rule = format!("jail:{}", name);
+            run(config, RCTL, &["-r", rule.as_str()])
+        }
+        Problem::LeakedEpair(ref iface, ref name) => {
+            let uuid = Uuid::parse_str(name)?;
+            let _lock = lock::jail(config, &uuid)?;
+            if running(config, name)? {
+                return Err(GenericError::bx("jail is running now"));
+            }
+            run(config, IFCONFIG, &[iface.as_str(), "destroy"])
+        }
+    }
+}
+
+/// Whether the jail `name` runs right now
+fn running(config: &Config, name: &str) -> Result<bool, Box<Error>> {
+    Ok(jails::list(config)?.contains_key(name))
+}
+
+/// Prints all problems and fixes them if asked to, the exit code is 1
+/// if problems remain.
+pub fn doctor(config: &Config, fix_problems: bool) -> Result<i32, Box<Error>> {
+    let problems = check(config)?;
+    let mut remaining = 0;
+    for problem in problems.iter() {
+        if !fix_problems {
+            println!("{}", problem);
+            remaining += 1;
+            continue;
+        }
+        match fix(config, problem) {
+            Ok(_) => println!("fixed: {}", problem),
+            Err(e) => {
+                warn!("Could not fix problem: {}", e; "problem" => problem.to_string());
+                println!("not fixed: {} ({})", problem, e);
+                remaining += 1;
+            }
+        }
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+    }
+    if remaining == 0 {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+fn run(config: &Config, cmd: &str, args: &[&str]) -> Result<i32, Box<Error>> {
+    let output = config.executor.output(cmd, args)?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx(String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// UUIDs of all `<uuid>.json` files in a folder
+fn uuid_files(dir: &Path) -> Result<HashSet<Uuid>, Box<Error>> {
+    let mut uuids = HashSet::new();
+    if !dir.exists() {
+        return Ok(uuids);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |e| e != "json") {
+            continue;
+        }
+        if let Some(uuid) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| {
+            Uuid::parse_str(s).ok()
+        })
+        {
+            uuids.insert(uuid);
+        }
+    }
+    Ok(uuids)
+}
+
+/// UUID of a dataset directly below the pool, vmadm names all its
+/// datasets after a jail or image.
+fn dataset_uuid(pool: &str, dataset: &str) -> Option<Uuid> {
+    let mut prefix = String::from(pool);
+    prefix.push('/');
+    if !dataset.starts_with(prefix.as_str()) {
+        return None;
+    }
+    Uuid::parse_str(&dataset[prefix.len()..]).ok()
+}
+
+/// Names of all jails with rctl rules
+fn rctl_jails(config: &Config) -> Result<HashSet<String>, Box<Error>> {
+    let output = config.executor.query(RCTL, &[])?;
+    let reply = String::from_utf8_lossy(&output.stdout);
+    Ok(
+        reply
+            .lines()
+            .filter(|l| l.starts_with("jail:"))
+            .filter_map(|l| l.split(':').nth(1))
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Interfaces with a jail VNic description and the jail they belong to
+fn vnics(config: &Config) -> Result<Vec<(String, String)>, Box<Error>> {
+    let output = config.executor.query(IFCONFIG, &["-a"])?;
+    let reply = String::from_utf8_lossy(&output.stdout);
+    let mut vnics = Vec::new();
+    let mut iface = None;
+    for line in reply.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            // renamed interfaces look like `j42:net0: flags=...`
+            iface = line.find(": flags=").map(|i| String::from(&line[..i]));
+        } else if let Some(desc) = line.trim().splitn(2, "description: ").nth(1) {
+            if let (Some(ref name), true) = (iface.clone(), desc.starts_with(VNIC_DESC)) {
+                vnics.push((name.clone(), String::from(&desc[VNIC_DESC.len()..])));
+            }
+        }
+    }
+    Ok(vnics)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+    use config::{Config, TestConfig};
+    use executor::RecordingExecutor;
+    use jail_config::JailConfig;
+    use jdb::JDB;
+    use doctor::*;
+
+    static JAIL: &'static str = "aaaaaaaa-0000-4000-8000-000000000000";
+    static STOPPED: &'static str = "bbbbbbbb-0000-4000-8000-000000000000";
+    static IMAGE: &'static str = "cccccccc-0000-4000-8000-000000000000";
+    static GONE: &'static str = "dddddddd-0000-4000-8000-000000000000";
+    static FOREIGN: &'static str = "eeeeeeee-0000-4000-8000-000000000000";
+    static UNKNOWN: &'static str = "ffffffff-0000-4000-8000-000000000000";
+
+    fn insert(conf: &Config, uuid: &str) {
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap())
+            .unwrap();
+        jail.uuid = Uuid::parse_str(uuid).unwrap();
+        JDB::open(conf).unwrap().insert(jail).unwrap();
+    }
+
+    fn setup() -> (RecordingExecutor, TestConfig) {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec.clone()));
+        insert(&conf, JAIL);
+        insert(&conf, STOPPED);
+        // a config without index entry
+        let mut orphan = PathBuf::from(conf.settings.conf_dir.as_str());
+        orphan.push(format!("{}.json", GONE));
+        fs::copy("examples/example.json", orphan).unwrap();
+        let mut image = PathBuf::from(conf.settings.image_dir.as_str());
+        image.push(format!("{}.json", IMAGE));
+        File::create(image).unwrap();
+        let pool = conf.settings.pool.clone();
+        rec.reply(
+            "zfs list -H -o name -d 1",
+            format!("{p}\n{p}/{}\n{p}/{}\n{p}/{}\n{p}/other\n", JAIL, IMAGE, GONE, p = pool)
+                .as_str(),
+        );
+        rec.reply(
+            "zfs list -H -o name -t snapshot",
+            format!("{p}/{i}@{}\n{p}/{i}@{}\n{p}/{i}@manual\n", JAIL, GONE, p = pool, i = IMAGE)
+                .as_str(),
+        );
+        rec.reply("zfs get -p -H origin", "x\torigin\tpool/image@snap\t-\n");
+        // GONE still runs, stray and FOREIGN are not vmadm's
+        rec.reply(
+            "jls",
+            format!("1 {j}\n2 {j}.{j}\n3 stray\n4 {}\n5 {}\n", GONE, FOREIGN, j = JAIL).as_str(),
+        );
+        rec.reply(
+            "rctl",
+            format!(
+                "jail:{}:memoryuse:deny=1024M\njail:{}:maxproc:deny=10\n\
+                 jail:other:maxproc:deny=10\njail:{}:maxproc:deny=10\n",
+                JAIL,
+                STOPPED,
+                UNKNOWN
+            ).as_str(),
+        );
+        rec.reply(
+            "/sbin/ifconfig -a",
+            format!(
+                "j1:net0: flags=8843<UP> metric 0 mtu 1500\n\tdescription: VNic from jail {}\n\
+                 epair5a: flags=8843<UP> metric 0 mtu 1500\n\tdescription: VNic from jail {}\n\
+                 em0: flags=8843<UP> metric 0 mtu 1500\n\tdescription: uplink\n",
+                JAIL,
+                STOPPED
+            ).as_str(),
+        );
+        (rec, conf)
+    }
+
+    #[test]
+    fn problems() {
+        let (_rec, conf) = setup();
+        let pool = conf.settings.pool.clone();
+        let stopped = Uuid::parse_str(STOPPED).unwrap();
+        assert_eq!(
+            vec![
+                Problem::MissingDataset(stopped, format!("{}/{}", pool, STOPPED)),
+                Problem::OrphanedConfig(Uuid::parse_str(GONE).unwrap()),
+                Problem::OrphanedDataset(format!("{}/{}", pool, GONE)),
+                Problem::OrphanedSnapshot(format!("{}/{}@{}", pool, IMAGE, GONE)),
+                Problem::OrphanedJail(String::from(GONE)),
+                Problem::LeakedRctl(String::from(STOPPED)),
+                Problem::LeakedEpair(String::from("epair5a"), String::from(STOPPED)),
+            ],
+            check(&conf).unwrap()
+        );
+    }
+
+    #[test]
+    fn fix_all() {
+        let (rec, conf) = setup();
+        let pool = conf.settings.pool.clone();
+        rec.clear();
+        assert_eq!(0, doctor(&conf, true).unwrap());
+        let calls = rec.calls();
+        for call in vec![
+            format!("zfs destroy {}/{}", pool, GONE),
+            format!("zfs destroy {}/{}@{}", pool, IMAGE, GONE),
+            format!("jail -r {}", GONE),
+            format!("rctl -r jail:{}", STOPPED),
+            String::from("/sbin/ifconfig epair5a destroy"),
+        ]
+        {
+            assert!(calls.contains(&call), "missing {}", call);
+        }
+        // foreign jails and rules are left alone
+        assert!(!calls.contains(&String::from("jail -r stray")));
+        assert!(!calls.contains(&format!("jail -r {}", FOREIGN)));
+        assert!(!calls.iter().any(|c| c.starts_with("rctl -r") && !c.contains(STOPPED)));
+        let db = JDB::open(&conf).unwrap();
+        assert!(db.get(&Uuid::parse_str(STOPPED).unwrap()).is_err());
+        let mut moved = PathBuf::from(conf.settings.conf_dir.as_str());
+        moved.push("orphaned");
+        moved.push(format!("{}.json", GONE));
+        assert!(moved.exists());
+    }
+
+    #[test]
+    fn started_meanwhile() {
+        let (rec, conf) = setup();
+        let problems = check(&conf).unwrap();
+        // STOPPED was started and GONE stopped after the check
+        rec.reply("jls", format!("1 {j}\n2 {j}.{j}\n6 {s}\n", j = JAIL, s = STOPPED).as_str());
+        rec.clear();
+        for problem in problems.iter() {
+            match *problem {
+                Problem::OrphanedJail(_) | Problem::LeakedRctl(_) | Problem::LeakedEpair(_, _) => {
+                    assert!(fix(&conf, problem).is_err(), "fixed {}", problem)
+                }
+                _ => (),
+            }
+        }
+        let calls = rec.calls();
+        assert!(!calls.iter().any(|c| c.starts_with("rctl -r")));
+        assert!(!calls.iter().any(|c| c.starts_with("jail -r")));
+        assert!(!calls.iter().any(|c| c.ends_with("destroy")));
+    }
+}
@@ -5,8 +5,9 @@ use std::fmt;
 use uuid::Uuid;
 
 
-/// Validation errors
-#[derive(Debug)]
+/// Validation errors, serialized as `{"errors": [...]}` for tools
+/// that want to show them per field
+#[derive(Debug, Serialize)]
 pub struct ValidationErrors {
     errors: Vec<ValidationError>,
 }
@@ -19,6 +20,10 @@ impl ValidationErrors {
    pub fn bx(errors: Vec<ValidationError>) -> Box<Error> {
        Box::new(ValidationErrors::new(errors))
    }
+    /// The individual errors
+    pub fn errors(&self) -> &[ValidationError] {
+        &self.errors
+    }
 }
 
 impl fmt::Display for ValidationErrors {
@@ -38,20 +43,48 @@ impl Error for ValidationErrors {
 
 
 /// Validation error for input validation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ValidationError {
+    /// path of the field in the payload, like `nics.0.ip`
     field: String,
+    /// machine readable kind of the error, see the `codes` module
+    code: &'static str,
+    /// human readable description
+    #[serde(rename = "message")]
     error: String,
 }
 
+/// Codes of validation errors
+pub mod codes {
+    /// the value can not be parsed or is out of range
+    pub const INVALID: &'static str = "invalid";
+    /// the value occurs more than once in the payload
+    pub const DUPLICATE: &'static str = "duplicate";
+    /// the value is used by another jail or host
+    pub const IN_USE: &'static str = "in_use";
+    /// the value refers to something that does not exist
+    pub const UNKNOWN: &'static str = "unknown";
+    /// the address can not be reached from the jail
+    pub const UNREACHABLE: &'static str = "unreachable";
+}
+
 impl ValidationError {
-    /// Initialize a new generic error
-    pub fn new(field: &str, error: &str) -> ValidationError {
+    /// Initialize a new validation error
+    pub fn new(field: &str, code: &'static str, error: &str) -> ValidationError {
         ValidationError {
             field: String::from(field),
+            code: code,
             error: String::from(error),
         }
     }
+    /// Path of the field the error is about
+    pub fn field(&self) -> &str {
+        self.field.as_str()
+    }
+    /// Code of the error
+    pub fn code(&self) -> &str {
+        self.code
+    }
     // /// Create a new error in a box
     // pub fn bx(field: &str, error: &str) -> Box<Error> {
     //     Box::new(ValidationError::new(field, error))
@@ -157,3 +190,42 @@ impl Error for NotFoundError {
         "Not Found"
     }
 }
+
+/// Error when a name does not resolve to exactly one jail
+#[derive(Debug)]
+pub struct LookupError {
+    name: String,
+    matches: Vec<Uuid>,
+}
+impl LookupError {
+    /// Initialize a new lookup error
+    pub fn new(name: &str, matches: Vec<Uuid>) -> LookupError {
+        LookupError {
+            name: String::from(name),
+            matches: matches,
+        }
+    }
+    /// Initialize a new lookup error in side a box
+    pub fn bx(name: &str, matches: Vec<Uuid>) -> Box<Error> {
+        Box::new(LookupError::new(name, matches))
+    }
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.matches.is_empty() {
+            return write!(f, "No jail with UUID, UUID prefix or alias: {}", self.name);
+        }
+        let mut r = write!(f, "Ambiguous jail name {}, it matches:", self.name);
+        for uuid in &self.matches {
+            r = write!(f, "\n  {}", uuid.hyphenated())
+        }
+        r
+    }
+}
+
+impl Error for LookupError {
+    fn description(&self) -> &str {
+        "Lookup"
+    }
+}
@@ -0,0 +1,323 @@
+//! Pluggable runner for host commands (zfs, jail, rctl, ifconfig, ...)
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io;
+use std::io::{Read, Write, copy};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::thread::JoinHandle;
+
+/// Executes commands on the host, every call to an external tool
+/// goes through an implementation of this trait.
+pub trait Executor: Debug {
+    /// Runs a command to completion and captures its output.
+    fn output(&self, cmd: &str, args: &[&str]) -> io::Result<Output>;
+    /// Runs a read only command that does not change the system
+    /// (`jls`, `zfs list`, ...) and captures its output.
+    fn query(&self, cmd: &str, args: &[&str]) -> io::Result<Output> {
+        self.output(cmd, args)
+    }
+    /// Runs a command to completion, feeding `input` into its stdin.
+    fn pipe(&self, cmd: &str, args: &[&str], input: &mut Read) -> io::Result<Output>;
+    /// Runs a command to completion, streaming its stdout into `output`.
+    fn stream(&self, cmd: &str, args: &[&str], output: &mut Write) -> io::Result<ExitStatus>;
+    /// Runs a command attached to the current terminal.
+    fn status(&self, cmd: &str, args: &[&str]) -> io::Result<ExitStatus>;
+}
+
+/// Executor that runs commands on the system
+#[derive(Debug, Clone)]
+pub struct SystemExecutor {}
+
+impl SystemExecutor {
+    /// Creates a new system executor
+    pub fn new() -> Self {
+        SystemExecutor {}
+    }
+}
+
+impl Executor for SystemExecutor {
+    fn output(&self, cmd: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(cmd).args(args).output()
+    }
+    fn pipe(&self, cmd: &str, args: &[&str], input: &mut Read) -> io::Result<Output> {
+        let mut child = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // the output is drained while the input is written, a child that
+        // fills the stderr pipe would otherwise never read more input
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+        {
+            let mut stdin = child.stdin.take().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "no stdin for child")
+            })?;
+            copy(input, &mut stdin)?;
+        }
+        let status = child.wait()?;
+        Ok(Output {
+            status,
+            stdout: collect(stdout)?,
+            stderr: collect(stderr)?,
+        })
+    }
+    fn stream(&self, cmd: &str, args: &[&str], output: &mut Write) -> io::Result<ExitStatus> {
+        let mut child = Command::new(cmd).args(args).stdout(Stdio::piped()).spawn()?;
+        {
+            let mut stdout = child.stdout.take().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "no stdout for child")
+            })?;
+            copy(&mut stdout, output)?;
+        }
+        child.wait()
+    }
+    fn status(&self, cmd: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        Command::new(cmd).args(args).status()
+    }
+}
+
+/// Reads a pipe of a child to its end on another thread
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    })
+}
+
+fn collect(reader: JoinHandle<io::Result<Vec<u8>>>) -> io::Result<Vec<u8>> {
+    reader.join().unwrap_or_else(|_| {
+        Err(io::Error::new(io::ErrorKind::Other, "output reader panicked"))
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Reply {
+    prefix: String,
+    success: bool,
+    stdout: String,
+}
+
+/// Executor that records all commands instead of running them, used
+/// for tests and the dummy mode on non FreeBSD systems.
+///
+/// Every command succeeds with an empty output unless a reply was
+/// registered for it. Clones share the same record so a test can
+/// keep one while the `Config` owns the other.
+#[derive(Debug, Clone)]
+pub struct RecordingExecutor {
+    calls: Rc<RefCell<Vec<String>>>,
+    replies: Rc<RefCell<Vec<Reply>>>,
+}
+
+impl RecordingExecutor {
+    /// Creates a new recording executor
+    pub fn new() -> Self {
+        RecordingExecutor {
+            calls: Rc::new(RefCell::new(Vec::new())),
+            replies: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+    /// Replies with `stdout` to every command line starting with `prefix`,
+    /// later replies take precedence over earlier ones.
+    pub fn reply(&self, prefix: &str, stdout: &str) {
+        self.replies.borrow_mut().insert(0, Reply {
+            prefix: String::from(prefix),
+            success: true,
+            stdout: String::from(stdout),
+        })
+    }
+    /// Fails every command line starting with `prefix`.
+    pub fn fail(&self, prefix: &str) {
+        self.replies.borrow_mut().insert(0, Reply {
+            prefix: String::from(prefix),
+            success: false,
+            stdout: String::new(),
+        })
+    }
+    /// All command lines recorded so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+    /// Forgets all recorded command lines.
+    pub fn clear(&self) {
+        self.calls.borrow_mut().clear()
+    }
+    fn record(&self, cmd: &str, args: &[&str]) -> Output {
+        let line = command_line(cmd, args);
+        debug!("Recording command"; "command" => line.clone());
+        let reply = self.replies
+            .borrow()
+            .iter()
+            .find(|r| line.starts_with(r.prefix.as_str()))
+            .cloned();
+        self.calls.borrow_mut().push(line);
+        match reply {
+            Some(Reply { success, stdout, .. }) => Output {
+                status: exit_status(success),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            },
+            None => Output {
+                status: exit_status(true),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            },
+        }
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn output(&self, cmd: &str, args: &[&str]) -> io::Result<Output> {
+        Ok(self.record(cmd, args))
+    }
+    fn pipe(&self, cmd: &str, args: &[&str], input: &mut Read) -> io::Result<Output> {
+        copy(input, &mut io::sink())?;
+        Ok(self.record(cmd, args))
+    }
+    fn stream(&self, cmd: &str, args: &[&str], output: &mut Write) -> io::Result<ExitStatus> {
+        let out = self.record(cmd, args);
+        output.write_all(&out.stdout)?;
+        Ok(out.status)
+    }
+    fn status(&self, cmd: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        Ok(self.record(cmd, args).status)
+    }
+}
+
+/// Executor for `--dry-run`, read only queries are passed on to the
+/// wrapped executor, everything else is printed instead of executed.
+#[derive(Debug)]
+pub struct DryRunExecutor {
+    inner: Box<Executor>,
+    plan: RecordingExecutor,
+}
+
+impl DryRunExecutor {
+    /// Creates a new dry run executor wrapping `inner`
+    pub fn new(inner: Box<Executor>) -> Self {
+        let plan = RecordingExecutor::new();
+        // Placeholders for commands whose output we need to carry on,
+        // they show up as such in the printed plan.
+        plan.reply("/sbin/ifconfig epair create", "epairNa\n");
+        plan.reply("jail -i", "0\n");
+        DryRunExecutor { inner, plan }
+    }
+    fn print(&self, cmd: &str, args: &[&str]) {
+        println!("[dry-run] {}", command_line(cmd, args));
+    }
+}
+
+impl Executor for DryRunExecutor {
+    fn output(&self, cmd: &str, args: &[&str]) -> io::Result<Output> {
+        self.print(cmd, args);
+        self.plan.output(cmd, args)
+    }
+    fn query(&self, cmd: &str, args: &[&str]) -> io::Result<Output> {
+        self.inner.query(cmd, args)
+    }
+    fn pipe(&self, cmd: &str, args: &[&str], _input: &mut Read) -> io::Result<Output> {
+        self.print(cmd, args);
+        self.plan.output(cmd, args)
+    }
+    fn stream(&self, cmd: &str, args: &[&str], _output: &mut Write) -> io::Result<ExitStatus> {
+        self.print(cmd, args);
+        self.plan.status(cmd, args)
+    }
+    fn status(&self, cmd: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        self.print(cmd, args);
+        self.plan.status(cmd, args)
+    }
+}
+
+fn command_line(cmd: &str, args: &[&str]) -> String {
+    let mut line = String::from(cmd);
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    line
+}
+
+fn exit_status(success: bool) -> ExitStatus {
+    if success {
+        ExitStatus::from_raw(0)
+    } else {
+        ExitStatus::from_raw(1 << 8)
+    }
+}
+
+/// The executor used when nothing else is requested, on FreeBSD this
+/// runs the commands, everywhere else it only records them.
+#[cfg(target_os = "freebsd")]
+pub fn default() -> Box<Executor> {
+    Box::new(SystemExecutor::new())
+}
+
+/// The executor used when nothing else is requested, on FreeBSD this
+/// runs the commands, everywhere else it only records them.
+#[cfg(not(target_os = "freebsd"))]
+pub fn default() -> Box<Executor> {
+    Box::new(RecordingExecutor::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::*;
+
+    #[test]
+    fn records_calls() {
+        let rec = RecordingExecutor::new();
+        let exec: Box<Executor> = Box::new(rec.clone());
+        exec.output("zfs", &["list", "zroot"]).unwrap();
+        exec.output("jls", &[]).unwrap();
+        assert_eq!(vec!["zfs list zroot", "jls"], rec.calls());
+    }
+
+    #[test]
+    fn replies() {
+        let rec = RecordingExecutor::new();
+        rec.reply("ifconfig epair", "epair3a\n");
+        rec.fail("ping");
+        let out = rec.output("ifconfig", &["epair", "create", "up"]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(b"epair3a\n".to_vec(), out.stdout);
+        assert!(!rec.output("ping", &["-o", "10.0.0.1"]).unwrap().status.success());
+        assert!(rec.output("zfs", &["list"]).unwrap().stdout.is_empty());
+        rec.reply("ifconfig epair", "epair4a\n");
+        let out = rec.output("ifconfig", &["epair", "create", "up"]).unwrap();
+        assert_eq!(b"epair4a\n".to_vec(), out.stdout);
+    }
+
+    #[test]
+    fn pipe_drains_output() {
+        // more stderr than a pipe buffer holds before the input is read
+        let script = "head -c 262144 /dev/zero >&2; wc -c";
+        let input = vec![0u8; 262144];
+        let out = SystemExecutor::new()
+            .pipe("sh", &["-c", script], &mut input.as_slice())
+            .unwrap();
+        assert!(out.status.success());
+        assert_eq!(262144, out.stderr.len());
+        assert_eq!("262144", String::from_utf8_lossy(&out.stdout).trim());
+    }
+
+    #[test]
+    fn dry_run_passes_queries() {
+        let rec = RecordingExecutor::new();
+        let exec = DryRunExecutor::new(Box::new(rec.clone()));
+        exec.query("jls", &["-q", "jid", "name"]).unwrap();
+        exec.output("zfs", &["destroy", "zroot/jails/a"]).unwrap();
+        let out = exec.output("/sbin/ifconfig", &["epair", "create", "up"]).unwrap();
+        assert_eq!(b"epairNa\n".to_vec(), out.stdout);
+        assert_eq!(vec!["jls -q jid name"], rec.calls());
+    }
+}
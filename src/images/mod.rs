@@ -1,4 +1,7 @@
 
+//! Images, ZFS datasets a jail is cloned from
+
+use std::io;
 use std::io::{Read, Seek, SeekFrom};
 use std::error::Error;
 use std::fs::{self, File};
@@ -38,32 +41,50 @@ impl ManifestWrapper {
     }
 }
 
+/// File of an image
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct ImageFile {
-    size: u64,
-    compression: String,
-    sha1: String,
+pub struct ImageFile {
+    /// size in bytes
+    pub size: u64,
+    /// compression of the file (bzip2 or gzip)
+    pub compression: String,
+    /// sha1 checksum of the file
+    pub sha1: String,
 }
 
+/// Image manifest
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct Image {
-    v: u32,
-    uuid: Uuid,
-    name: String,
-    version: String,
+pub struct Image {
+    /// manifest version
+    pub v: u32,
+    /// UUID of the image
+    pub uuid: Uuid,
+    /// name of the image
+    pub name: String,
+    /// version of the image
+    pub version: String,
+    /// type of the image
     #[serde(rename = "type")]
-    image_type: String,
-    os: String,
-    origin: Option<Uuid>,
+    pub image_type: String,
+    /// OS of the image
+    pub os: String,
+    /// image this one is based on
+    pub origin: Option<Uuid>,
+    /// files of the image
     #[serde(default = "empty_files")]
-    files: Vec<ImageFile>,
-    published_at: Option<DateTime<Utc>>,
-    public: bool,
-    state: String,
-    disabled: bool,
+    pub files: Vec<ImageFile>,
+    /// date the image was published
+    pub published_at: Option<DateTime<Utc>>,
+    /// if the image is public
+    pub public: bool,
+    /// state of the image
+    pub state: String,
+    /// if the image is disabled
+    pub disabled: bool,
 }
 
 impl Image {
+    /// Reads a manifest from a reader
     pub fn from_reader<R>(reader: R) -> Result<Self, Box<Error>>
     where
         R: Read,
@@ -72,6 +93,7 @@ impl Image {
         return Ok(image);
     }
 
+    /// Reads a list of manifests from a reader
     pub fn list_from_reader<R>(reader: R) -> Result<Vec<Self>, Box<Error>>
     where
         R: Read,
@@ -136,7 +158,8 @@ fn print_images(images: Vec<Image>, headerless: bool, parsable: bool) {
     };
 
 }
-pub fn list(config: &Config) -> Result<i32, Box<Error>> {
+/// Reads the manifests of all installed images
+pub fn installed(config: &Config) -> Result<Vec<Image>, Box<Error>> {
     let mut images: Vec<Image> = Vec::new();
     for entry in fs::read_dir(config.settings.image_dir.clone())? {
         let entry = entry?;
@@ -144,17 +167,40 @@ pub fn list(config: &Config) -> Result<i32, Box<Error>> {
         let manifest = ManifestWrapper::from_reader(image_file)?;
         images.push(manifest.manifest);
     }
-    print_images(images, false, false);
-    Ok(0)
+    Ok(images)
 }
-pub fn avail(config: &Config) -> Result<i32, Box<Error>> {
+
+/// Fetches the manifests of all images in the repository
+pub fn available(config: &Config) -> Result<Vec<Image>, Box<Error>> {
     debug!("Listing images"; "repo" => config.settings.repo.clone());
     let resp = reqwest::get(config.settings.repo.as_str())?;
-    let images = Image::list_from_reader(resp)?;
-    print_images(images, false, false);
+    Image::list_from_reader(resp)
+}
+
+/// Prints all installed images, as a JSON array if `json` is set
+pub fn list(config: &Config, json: bool) -> Result<i32, Box<Error>> {
+    let images = installed(config)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&images)?);
+    } else {
+        print_images(images, false, false);
+    }
     Ok(0)
 }
 
+/// Prints all images in the repository, as a JSON array if `json`
+/// is set
+pub fn avail(config: &Config, json: bool) -> Result<i32, Box<Error>> {
+    let images = available(config)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&images)?);
+    } else {
+        print_images(images, false, false);
+    }
+    Ok(0)
+}
+
+/// Prints the manifest of an installed image
 pub fn get(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     let mut file_name = config.settings.image_dir.clone();
     let uuid_str = uuid.hyphenated().to_string();
@@ -171,6 +217,7 @@ pub fn get(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     Ok(0)
 }
 
+/// Prints the manifest of an image in the repository
 pub fn show(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     let mut url = config.settings.repo.clone();
     let uuid_str = uuid.hyphenated().to_string();
@@ -187,6 +234,7 @@ pub fn show(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
 }
 
 
+/// Imports an image, and its origins, from the repository
 pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
 
     let mut url = config.settings.repo.clone();
@@ -197,7 +245,7 @@ pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     url.push('/');
     url.push_str(uuid_str.as_str());
 
-    if zfs::is_present(dataset.as_str()) {
+    if zfs::is_present(config, dataset.as_str()) {
         println!("Image {} already imported.", uuid_str);
         return Ok(0);
     };
@@ -213,13 +261,18 @@ pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
             let mut origin_dataset = config.settings.pool.clone();
             origin_dataset.push('/');
             origin_dataset.push_str(origin.hyphenated().to_string().as_str());
-            if ! zfs::is_present(origin_dataset.as_str()) {
+            if ! zfs::is_present(config, origin_dataset.as_str()) {
                 import(config, origin)?;
             }
         }
     };
     let file_info = image.files[0].clone();
     url.push_str("/file");
+    if config.dry_run {
+        println!("[dry-run] download {}", url);
+        zfs::receive(config, dataset.as_str(), &mut io::empty())?;
+        return Ok(0);
+    }
     let mut out: File = tempfile::tempfile()?;
     let mut resp = reqwest::get(url.as_str())?;
     println!("Importing {} ...", uuid_str.as_str());
@@ -229,11 +282,11 @@ pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     match file_info.compression.as_str() {
         "bzip2" => {
             let mut decompressor = BzDecoder::new(out);
-            zfs::receive(dataset.as_str(), &mut decompressor)?;
+            zfs::receive(config, dataset.as_str(), &mut decompressor)?;
         }
         "gzip" => {
             let mut decompressor = GzDecoder::new(out)?;
-            zfs::receive(dataset.as_str(), &mut decompressor)?;
+            zfs::receive(config, dataset.as_str(), &mut decompressor)?;
         }
         compression => {
             println!("Encountered {} compression", compression);
@@ -3,12 +3,13 @@
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use std::process::Command;
-#[cfg(target_os = "freebsd")]
-use errors::GenericError;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use errors::ValidationError;
-use config::Config;
+use errors::{codes, GenericError, ValidationError};
+use config::{Config, IpProbe};
+use jdb::JDB;
+use metadata;
 
 use serde_json;
 use uuid::Uuid;
@@ -30,12 +31,32 @@ pub struct NIC {
     pub vlan: Option<u16>,
     /// The nic_tag for the nic to uise
     pub nic_tag: String,
-    /// The IP for the nic
+    /// The IP for the nic, can be left out when `ips` is used
+    #[serde(default = "empty_string", skip_serializing_if = "String::is_empty")]
     pub ip: String,
     /// The netmask for the nic
+    #[serde(default = "empty_string", skip_serializing_if = "String::is_empty")]
     pub netmask: String,
     /// The gateway for the nic
+    #[serde(default = "empty_string", skip_serializing_if = "String::is_empty")]
     pub gateway: String,
+    /// Addresses of the nic in SmartOS form, `10.0.0.5/24`, `fd00::5/64`,
+    /// `dhcp` or `addrconf`, added after the legacy `ip`
+    #[serde(default = "empty_svec", skip_serializing_if = "Vec::is_empty")]
+    pub ips: Vec<String>,
+    /// Gateways of the nic, IPv4 and IPv6
+    #[serde(default = "empty_svec", skip_serializing_if = "Vec::is_empty")]
+    pub gateways: Vec<String>,
+    /// IPv6 address with prefix length, `2001:db8::2/64`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<String>,
+    /// The IPv6 gateway for the nic, link local gateways need a scope,
+    /// `fe80::1%net0`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_gateway: Option<String>,
+    /// Configure IPv6 addresses from router advertisements (SLAAC)
+    #[serde(default = "dflt_false")]
+    pub accept_rtadv: bool,
     #[serde(default = "dflt_false")]
     /// If this nic is the primary interface or not
     pub primary: bool,
@@ -53,17 +74,57 @@ impl PartialEq for NIC {
             self.mac == other.mac &&
             self.vlan == other.vlan &&
             self.nic_tag == other.nic_tag &&
-            self.ip == self.ip &&
+            self.ip == other.ip &&
             self.netmask == other.netmask &&
             self.gateway == other.gateway &&
+            self.ips == other.ips &&
+            self.gateways == other.gateways &&
+            self.ipv6 == other.ipv6 &&
+            self.ipv6_gateway == other.ipv6_gateway &&
+            self.accept_rtadv == other.accept_rtadv &&
             self.primary == other.primary &&
             self.mtu == other.mtu &&
             self.network_uuid == other.network_uuid
     }
 }
 
-#[cfg(target_os = "freebsd")]
+/// Host directory or tmpfs mounted into the jail before it boots
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Filesystem {
+    /// Directory on the host, not used for tmpfs
+    #[serde(default = "empty_string", skip_serializing_if = "String::is_empty")]
+    pub source: String,
+    /// Absolute path inside the jail
+    pub target: String,
+    /// `nullfs` or `tmpfs`
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    /// Mount options, like `ro`
+    #[serde(default = "empty_svec", skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl Filesystem {
+    /// Line for the `filesystems` file the brand's init reads:
+    /// type, source, target and options
+    pub fn mount_line(&self) -> String {
+        let source = if self.fs_type == "tmpfs" {
+            "tmpfs"
+        } else {
+            self.source.as_str()
+        };
+        let options = if self.options.is_empty() {
+            String::from("rw")
+        } else {
+            self.options.join(",")
+        };
+        format!("{}\t{}\t{}\t{}", self.fs_type, source, self.target, options)
+    }
+}
+
 static IFCONFIG: &'static str = "/sbin/ifconfig";
+static PING: &'static str = "ping";
+static ARP: &'static str = "arp";
 
 /// Interface after creating
 #[derive(Debug, Clone)]
@@ -78,12 +139,8 @@ pub struct IFace {
 
 impl NIC {
     /// Creates the related interface
-    #[cfg(target_os = "freebsd")]
     pub fn get_iface(&self, config: &Config, uuid: &Uuid) -> Result<IFace, Box<Error>> {
-        let output = Command::new(IFCONFIG)
-            .args(&["epair", "create", "up"])
-            .output()
-            .expect("failed ifconfig");
+        let output = config.executor.output(IFCONFIG, &["epair", "create", "up"])?;
         if !output.status.success() {
             return Err(GenericError::bx("could not create interface"));
         }
@@ -95,10 +152,7 @@ impl NIC {
         match config.settings.networks.get(&self.nic_tag) {
             Some(bridge) => {
 
-                let output = Command::new(IFCONFIG)
-                    .args(&[bridge.as_str(), "addm", epaira])
-                    .output()
-                    .expect("failed ifconfig");
+                let output = config.executor.output(IFCONFIG, &[bridge.as_str(), "addm", epaira])?;
 
                 if !output.status.success() {
                     return Err(GenericError::bx("could not add epair to bridge"));
@@ -107,36 +161,29 @@ impl NIC {
             None => return Err(GenericError::bx("bridge not configured")),
         }
 
-        let mut script = if self.vlan.is_some() {
+        let script = if self.vlan.is_some() {
             // This may seem stupid but freebsd can't create a vlan interface
             // that is not named vlan<X> or <interface>.<X>
             // however once created it happiely renames it ...
             format!(
                 "/sbin/ifconfig {epair}b.{vlan} create vlan {vlan} vlandev {epair}p; \
-                /sbin/ifconfig {epair}b.{vlan} name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} netmask {mask}; ",
+                /sbin/ifconfig {epair}b.{vlan} name {iface}; {inet}",
                 epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
+                inet = self.inet_script(),
                 iface = self.interface,
                 vlan = self.vlan.unwrap()
             )
         } else {
             format!(
-                "/sbin/ifconfig {epair}b name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} netmask {mask}; ",
+                "/sbin/ifconfig {epair}b name {iface}; {inet}",
                 epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
+                inet = self.inet_script(),
                 iface = self.interface
             )
         };
         let mut desc = String::from("VNic from jail ");
         desc.push_str(uuid.hyphenated().to_string().as_str());
-        let output = Command::new(IFCONFIG)
-            .args(&[epaira, "description", desc.as_str()])
-            .output()
-            .expect("failed to add descirption");
+        let output = config.executor.output(IFCONFIG, &[epaira, "description", desc.as_str()])?;
         if !output.status.success() {
             return Err(GenericError::bx("could not set description"));
         }
@@ -146,41 +193,112 @@ impl NIC {
             start_script: script,
         })
     }
-    /// Creates the related interface
-    #[cfg(not(target_os = "freebsd"))]
-    pub fn get_iface(&self, _config: &Config, _uuid: &Uuid) -> Result<IFace, Box<Error>> {
-        let epair = "epair0";
-        let script = if self.vlan.is_some() {
-            // This may seem stupid but freebsd can't create a vlan interface
-            // that is not named vlan<X> or <interface>.<X>
-            // however once created it happiely renames it ...
-            format!(
-                "/sbin/ifconfig {epair}b name {iface}p; \
-                /sbin/ifconfig {iface}p.{vlan} create vlan {vlan} vlandev {iface}p; \
-                /sbin/ifconfig {iface}p.{vlan} name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} netmask {mask}; ",
-                epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
-                iface = self.interface,
-                vlan = self.vlan.unwrap()
-            )
-        } else {
-            format!(
-                "/sbin/ifconfig {epair}b name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} netmask {mask}; ",
-                epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
-                iface = self.interface
-            )
-        };
 
-        Ok(IFace {
-            iface: self.interface.clone(),
-            epair: String::from(epair),
-            start_script: script,
-        })
+    /// If the nic is configured for IPv6
+    pub fn has_ipv6(&self) -> bool {
+        self.ipv6.is_some() || self.slaac() || self.ips.iter().any(|ip| ip.contains(':'))
+    }
+
+    /// If the nic takes IPv6 addresses from router advertisements
+    pub fn slaac(&self) -> bool {
+        self.accept_rtadv || self.ips.iter().any(|ip| ip == "addrconf")
+    }
+
+    /// If the nic is configured by DHCP
+    pub fn dhcp(&self) -> bool {
+        self.ips.iter().any(|ip| ip == "dhcp")
+    }
+
+    /// All gateways of the nic, the legacy ones first
+    pub fn all_gateways(&self) -> Vec<String> {
+        self.named_gateways()
+            .into_iter()
+            .map(|(_, gateway)| String::from(gateway))
+            .collect()
+    }
+
+    /// The gateways of the nic with the fields they are set in
+    fn named_gateways(&self) -> Vec<(String, &str)> {
+        let mut gateways = Vec::new();
+        if !self.gateway.is_empty() {
+            gateways.push((String::from("gateway"), self.gateway.as_str()));
+        }
+        if let Some(ref gateway) = self.ipv6_gateway {
+            gateways.push((String::from("ipv6_gateway"), gateway.as_str()));
+        }
+        for (i, gateway) in self.gateways.iter().enumerate() {
+            gateways.push((format!("gateways.{}", i), gateway.as_str()));
+        }
+        gateways
+    }
+
+    /// Addresses the nic, once it was renamed inside the jail
+    fn inet_script(&self) -> String {
+        let mut script = String::new();
+        let mut inet = Vec::new();
+        let mut inet6 = Vec::new();
+        if !self.ip.is_empty() {
+            script.push_str(
+                format!(
+                    "/sbin/ifconfig {iface} inet {ip} netmask {mask}; ",
+                    ip = self.ip,
+                    mask = self.netmask,
+                    iface = self.interface
+                ).as_str(),
+            );
+            inet.push(self.ip.as_str());
+        }
+        if let Some(ref ipv6) = self.ipv6 {
+            inet6.push(ipv6.as_str());
+        }
+        for ip in self.ips.iter().filter(|ip| *ip != "dhcp" && *ip != "addrconf") {
+            if ip.contains(':') {
+                inet6.push(ip.as_str());
+            } else {
+                // the first address replaces, all others are aliases
+                let alias = if inet.is_empty() { "" } else { " alias" };
+                script.push_str(
+                    format!("/sbin/ifconfig {} inet {}{}; ", self.interface, ip, alias).as_str(),
+                );
+                inet.push(ip.as_str());
+            }
+        }
+        if self.has_ipv6() {
+            // IPv6 is disabled on new interfaces unless the host
+            // enables it everywhere
+            script.push_str(
+                format!("/sbin/ifconfig {} inet6 -ifdisabled; ", self.interface).as_str(),
+            );
+        }
+        for (i, ip) in inet6.iter().enumerate() {
+            let mut parts = ip.splitn(2, '/');
+            let addr = parts.next().unwrap_or("");
+            let prefixlen = parts.next().unwrap_or("64");
+            let alias = if i == 0 { "" } else { " alias" };
+            script.push_str(
+                format!(
+                    "/sbin/ifconfig {} inet6 {} prefixlen {}{}; ",
+                    self.interface,
+                    addr,
+                    prefixlen,
+                    alias
+                ).as_str(),
+            );
+        }
+        if self.slaac() {
+            script.push_str(
+                format!(
+                    "/sbin/ifconfig {iface} inet6 accept_rtadv; /sbin/rtsol {iface}; ",
+                    iface = self.interface
+                ).as_str(),
+            );
+        }
+        if self.dhcp() {
+            // in the background so a missing DHCP server does not
+            // hold up the start
+            script.push_str(format!("/sbin/dhclient -b {}; ", self.interface).as_str());
+        }
+        script
     }
 }
 
@@ -200,6 +318,7 @@ pub struct JailConfig {
     /// hostname of the jail
     pub hostname: String,
 
+    /// DNS resolvers of the jail
     #[serde(default = "empty_resolvers")]
     pub resolvers: Vec<String>,
     /// weather to start this jail on --startup
@@ -213,6 +332,25 @@ pub struct JailConfig {
     pub cpu_cap: u64,
     /// max quota (zfs quota)
     pub quota: u64,
+    /// compression of the jail's dataset (zfs compression), new jails
+    /// default to `lz4`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zfs_root_compression: Option<String>,
+    /// record size of the jail's dataset in bytes (zfs recordsize)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zfs_root_recordsize: Option<u64>,
+    /// update access times (zfs atime)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub atime: Option<bool>,
+    /// guaranteed space in GB including snapshots (zfs reservation)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reservation: Option<u64>,
+    /// guaranteed space in GB (zfs refreservation)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refreservation: Option<u64>,
+    /// quota in GB not counting snapshots (zfs refquota)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refquota: Option<u64>,
 
     /// SysV shared memory size, in bytes (shmsize)
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -230,6 +368,40 @@ pub struct JailConfig {
     #[serde(default = "dflt_max_lwp")]
     pub max_lwps: u64,
 
+    /// swap in MB (swapuse)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_swap: Option<u64>,
+    /// virtual memory in MB (vmemoryuse)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_virtual_memory: Option<u64>,
+    /// open file descriptors (openfiles)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_open_files: Option<u64>,
+    /// threads (nthr)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_threads: Option<u64>,
+    /// queued SysV messages (msgqqueued)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_msg_queued: Option<u64>,
+    /// SysV semaphores (semcnt)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sem_count: Option<u64>,
+    /// disk reads in bytes per second, throttled (readbps)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_bps: Option<u64>,
+    /// disk writes in bytes per second, throttled (writebps)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_bps: Option<u64>,
+    /// disk reads per second, throttled (readiops)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_iops: Option<u64>,
+    /// disk writes per second, throttled (writeiops)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_iops: Option<u64>,
+    /// cores the jail is pinned to, like `0-3,8`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpuset: Option<String>,
+
     // Metadata fields w/o effect on vmadm at the moment
     /// Should be archived when deleted
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -244,7 +416,10 @@ pub struct JailConfig {
     /// dns domain for the jail
     #[serde(default = "dflt_dns_domain")]
     pub dns_domain: String,
-    // currently no effect
+    /// Create `<root>/data` and attach it to the jail, so it can manage
+    /// its own datasets and snapshots
+    #[serde(default = "dflt_false")]
+    pub delegate_dataset: bool,
     /// Prevent the jail delegate to be destroyed
     #[serde(skip_serializing_if = "Option::is_none")]
     pub indestructible_delegated: Option<bool>,
@@ -261,15 +436,42 @@ pub struct JailConfig {
     /// Version of the package used for this jail
     #[serde(skip_serializing_if = "Option::is_none")]
     pub package_version: Option<String>,
+    /// static routes, destination to gateway
     #[serde(default = "empty_map")]
     pub routes: Map<String, String>,
-    // TODO:
+    /// host directories and tmpfs mounted into the jail
+    #[serde(default = "empty_filesystems", skip_serializing_if = "Vec::is_empty")]
+    pub filesystems: Vec<Filesystem>,
+    /// jail(8) parameters, they take precedence over the host's defaults
+    /// and the toggles below
+    #[serde(default = "empty_map", skip_serializing_if = "Map::is_empty")]
+    pub jail_params: Map<String, String>,
+    /// allow locking memory (allow.mlock)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_mlock: Option<bool>,
+    /// share SysV IPC with the host instead of having its own
+    /// (sysvmsg, sysvsem and sysvshm)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_sysvipc: Option<bool>,
+    /// filesystems the jail can mount, like `nullfs` (allow.mount.*)
+    #[serde(default = "empty_svec", skip_serializing_if = "Vec::is_empty")]
+    pub allow_mount: Vec<String>,
+    /// mounts visible in the jail, 0 to 2 (enforce_statfs)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enforce_statfs: Option<u8>,
+    /// kernel securelevel of the jail, -1 to 3 (securelevel)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub securelevel: Option<i8>,
+    /// metadata set by the customer
     #[serde(default = "empty_map")]
     pub customer_metadata: Map<String, String>,
+    /// metadata set by the operator
     #[serde(default = "empty_map")]
     pub internal_metadata: Map<String, String>,
-    // internal_metadata_namespaces: Vec<String>
-    // zfs_data_compression
+    /// namespaces, like `sdc` for `sdc:owner`, whose keys are kept in
+    /// `internal_metadata` and can not be changed from inside the jail
+    #[serde(default = "empty_svec", skip_serializing_if = "Vec::is_empty")]
+    pub internal_metadata_namespaces: Vec<String>,
 }
 
 impl PartialEq for JailConfig {
@@ -283,19 +485,47 @@ impl PartialEq for JailConfig {
             self.max_physical_memory == other.max_physical_memory &&
             self.cpu_cap == other.cpu_cap &&
             self.quota == other.quota &&
+            self.zfs_root_compression == other.zfs_root_compression &&
+            self.zfs_root_recordsize == other.zfs_root_recordsize &&
+            self.atime == other.atime &&
+            self.reservation == other.reservation &&
+            self.refreservation == other.refreservation &&
+            self.refquota == other.refquota &&
             self.max_shm_memory == other.max_shm_memory &&
             self.max_locked_memory == other.max_locked_memory &&
             self.nics == other.nics &&
             self.max_lwps == other.max_lwps &&
+            self.max_swap == other.max_swap &&
+            self.max_virtual_memory == other.max_virtual_memory &&
+            self.max_open_files == other.max_open_files &&
+            self.max_threads == other.max_threads &&
+            self.max_msg_queued == other.max_msg_queued &&
+            self.max_sem_count == other.max_sem_count &&
+            self.read_bps == other.read_bps &&
+            self.write_bps == other.write_bps &&
+            self.read_iops == other.read_iops &&
+            self.write_iops == other.write_iops &&
+            self.cpuset == other.cpuset &&
             self.archive_on_delete == other.archive_on_delete &&
             self.billing_id == other.billing_id &&
             self.do_not_inventory == other.do_not_inventory &&
             self.dns_domain == other.dns_domain &&
+            self.delegate_dataset == other.delegate_dataset &&
+            self.filesystems == other.filesystems &&
+            self.jail_params == other.jail_params &&
+            self.allow_mlock == other.allow_mlock &&
+            self.allow_sysvipc == other.allow_sysvipc &&
+            self.allow_mount == other.allow_mount &&
+            self.enforce_statfs == other.enforce_statfs &&
+            self.securelevel == other.securelevel &&
             self.indestructible_delegated == other.indestructible_delegated &&
             self.indestructible_zoneroot == other.indestructible_zoneroot &&
             self.owner_uuid == other.owner_uuid &&
             self.package_name == other.package_name &&
             self.routes == other.routes &&
+            self.customer_metadata == other.customer_metadata &&
+            self.internal_metadata == other.internal_metadata &&
+            self.internal_metadata_namespaces == other.internal_metadata_namespaces &&
             self.package_version == other.package_version
     }
 }
@@ -304,11 +534,57 @@ lazy_static! {
   static ref HOSTNAME_RE: Regex = Regex::new("^[a-zA-Z0-9]([a-zA-Z0-9-]{0,253}[a-zA-Z0-9])?$").unwrap();
   static ref ALIAS_RE: Regex = Regex::new("^[a-zA-Z0-9]([a-zA-Z0-9-]{0,253}[a-zA-Z0-9])?$").unwrap();
   static ref INTERFACE_RE: Regex = Regex::new("^[a-zA-Z]{1,4}[0-9]{0,3}$").unwrap();
-    static ref IP_RE: Regex = Regex::new("^(([0-9]|[1-9][0-9]|1[0-9]{2}|2[0-4][0-9]|25[0-5])\\.){3}([0-9]|[1-9][0-9]|1[0-9]{2}|2[0-4][0-9]|25[0-5])$").unwrap();
-    static ref NET_RE: Regex = Regex::new("^(([0-9]|[1-9][0-9]|1[0-9]{2}|2[0-4][0-9]|25[0-5])\\.){3}([0-9]|[1-9][0-9]|1[0-9]{2}|2[0-4][0-9]|25[0-5])(/((3[0-2])|([12][0-9])|[0-9]))?$").unwrap();
+  static ref JAIL_PARAM_RE: Regex = Regex::new("^[a-z][a-z0-9_]*(\\.[a-z][a-z0-9_]*)*$").unwrap();
+  static ref JAIL_PARAM_VALUE_RE: Regex = Regex::new("^[a-zA-Z0-9_.,:/+-]*$").unwrap();
   static ref MAC_RE: Regex = Regex::new("^[a-fA-F0-9]{1,2}([:][a-fA-F0-9]{1,2}){5}$").unwrap();
 }
 
+/// jail(8) parameters vmadm sets itself, with their sub parameters
+static MANAGED_PARAMS: [&'static str; 11] = [
+    "jid",
+    "name",
+    "path",
+    "persist",
+    "host",
+    "vnet",
+    "ip4",
+    "ip6",
+    "exec",
+    "children",
+    "devfs_ruleset",
+];
+
+/// Values of the zfs compression property
+static COMPRESSIONS: [&'static str; 15] = [
+    "on",
+    "off",
+    "lz4",
+    "lzjb",
+    "zle",
+    "gzip",
+    "gzip-1",
+    "gzip-2",
+    "gzip-3",
+    "gzip-4",
+    "gzip-5",
+    "gzip-6",
+    "gzip-7",
+    "gzip-8",
+    "gzip-9",
+];
+
+/// Filesystems that can be mounted inside a jail
+static MOUNT_TYPES: [&'static str; 8] = [
+    "devfs",
+    "fdescfs",
+    "linprocfs",
+    "linsysfs",
+    "nullfs",
+    "procfs",
+    "tmpfs",
+    "zfs",
+];
+
 impl JailConfig {
     /// Reads a new config from a file
     pub fn from_file(config_path: &str) -> Result<Self, Box<Error>> {
@@ -331,75 +607,418 @@ impl JailConfig {
         }
         Ok(conf)
     }
-    /// checks the config for errors
+    /// checks the config for errors, addresses are checked against
+    /// all jails in the `JDB` and then probed on the network as set by
+    /// `ip_probe`
     pub fn errors(&self, config: &Config) -> Option<Vec<ValidationError>> {
+        self.validate(config, true, &[])
+    }
+
+    /// checks the config for errors without probing the network
+    pub fn offline_errors(&self, config: &Config) -> Option<Vec<ValidationError>> {
+        self.validate(config, false, &[])
+    }
+
+    /// checks an updated config for errors, only addresses that are new
+    /// to the jail are probed on the network as it already answers for
+    /// its own
+    pub fn update_errors(&self, config: &Config, previous: &JailConfig) -> Option<Vec<ValidationError>> {
+        let known: Vec<IpAddr> = previous
+            .nics
+            .iter()
+            .flat_map(|nic| nic.subnets().into_iter().map(|s| s.addr()))
+            .collect();
+        self.validate(config, true, &known)
+    }
+
+    fn validate(&self, config: &Config, probe: bool, known: &[IpAddr]) -> Option<Vec<ValidationError>> {
         let mut errors = Vec::new();
         if !HOSTNAME_RE.is_match(self.hostname.as_str()) {
-            errors.push(ValidationError::new("hostname", "Invalid hostname"))
+            errors.push(ValidationError::new("hostname", codes::INVALID, "Invalid hostname"))
         }
         if !ALIAS_RE.is_match(self.alias.as_str()) {
-            errors.push(ValidationError::new("alias", "Invalid alias"))
+            errors.push(ValidationError::new("alias", codes::INVALID, "Invalid alias"))
         }
+        let mut interfaces: Vec<&str> = Vec::new();
+        let mut allocations = Allocations::load(config, &self.uuid);
         let mut i = 0;
-        for nic in self.nics.clone() {
+        for nic in self.nics.iter() {
+            let field = |name: &str| format!("nics.{}.{}", i, name);
             if !INTERFACE_RE.is_match(nic.interface.as_str()) {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
+                    field("interface").as_str(),
+                    codes::INVALID,
                     "Invalid interface name",
                 ))
             }
-            if !IP_RE.is_match(nic.ip.as_str()) {
+            if interfaces.contains(&nic.interface.as_str()) {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid ip",
+                    field("interface").as_str(),
+                    codes::DUPLICATE,
+                    "Duplicate interface name",
                 ))
             }
-            if !IP_RE.is_match(nic.netmask.as_str()) {
+            interfaces.push(nic.interface.as_str());
+            // the legacy fields are only optional next to `ips`
+            if !nic.ip.is_empty() || nic.ips.is_empty() {
+                if nic.ip.parse::<Ipv4Addr>().is_err() {
+                    errors.push(ValidationError::new(field("ip").as_str(), codes::INVALID, "Invalid ip"))
+                }
+                match nic.netmask.parse::<Ipv4Addr>() {
+                    Err(_) => {
+                        errors.push(ValidationError::new(
+                            field("netmask").as_str(),
+                            codes::INVALID,
+                            "Invalid netmask",
+                        ))
+                    }
+                    Ok(ref mask) if prefix_len(mask).is_none() => {
+                        errors.push(ValidationError::new(
+                            field("netmask").as_str(),
+                            codes::INVALID,
+                            "Netmask is not contiguous",
+                        ))
+                    }
+                    Ok(_) => (),
+                }
+            }
+            if (!nic.gateway.is_empty() || nic.ips.is_empty()) &&
+                nic.gateway.parse::<Ipv4Addr>().is_err()
+            {
+                errors.push(ValidationError::new(field("gateway").as_str(), codes::INVALID, "Invalid gateway"))
+            }
+            for (j, ip) in nic.ips.iter().enumerate() {
+                if ip != "dhcp" && ip != "addrconf" && parse_cidr(ip.as_str()).is_none() {
+                    errors.push(ValidationError::new(
+                        field(format!("ips.{}", j).as_str()).as_str(),
+                        codes::INVALID,
+                        format!("Invalid address {}", ip).as_str(),
+                    ))
+                }
+            }
+            for (j, gateway) in nic.gateways.iter().enumerate() {
+                if parse_gateway(gateway.as_str()).is_none() {
+                    errors.push(ValidationError::new(
+                        field(format!("gateways.{}", j).as_str()).as_str(),
+                        codes::INVALID,
+                        format!("Invalid gateway {}", gateway).as_str(),
+                    ))
+                }
+            }
+            if let Some(ref ipv6) = nic.ipv6 {
+                match parse_cidr(ipv6.as_str()) {
+                    Some(Subnet::Inet6(_, _)) => (),
+                    _ => {
+                        errors.push(ValidationError::new(
+                            field("ipv6").as_str(),
+                            codes::INVALID,
+                            "Invalid ipv6, expected an address with prefix length",
+                        ))
+                    }
+                }
+            }
+            if let Some(ref gateway) = nic.ipv6_gateway {
+                match parse_gateway(gateway.as_str()) {
+                    Some(IpAddr::V6(_)) => (),
+                    _ => {
+                        errors.push(ValidationError::new(
+                            field("ipv6_gateway").as_str(),
+                            codes::INVALID,
+                            "Invalid ipv6_gateway",
+                        ))
+                    }
+                }
+            }
+            // every gateway of the primary nic is a default route, a
+            // second one of the same family fails at boot
+            let mut defaults = (false, false);
+            for (name, gateway) in nic.named_gateways() {
+                if let Some(addr) = parse_gateway(gateway) {
+                    if !nic.reaches(&addr) {
+                        errors.push(ValidationError::new(
+                            field(name.as_str()).as_str(),
+                            codes::UNREACHABLE,
+                            format!("Gateway {} is outside the subnets of the nic", gateway)
+                                .as_str(),
+                        ))
+                    }
+                    if !nic.primary {
+                        continue;
+                    }
+                    let (seen, family) = match addr {
+                        IpAddr::V4(_) => (&mut defaults.0, "IPv4"),
+                        IpAddr::V6(_) => (&mut defaults.1, "IPv6"),
+                    };
+                    if *seen {
+                        errors.push(ValidationError::new(
+                            field(name.as_str()).as_str(),
+                            codes::DUPLICATE,
+                            format!("The primary nic can only have one {} default gateway", family)
+                                .as_str(),
+                        ))
+                    }
+                    *seen = true;
+                }
+            }
+
+            for (name, subnet) in nic.named_subnets() {
+                let ip = subnet.addr();
+                match allocations.ips.get(&ip) {
+                    Some(owner) => {
+                        errors.push(ValidationError::new(
+                            field(name.as_str()).as_str(),
+                            codes::IN_USE,
+                            format!("ip address {} is already used by {}", ip, owner).as_str(),
+                        ))
+                    }
+                    None if probe && !known.contains(&ip) && probe_ip(config, &ip) => {
+                        errors.push(ValidationError::new(
+                            field(name.as_str()).as_str(),
+                            codes::IN_USE,
+                            format!("ip address {} is already taken on the network", ip).as_str(),
+                        ))
+                    }
+                    None => (),
+                }
+                allocations.ips.insert(ip, format!("nic {} of this jail", nic.interface));
+            }
+
+            match mac_octets(nic.mac.as_str()) {
+                None => errors.push(ValidationError::new(field("mac").as_str(), codes::INVALID, "Invalid mac")),
+                Some(mac) => {
+                    match allocations.macs.get(&mac) {
+                        Some(owner) => {
+                            errors.push(ValidationError::new(
+                                field("mac").as_str(),
+                                codes::IN_USE,
+                                format!("Mac {} is already used by {}", nic.mac, owner).as_str(),
+                            ))
+                        }
+                        None => (),
+                    }
+                    allocations.macs.insert(mac, format!("nic {} of this jail", nic.interface));
+                }
+            }
+            if !config.settings.networks.contains_key(&nic.nic_tag) {
+                errors.push(ValidationError::new(field("nic_tag").as_str(), codes::UNKNOWN, "Unknown nic_tag"))
+            }
+            i = i + 1;
+        }
+        if !self.nics.is_empty() && self.nics.iter().filter(|n| n.primary).count() != 1 {
+            errors.push(ValidationError::new(
+                "nics",
+                codes::INVALID,
+                "Exactly one nic has to be primary",
+            ))
+        }
+        for (dest, gw) in self.routes.iter() {
+            // routes are keyed by destination, which can contain dots
+            let field = format!("routes.{}", dest);
+            let field = field.as_str();
+            let family = match dest.parse::<IpAddr>() {
+                Ok(addr) => Some(addr.is_ipv6()),
+                Err(_) => parse_cidr(dest.as_str()).map(|net| net.is_ipv6()),
+            };
+            if family.is_none() {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid netmask",
+                    field,
+                    codes::INVALID,
+                    format!("Invalid destination {}", dest).as_str(),
                 ))
             }
-            if !IP_RE.is_match(nic.gateway.as_str()) {
+            if INTERFACE_RE.is_match(gw.as_str()) {
+                if gw != "lo0" && !interfaces.contains(&gw.as_str()) {
+                    errors.push(ValidationError::new(
+                        field,
+                        codes::UNKNOWN,
+                        format!("Unknown interface {}", gw).as_str(),
+                    ))
+                }
+                continue;
+            }
+            match parse_gateway(gw.as_str()) {
+                None => {
+                    errors.push(ValidationError::new(
+                        field,
+                        codes::INVALID,
+                        format!("Invalid gateway {}", gw).as_str(),
+                    ))
+                }
+                Some(ref addr) if family.is_some() && family != Some(addr.is_ipv6()) => {
+                    errors.push(ValidationError::new(
+                        field,
+                        codes::INVALID,
+                        "Gateway and destination are of different address families",
+                    ))
+                }
+                Some(ref addr) if !self.nics.iter().any(|nic| nic.reaches(addr)) => {
+                    errors.push(ValidationError::new(
+                        field,
+                        codes::UNREACHABLE,
+                        format!("Gateway {} is not reachable from any nic", gw).as_str(),
+                    ))
+                }
+                Some(_) => (),
+            }
+        }
+        let mut targets: Vec<&str> = Vec::new();
+        for (i, fs) in self.filesystems.iter().enumerate() {
+            let field = |name: &str| format!("filesystems.{}.{}", i, name);
+            if fs.fs_type != "nullfs" && fs.fs_type != "tmpfs" {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid gateway",
+                    field("type").as_str(),
+                    codes::INVALID,
+                    "Unsupported filesystem type, expected nullfs or tmpfs",
                 ))
             }
-
-            if checkip(nic.ip.as_str())  {
+            // the brand scripts mount into the jail root, so paths have to
+            // stay inside it
+            if !is_mount_path(fs.target.as_str()) || fs.target == "/" {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "ip address already taken",
+                    field("target").as_str(),
+                    codes::INVALID,
+                    "Invalid target, expected an absolute path",
                 ))
             }
-
-            if !MAC_RE.is_match(nic.mac.as_str()) {
+            if targets.contains(&fs.target.as_str()) {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid mac",
+                    field("target").as_str(),
+                    codes::DUPLICATE,
+                    "Duplicate target",
                 ))
             }
-            if !config.settings.networks.contains_key(&nic.nic_tag) {
+            targets.push(fs.target.as_str());
+            if fs.fs_type == "nullfs" && !is_mount_path(fs.source.as_str()) {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Unknown nic_tag",
+                    field("source").as_str(),
+                    codes::INVALID,
+                    "Invalid source, expected an absolute path",
                 ))
-
             }
-            i = i + 1;
+            for (j, option) in fs.options.iter().enumerate() {
+                if option.is_empty() || option.contains(',') || option.contains(char::is_whitespace) {
+                    errors.push(ValidationError::new(
+                        field(format!("options.{}", j).as_str()).as_str(),
+                        codes::INVALID,
+                        format!("Invalid mount option {}", option).as_str(),
+                    ))
+                }
+            }
         }
-        for (dest, gw) in self.routes.iter() {
-            if !NET_RE.is_match(dest.as_str()) {
+        for (name, value) in self.jail_params.iter() {
+            let field = format!("jail_params.{}", name);
+            if !JAIL_PARAM_RE.is_match(name.as_str()) {
+                errors.push(ValidationError::new(
+                    field.as_str(),
+                    codes::INVALID,
+                    format!("Invalid jail parameter {}", name).as_str(),
+                ))
+            } else if MANAGED_PARAMS.iter().any(|p| name == p || name.starts_with(&format!("{}.", p))) {
+                errors.push(ValidationError::new(
+                    field.as_str(),
+                    codes::INVALID,
+                    format!("Jail parameter {} is set by vmadm", name).as_str(),
+                ))
+            }
+            // the inner jail gets them through a shell script
+            if !JAIL_PARAM_VALUE_RE.is_match(value.as_str()) {
+                errors.push(ValidationError::new(
+                    field.as_str(),
+                    codes::INVALID,
+                    format!("Invalid value for jail parameter {}", name).as_str(),
+                ))
+            }
+        }
+        for (i, fs) in self.allow_mount.iter().enumerate() {
+            if !MOUNT_TYPES.contains(&fs.as_str()) {
+                errors.push(ValidationError::new(
+                    format!("allow_mount.{}", i).as_str(),
+                    codes::INVALID,
+                    format!("Unknown filesystem {}", fs).as_str(),
+                ))
+            }
+        }
+        match self.zfs_root_compression {
+            Some(ref compression) if !COMPRESSIONS.contains(&compression.as_str()) => {
+                errors.push(ValidationError::new(
+                    "zfs_root_compression",
+                    codes::INVALID,
+                    format!("Unknown compression {}", compression).as_str(),
+                ))
+            }
+            _ => (),
+        }
+        match self.zfs_root_recordsize {
+            Some(size) if size < 512 || size > 1048576 || !size.is_power_of_two() => {
+                errors.push(ValidationError::new(
+                    "zfs_root_recordsize",
+                    codes::INVALID,
+                    "Invalid recordsize, expected a power of two from 512 to 1048576",
+                ))
+            }
+            _ => (),
+        }
+        let space = vec![
+            ("reservation", self.reservation),
+            ("refreservation", self.refreservation),
+            ("refquota", self.refquota),
+        ];
+        for (field, size) in space {
+            match size {
+                Some(size) if size > self.quota => {
+                    errors.push(ValidationError::new(
+                        field,
+                        codes::INVALID,
+                        format!("{} can't be larger than the quota", field).as_str(),
+                    ))
+                }
+                _ => (),
+            }
+        }
+        match self.enforce_statfs {
+            Some(enforce_statfs) if enforce_statfs > 2 => {
+                errors.push(ValidationError::new(
+                    "enforce_statfs",
+                    codes::INVALID,
+                    "Invalid enforce_statfs, expected 0, 1 or 2",
+                ))
+            }
+            _ => (),
+        }
+        match self.securelevel {
+            Some(level) if level < -1 || level > 3 => {
+                errors.push(ValidationError::new(
+                    "securelevel",
+                    codes::INVALID,
+                    "Invalid securelevel, expected -1 to 3",
+                ))
+            }
+            _ => (),
+        }
+        if let Some(ref cpuset) = self.cpuset {
+            if parse_cpuset(cpuset.as_str()).is_none() {
                 errors.push(ValidationError::new(
-                    format!("routes {} -> {}", dest, gw).as_str(),
-                    "Invalid destination",
+                    "cpuset",
+                    codes::INVALID,
+                    "Invalid cpuset, expected a list of cores like 0-3,8",
                 ))
             }
-            if !IP_RE.is_match(gw.as_str()) && !INTERFACE_RE.is_match(gw.as_str()) {
+        }
+        for (i, ns) in self.internal_metadata_namespaces.iter().enumerate() {
+            if ns.is_empty() || ns.contains(':') {
+                errors.push(ValidationError::new(
+                    format!("internal_metadata_namespaces.{}", i).as_str(),
+                    codes::INVALID,
+                    format!("Invalid namespace {}", ns).as_str(),
+                ))
+            }
+        }
+        for key in self.customer_metadata.keys() {
+            if metadata::is_protected(self, key) {
                 errors.push(ValidationError::new(
-                    format!("routes {} -> {}", dest, gw).as_str(),
-                    "Invalid gateway",
+                    format!("customer_metadata.{}", key).as_str(),
+                    codes::INVALID,
+                    "Key is in an internal namespace and belongs in internal_metadata",
                 ))
             }
         }
@@ -411,6 +1030,78 @@ impl JailConfig {
 
     }
 
+    /// Cores the jail is pinned to, empty if it can use all of them
+    pub fn cores(&self) -> Vec<u32> {
+        match self.cpuset {
+            Some(ref cpuset) => parse_cpuset(cpuset.as_str()).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+
+    /// jail(8) parameters of the outer and inner jail, built from the
+    /// host's defaults, the features in use, the toggles and `jail_params`
+    pub fn jail_params(&self, defaults: &Map<String, String>) -> Vec<String> {
+        let mut params = defaults.clone();
+        let mut set = |name: &str, value: &str| {
+            params.insert(String::from(name), String::from(value));
+        };
+        if self.delegate_dataset {
+            // mounting zfs needs the mounts of the jail to be visible
+            set("allow.mount", "true");
+            set("allow.mount.zfs", "true");
+            set("enforce_statfs", "1");
+        }
+        if let Some(mlock) = self.allow_mlock {
+            set("allow.mlock", &mlock.to_string());
+        }
+        if let Some(sysvipc) = self.allow_sysvipc {
+            let mode = if sysvipc { "inherit" } else { "new" };
+            set("sysvmsg", mode);
+            set("sysvsem", mode);
+            set("sysvshm", mode);
+        }
+        if !self.allow_mount.is_empty() {
+            set("allow.mount", "true");
+        }
+        for fs in self.allow_mount.iter() {
+            set(format!("allow.mount.{}", fs).as_str(), "true");
+        }
+        if let Some(enforce_statfs) = self.enforce_statfs {
+            set("enforce_statfs", &enforce_statfs.to_string());
+        }
+        if let Some(securelevel) = self.securelevel {
+            set("securelevel", &securelevel.to_string());
+        }
+        for (name, value) in self.jail_params.iter() {
+            set(name, value);
+        }
+        params.iter().map(|(name, value)| jail_param(name, value)).collect()
+    }
+
+    /// ZFS properties of the jail's dataset, the quota is set on its own
+    pub fn zfs_properties(&self) -> Vec<String> {
+        let mut props = Vec::new();
+        if let Some(ref compression) = self.zfs_root_compression {
+            props.push(format!("compression={}", compression));
+        }
+        if let Some(recordsize) = self.zfs_root_recordsize {
+            props.push(format!("recordsize={}", recordsize));
+        }
+        if let Some(atime) = self.atime {
+            props.push(format!("atime={}", if atime { "on" } else { "off" }));
+        }
+        if let Some(reservation) = self.reservation {
+            props.push(format!("reservation={}G", reservation));
+        }
+        if let Some(refreservation) = self.refreservation {
+            props.push(format!("refreservation={}G", refreservation));
+        }
+        if let Some(refquota) = self.refquota {
+            props.push(format!("refquota={}G", refquota));
+        }
+        props
+    }
+
     /// Translates the config into resource controle limts
     pub fn rctl_limits(&self) -> Vec<String> {
         let mut res = Vec::new();
@@ -458,6 +1149,25 @@ impl JailConfig {
         maxproc.push_str(self.max_lwps.to_string().as_str());
         res.push(maxproc);
 
+        // disk I/O can only be throttled, not denied
+        let optional = vec![
+            ("swapuse", "deny", self.max_swap.map(|v| format!("{}M", v))),
+            ("vmemoryuse", "deny", self.max_virtual_memory.map(|v| format!("{}M", v))),
+            ("openfiles", "deny", self.max_open_files.map(|v| v.to_string())),
+            ("nthr", "deny", self.max_threads.map(|v| v.to_string())),
+            ("msgqqueued", "deny", self.max_msg_queued.map(|v| v.to_string())),
+            ("semcnt", "deny", self.max_sem_count.map(|v| v.to_string())),
+            ("readbps", "throttle", self.read_bps.map(|v| v.to_string())),
+            ("writebps", "throttle", self.write_bps.map(|v| v.to_string())),
+            ("readiops", "throttle", self.read_iops.map(|v| v.to_string())),
+            ("writeiops", "throttle", self.write_iops.map(|v| v.to_string())),
+        ];
+        for (resource, action, limit) in optional {
+            if let Some(limit) = limit {
+                res.push(format!("{}:{}:{}={}", base, resource, action, limit));
+            }
+        }
+
         res
     }
 }
@@ -482,6 +1192,10 @@ fn empty_nics() -> Vec<NIC> {
     Vec::new()
 }
 
+fn empty_filesystems() -> Vec<Filesystem> {
+    Vec::new()
+}
+
 fn empty_resolvers() -> Vec<String> {
     Vec::new()
 }
@@ -489,6 +1203,14 @@ fn dflt_brand() -> String {
     String::from("jail")
 }
 
+fn empty_string() -> String {
+    String::new()
+}
+
+fn empty_svec() -> Vec<String> {
+    Vec::new()
+}
+
 fn empty_map() -> Map<String, String> {
     Map::new()
 }
@@ -508,11 +1230,591 @@ fn new_mac() -> String {
     )
 }
 
-fn checkip(ipaddr: &str) -> bool {
-    debug!("Checking if ip address {} is used up ",ipaddr);
-    let output = Command::new("ping")
-        .args(&["-o","-c 1",ipaddr])
-        .output()
-	.expect("failed to ping");
-    output.status.success() 
+/// A subnet a nic is attached to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Subnet {
+    Inet(Ipv4Addr, u8),
+    Inet6(Ipv6Addr, u8),
+}
+
+impl Subnet {
+    fn addr(&self) -> IpAddr {
+        match *self {
+            Subnet::Inet(addr, _) => IpAddr::V4(addr),
+            Subnet::Inet6(addr, _) => IpAddr::V6(addr),
+        }
+    }
+
+    fn is_ipv6(&self) -> bool {
+        match *self {
+            Subnet::Inet6(_, _) => true,
+            Subnet::Inet(_, _) => false,
+        }
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        match (*self, *addr) {
+            (Subnet::Inet(net, len), IpAddr::V4(addr)) => {
+                same_prefix(&net.octets(), &addr.octets(), len)
+            }
+            (Subnet::Inet6(net, len), IpAddr::V6(addr)) => {
+                same_prefix(&net.octets(), &addr.octets(), len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compares the first `len` bits of two addresses
+fn same_prefix(a: &[u8], b: &[u8], len: u8) -> bool {
+    a.iter().zip(b.iter()).enumerate().all(|(i, (a, b))| {
+        let bits = (len as i32 - 8 * i as i32).max(0).min(8);
+        let mask = if bits == 0 { 0 } else { 0xffu8 << (8 - bits) };
+        a & mask == b & mask
+    })
+}
+
+impl NIC {
+    /// Subnets of the statically configured addresses
+    fn subnets(&self) -> Vec<Subnet> {
+        self.named_subnets().into_iter().map(|(_, s)| s).collect()
+    }
+
+    /// The subnets with the fields they are set in
+    fn named_subnets(&self) -> Vec<(String, Subnet)> {
+        let mut subnets = Vec::new();
+        if let (Ok(ip), Ok(mask)) = (self.ip.parse(), self.netmask.parse()) {
+            if let Some(len) = prefix_len(&mask) {
+                subnets.push((String::from("ip"), Subnet::Inet(ip, len)));
+            }
+        }
+        if let Some(ref ipv6) = self.ipv6 {
+            if let Some(subnet @ Subnet::Inet6(_, _)) = parse_cidr(ipv6.as_str()) {
+                subnets.push((String::from("ipv6"), subnet));
+            }
+        }
+        for (i, ip) in self.ips.iter().enumerate() {
+            if let Some(subnet) = parse_cidr(ip.as_str()) {
+                subnets.push((format!("ips.{}", i), subnet));
+            }
+        }
+        subnets
+    }
+
+    /// If a gateway can be reached over this nic, addresses from
+    /// DHCP or SLAAC are unknown up front so everything of their family
+    /// is considered reachable.
+    fn reaches(&self, gateway: &IpAddr) -> bool {
+        let dynamic = match *gateway {
+            IpAddr::V4(_) => self.dhcp(),
+            IpAddr::V6(ref addr) => self.slaac() || is_link_local(addr),
+        };
+        dynamic || self.subnets().iter().any(|s| s.contains(gateway))
+    }
+}
+
+/// Expands a cpuset like `0-3,8` into its cores
+fn parse_cpuset(cpuset: &str) -> Option<Vec<u32>> {
+    let mut cores = Vec::new();
+    for range in cpuset.split(',') {
+        let mut bounds = range.splitn(2, '-');
+        let first: u32 = bounds.next()?.parse().ok()?;
+        let last: u32 = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        if last < first {
+            return None;
+        }
+        cores.extend(first..last + 1);
+    }
+    cores.sort();
+    cores.dedup();
+    Some(cores)
+}
+
+/// Formats a jail(8) parameter, booleans are passed as `name` and
+/// `noname` like `allow.noraw_sockets`
+fn jail_param(name: &str, value: &str) -> String {
+    match value {
+        "true" => String::from(name),
+        "false" => {
+            match name.rfind('.') {
+                Some(i) => format!("{}no{}", &name[..i + 1], &name[i + 1..]),
+                None => format!("no{}", name),
+            }
+        }
+        _ => format!("{}={}", name, value),
+    }
+}
+
+/// Absolute path without `..` or whitespace, the brand scripts read
+/// the mount lines split on whitespace
+fn is_mount_path(path: &str) -> bool {
+    path.starts_with('/') && !path.split('/').any(|c| c == "..") &&
+        !path.contains(char::is_whitespace)
+}
+
+/// Prefix length of a netmask, `None` if it is not contiguous
+fn prefix_len(mask: &Ipv4Addr) -> Option<u8> {
+    let inverted = !u32::from(*mask);
+    if inverted & inverted.wrapping_add(1) == 0 {
+        Some(32 - inverted.count_ones() as u8)
+    } else {
+        None
+    }
+}
+
+/// Parses an address with a prefix length, `10.0.0.5/24` or
+/// `2001:db8::2/64`
+fn parse_cidr(net: &str) -> Option<Subnet> {
+    let mut parts = net.splitn(2, '/');
+    let addr = parts.next().and_then(|a| a.parse::<IpAddr>().ok());
+    let len = parts.next().and_then(|l| l.parse::<u8>().ok());
+    match (addr, len) {
+        (Some(IpAddr::V4(addr)), Some(len)) if len <= 32 => Some(Subnet::Inet(addr, len)),
+        (Some(IpAddr::V6(addr)), Some(len)) if len <= 128 => Some(Subnet::Inet6(addr, len)),
+        _ => None,
+    }
+}
+
+/// Parses a gateway, IPv6 link local ones can be scoped to an
+/// interface, `fe80::1%net0`
+fn parse_gateway(gateway: &str) -> Option<IpAddr> {
+    let mut parts = gateway.splitn(2, '%');
+    let addr = parts.next().and_then(|a| a.parse::<IpAddr>().ok());
+    match (addr, parts.next()) {
+        (Some(addr), None) => Some(addr),
+        (Some(IpAddr::V6(addr)), Some(scope)) if INTERFACE_RE.is_match(scope) => {
+            Some(IpAddr::V6(addr))
+        }
+        _ => None,
+    }
+}
+
+fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// MAC address as bytes so differently written ones compare equal
+fn mac_octets(mac: &str) -> Option<Vec<u8>> {
+    if !MAC_RE.is_match(mac) {
+        return None;
+    }
+    mac.split(':').map(|o| u8::from_str_radix(o, 16).ok()).collect()
+}
+
+/// MACs and static addresses in use, the values name who uses them
+struct Allocations {
+    macs: HashMap<Vec<u8>, String>,
+    ips: HashMap<IpAddr, String>,
+}
+
+impl Allocations {
+    /// Collects the allocations of all jails in the `JDB` but `except`,
+    /// a jail never conflicts with itself.
+    fn load(config: &Config, except: &Uuid) -> Self {
+        let mut allocations = Allocations {
+            macs: HashMap::new(),
+            ips: HashMap::new(),
+        };
+        let db = match JDB::open(config) {
+            Ok(db) => db,
+            Err(e) => {
+                warn!("Could not check addresses against the jdb: {}", e);
+                return allocations;
+            }
+        };
+        for other in db.configs().iter().filter(|c| c.uuid != *except) {
+            let owner = format!("jail {}", other.uuid);
+            for nic in other.nics.iter() {
+                if let Some(mac) = mac_octets(nic.mac.as_str()) {
+                    allocations.macs.insert(mac, owner.clone());
+                }
+                for subnet in nic.subnets() {
+                    allocations.ips.insert(subnet.addr(), owner.clone());
+                }
+            }
+        }
+        allocations
+    }
+}
+
+/// Looks for a host outside of vmadm using an address, only IPv4
+/// addresses are probed.
+fn probe_ip(config: &Config, ip: &IpAddr) -> bool {
+    let ip = match *ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(_) => return false,
+    };
+    debug!("Probing ip address {}", ip; "probe" => format!("{:?}", config.settings.ip_probe));
+    match config.settings.ip_probe {
+        IpProbe::None => false,
+        IpProbe::Ping => {
+            match config.executor.query(PING, &["-o", "-c 1", ip.as_str()]) {
+                Ok(output) => output.status.success(),
+                Err(_) => false,
+            }
+        }
+        IpProbe::Arp => {
+            // the ping only triggers address resolution, hosts that
+            // filter ICMP still answer ARP
+            let _ = config.executor.query(PING, &["-c", "1", "-t", "1", ip.as_str()]);
+            match config.executor.query(ARP, &["-n", ip.as_str()]) {
+                Ok(ref output) if output.status.success() => {
+                    let reply = String::from_utf8_lossy(&output.stdout);
+                    reply.contains(" at ") && !reply.contains("incomplete")
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::RecordingExecutor;
+    use errors::ValidationErrors;
+    use jail_config::*;
+
+    fn dual_stack() -> JailConfig {
+        let json = r#"{
+            "image_uuid": "616d4ab2-832c-11e7-9392-784f438c8d54",
+            "alias": "test", "hostname": "test",
+            "cpu_cap": 100, "max_physical_memory": 1024, "quota": 100,
+            "nics": [{
+                "interface": "net0", "nic_tag": "admin", "primary": true,
+                "ip": "192.168.1.234", "netmask": "255.255.255.0", "gateway": "192.168.1.1",
+                "ipv6": "2001:db8::234/64", "ipv6_gateway": "fe80::1%net0",
+                "accept_rtadv": true
+            }],
+            "routes": {"10.0.0.0/8": "192.168.1.2", "2001:db8:1::/48": "2001:db8::1"}
+        }"#;
+        JailConfig::from_reader(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn ipv6_errors() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        assert!(jail.errors(&conf).is_none());
+        jail.nics[0].ipv6 = Some(String::from("2001:db8::234"));
+        jail.nics[0].ipv6_gateway = Some(String::from("fe80::1%net 0"));
+        jail.routes.insert(String::from("2001:db8:2::/48"), String::from("192.168.1.1"));
+        jail.routes.insert(String::from("2001:db8:3::/129"), String::from("2001:db8::1"));
+        assert_eq!(4, jail.errors(&conf).unwrap().len());
+    }
+
+    #[test]
+    fn ipv6_start_script() {
+        let rec = RecordingExecutor::new();
+        rec.reply("/sbin/ifconfig epair create", "epair7a\n");
+        let conf = Config::test(Box::new(rec));
+        let iface = dual_stack().nics[0].get_iface(&conf, &Uuid::nil()).unwrap();
+        assert_eq!(
+            "/sbin/ifconfig epair7b name net0; \
+             /sbin/ifconfig net0 inet 192.168.1.234 netmask 255.255.255.0; \
+             /sbin/ifconfig net0 inet6 -ifdisabled; \
+             /sbin/ifconfig net0 inet6 2001:db8::234 prefixlen 64; \
+             /sbin/ifconfig net0 inet6 accept_rtadv; /sbin/rtsol net0; ",
+            iface.start_script
+        );
+    }
+
+    #[test]
+    fn network_errors() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        jail.nics[0].netmask = String::from("255.0.255.0");
+        jail.nics[0].gateway = String::from("192.168.2.1");
+        jail.routes.insert(String::from("172.16.0.0/12"), String::from("net1"));
+        assert_eq!(
+            vec![
+                "nics.0.netmask: Netmask is not contiguous",
+                "nics.0.gateway: Gateway 192.168.2.1 is outside the subnets of the nic",
+                "routes.10.0.0.0/8: Gateway 192.168.1.2 is not reachable from any nic",
+                "routes.172.16.0.0/12: Unknown interface net1",
+            ],
+            messages(jail.errors(&conf))
+        );
+        let mut jail = dual_stack();
+        let mut second = jail.nics[0].clone();
+        second.mac = jail.nics[0].mac.to_uppercase();
+        second.ip = String::from("192.168.1.235");
+        second.ipv6 = None;
+        jail.nics.push(second);
+        assert_eq!(
+            vec![
+                "nics.1.interface: Duplicate interface name",
+                format!("nics.1.mac: Mac {} is already used by nic net0 of this jail",
+                        jail.nics[1].mac).as_str(),
+                "nics: Exactly one nic has to be primary",
+            ],
+            messages(jail.errors(&conf))
+        );
+        let errors = ValidationErrors::new(jail.errors(&conf).unwrap());
+        assert_eq!(
+            json!({"field": "nics.1.interface", "code": "duplicate",
+                   "message": "Duplicate interface name"}),
+            serde_json::to_value(&errors).unwrap()["errors"][0]
+        );
+    }
+
+    #[test]
+    fn macs_unique_in_jdb() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut existing = dual_stack();
+        existing.nics[0].mac = String::from("02:00:00:0a:0b:0c");
+        JDB::open(&conf).unwrap().insert(existing.clone()).unwrap();
+        // updating a jail does not conflict with itself
+        assert!(existing.errors(&conf).is_none());
+        let mut jail = dual_stack();
+        jail.nics[0].mac = String::from("2:0:0:A:B:C");
+        jail.nics[0].ip = String::from("192.168.1.235");
+        jail.nics[0].ipv6 = None;
+        assert_eq!(
+            vec![
+                format!("nics.0.mac: Mac 2:0:0:A:B:C is already used by jail {}", existing.uuid),
+            ],
+            messages(jail.errors(&conf))
+        );
+    }
+
+    #[test]
+    fn cpuset() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        assert!(jail.cores().is_empty());
+        jail.cpuset = Some(String::from("4,0-2,8-9"));
+        assert_eq!(vec![0, 1, 2, 4, 8, 9], jail.cores());
+        assert!(jail.errors(&conf).is_none());
+        for bad in vec!["", "3-1", "0,", "a-b", "1-2-3"] {
+            jail.cpuset = Some(String::from(bad));
+            assert_eq!(vec!["cpuset: Invalid cpuset, expected a list of cores like 0-3,8"],
+                       messages(jail.errors(&conf)));
+        }
+    }
+
+    #[test]
+    fn jail_params() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        assert_eq!(
+            vec!["allow.raw_sockets", "securelevel=2", "sysvmsg=new", "sysvsem=new", "sysvshm=new"],
+            jail.jail_params(&conf.settings.jail_params)
+        );
+        jail.allow_sysvipc = Some(true);
+        jail.allow_mount = vec![String::from("nullfs")];
+        jail.securelevel = Some(1);
+        // the jail's own parameters win over the toggles
+        jail.jail_params.insert(String::from("securelevel"), String::from("0"));
+        jail.jail_params.insert(String::from("allow.raw_sockets"), String::from("false"));
+        assert_eq!(
+            vec![
+                "allow.mount",
+                "allow.mount.nullfs",
+                "allow.noraw_sockets",
+                "securelevel=0",
+                "sysvmsg=inherit",
+                "sysvsem=inherit",
+                "sysvshm=inherit",
+            ],
+            jail.jail_params(&conf.settings.jail_params)
+        );
+        assert!(jail.errors(&conf).is_none());
+        jail.jail_params.insert(String::from("host.hostname"), String::from("other"));
+        jail.jail_params.insert(String::from("osrelease"), String::from("$(reboot)"));
+        jail.allow_mount.push(String::from("ext4"));
+        jail.enforce_statfs = Some(3);
+        assert_eq!(
+            vec![
+                "jail_params.host.hostname: Jail parameter host.hostname is set by vmadm",
+                "jail_params.osrelease: Invalid value for jail parameter osrelease",
+                "allow_mount.1: Unknown filesystem ext4",
+                "enforce_statfs: Invalid enforce_statfs, expected 0, 1 or 2",
+            ],
+            messages(jail.errors(&conf))
+        );
+    }
+
+    #[test]
+    fn zfs_properties() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        assert!(jail.zfs_properties().is_empty());
+        jail.zfs_root_compression = Some(String::from("gzip-6"));
+        jail.zfs_root_recordsize = Some(16384);
+        jail.atime = Some(false);
+        jail.refreservation = Some(jail.quota);
+        assert_eq!(
+            vec!["compression=gzip-6", "recordsize=16384", "atime=off", "refreservation=100G"],
+            jail.zfs_properties()
+        );
+        assert!(jail.errors(&conf).is_none());
+        jail.zfs_root_compression = Some(String::from("zstd"));
+        jail.zfs_root_recordsize = Some(3000);
+        jail.reservation = Some(jail.quota + 1);
+        assert_eq!(
+            vec![
+                "zfs_root_compression: Unknown compression zstd",
+                "zfs_root_recordsize: Invalid recordsize, expected a power of two from 512 to 1048576",
+                "reservation: reservation can't be larger than the quota",
+            ],
+            messages(jail.errors(&conf))
+        );
+    }
+
+    #[test]
+    fn rctl_limits() {
+        let mut jail = dual_stack();
+        jail.uuid = Uuid::nil();
+        jail.max_swap = Some(2048);
+        jail.max_open_files = Some(1024);
+        jail.read_bps = Some(10485760);
+        jail.write_iops = Some(500);
+        let base = "jail:00000000-0000-0000-0000-000000000000";
+        let limits = jail.rctl_limits();
+        assert_eq!(
+            vec![
+                format!("{}:swapuse:deny=2048M", base),
+                format!("{}:openfiles:deny=1024", base),
+                format!("{}:readbps:throttle=10485760", base),
+                format!("{}:writeiops:throttle=500", base),
+            ],
+            limits[6..].to_vec()
+        );
+    }
+
+    #[test]
+    fn ip_conflicts() {
+        let rec = RecordingExecutor::new();
+        let mut conf = Config::test(Box::new(rec.clone()));
+        let existing = dual_stack();
+        JDB::open(&conf).unwrap().insert(existing.clone()).unwrap();
+        let mut jail = dual_stack();
+        jail.uuid = Uuid::new_v4();
+        jail.nics[0].mac = String::from("02:00:00:0a:0b:0c");
+        assert_eq!(
+            vec![
+                format!("nics.0.ip: ip address 192.168.1.234 is already used by jail {}", existing.uuid),
+                format!("nics.0.ipv6: ip address 2001:db8::234 is already used by jail {}", existing.uuid),
+            ],
+            messages(jail.errors(&conf))
+        );
+        // nothing is probed unless configured
+        assert!(!rec.calls().iter().any(|c| c.starts_with("ping") || c.starts_with("arp")));
+
+        jail.nics[0].ip = String::from("192.168.1.235");
+        jail.nics[0].ipv6 = None;
+        conf.settings.ip_probe = IpProbe::Arp;
+        rec.reply("arp -n 192.168.1.235", "? (192.168.1.235) at 00:1b:21:3a:4f:01 on bridge0 [ethernet]\n");
+        assert_eq!(
+            vec!["nics.0.ip: ip address 192.168.1.235 is already taken on the network"],
+            messages(jail.errors(&conf))
+        );
+        assert!(jail.offline_errors(&conf).is_none());
+        rec.reply("arp -n 192.168.1.235", "192.168.1.235 (192.168.1.235) -- no entry\n");
+        assert!(jail.errors(&conf).is_none());
+
+        conf.settings.ip_probe = IpProbe::Ping;
+        assert!(jail.errors(&conf).is_some());
+        rec.fail("ping");
+        assert!(jail.errors(&conf).is_none());
+    }
+
+    fn messages(errors: Option<Vec<ValidationError>>) -> Vec<String> {
+        errors.unwrap_or_default().iter().map(|e| e.to_string()).collect()
+    }
+
+    fn multi_homed() -> NIC {
+        let json = r#"{
+            "interface": "net0", "nic_tag": "admin", "primary": true,
+            "ips": ["10.0.0.5/24", "10.0.0.6/24", "fd00::5/64", "fd00::6/64", "dhcp", "addrconf"],
+            "gateways": ["10.0.0.1", "fd00::1"]
+        }"#;
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn ips_errors() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        jail.nics = vec![multi_homed()];
+        assert!(jail.errors(&conf).is_none());
+        jail.nics[0].ips.push(String::from("10.0.0.7"));
+        jail.nics[0].gateways.push(String::from("10.0.0.300"));
+        assert_eq!(2, jail.errors(&conf).unwrap().len());
+        // without ips the legacy fields are required and nothing is
+        // reachable any more
+        jail.nics[0].ips.clear();
+        assert_eq!(8, jail.errors(&conf).unwrap().len());
+        assert_eq!(
+            vec![
+                "nics.0.ip: Invalid ip",
+                "nics.0.netmask: Invalid netmask",
+                "nics.0.gateway: Invalid gateway",
+                "nics.0.gateways.2: Invalid gateway 10.0.0.300",
+                "nics.0.gateways.0: Gateway 10.0.0.1 is outside the subnets of the nic",
+                "nics.0.gateways.1: Gateway fd00::1 is outside the subnets of the nic",
+                "routes.10.0.0.0/8: Gateway 192.168.1.2 is not reachable from any nic",
+                "routes.2001:db8:1::/48: Gateway 2001:db8::1 is not reachable from any nic",
+            ],
+            messages(jail.errors(&conf))
+        );
+    }
+
+    #[test]
+    fn default_gateways() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = dual_stack();
+        jail.nics = vec![multi_homed()];
+        jail.nics[0].gateways.push(String::from("10.0.0.2"));
+        assert_eq!(
+            vec!["nics.0.gateways.2: The primary nic can only have one IPv4 default gateway"],
+            messages(jail.errors(&conf))
+        );
+        // the legacy gateway is a default route as well
+        jail.nics[0].gateways.pop();
+        jail.nics[0].ip = String::from("10.0.0.7");
+        jail.nics[0].netmask = String::from("255.255.255.0");
+        jail.nics[0].gateway = String::from("10.0.0.1");
+        assert_eq!(
+            vec!["nics.0.gateways.0: The primary nic can only have one IPv4 default gateway"],
+            messages(jail.errors(&conf))
+        );
+        // only the primary nic sets default routes
+        jail.nics[0].primary = false;
+        jail.nics[0].interface = String::from("net1");
+        jail.nics.insert(0, dual_stack().nics[0].clone());
+        assert!(jail.errors(&conf).is_none());
+    }
+
+    #[test]
+    fn ips_start_script() {
+        let rec = RecordingExecutor::new();
+        rec.reply("/sbin/ifconfig epair create", "epair7a\n");
+        let conf = Config::test(Box::new(rec));
+        let nic = multi_homed();
+        let iface = nic.get_iface(&conf, &Uuid::nil()).unwrap();
+        assert_eq!(
+            "/sbin/ifconfig epair7b name net0; \
+             /sbin/ifconfig net0 inet 10.0.0.5/24; \
+             /sbin/ifconfig net0 inet 10.0.0.6/24 alias; \
+             /sbin/ifconfig net0 inet6 -ifdisabled; \
+             /sbin/ifconfig net0 inet6 fd00::5 prefixlen 64; \
+             /sbin/ifconfig net0 inet6 fd00::6 prefixlen 64 alias; \
+             /sbin/ifconfig net0 inet6 accept_rtadv; /sbin/rtsol net0; \
+             /sbin/dhclient -b net0; ",
+            iface.start_script
+        );
+        assert_eq!(vec!["10.0.0.1", "fd00::1"], nic.all_gateways());
+    }
 }
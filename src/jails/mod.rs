@@ -3,17 +3,25 @@
 use std::error::Error;
 use errors::GenericError;
 use std::collections::HashMap;
-use std::process::Command;
 use jail_config::IFace;
 use config::Config;
 use uuid::Uuid;
-use jdb::IdxEntry;
+use jdb;
+use jdb::{JDB, IdxEntry, JailState};
 use jail_config::JailConfig;
+use update::JailUpdate;
 use brand::Brand;
+use zfs;
+use lock;
+use mdata;
+use errors::ValidationErrors;
+use aud::{Failure, Adventure, Saga};
 use std::path::PathBuf;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use serde_json;
+use serde_json::Value;
 
 
 #[derive(Debug)]
@@ -30,18 +38,12 @@ struct CreateArgs {
     ifs: Vec<IFace>,
 }
 
-#[cfg(target_os = "freebsd")]
 static RCTL: &'static str = "rctl";
-#[cfg(target_os = "freebsd")]
 static JAIL: &'static str = "jail";
-#[cfg(not(target_os = "freebsd"))]
-static RCTL: &'static str = "echo";
-#[cfg(not(target_os = "freebsd"))]
-static JAIL: &'static str = "echo";
-#[cfg(target_os = "freebsd")]
+static JLS: &'static str = "jls";
 static IFCONFIG: &'static str = "/sbin/ifconfig";
-#[cfg(not(target_os = "freebsd"))]
-static IFCONFIG: &'static str = "echo";
+static CPUSET: &'static str = "cpuset";
+static JEXEC: &'static str = "jexec";
 
 
 /// Jail config
@@ -58,26 +60,58 @@ pub struct Jail<'a> {
 }
 
 impl<'a> Jail<'a> {
-    /// starts a jail
-
+    /// loads the brand of the jail
     pub fn brand(&self, config: &Config) -> Result<Brand, Box<Error>> {
         Brand::load(self.config.brand.as_str(), config)
     }
+    /// starts a jail
     pub fn start(&self, config: &Config) -> Result<i32, Box<Error>> {
-        self.set_rctl()?;
+        self.set_rctl(config)?;
         let brand = self.brand(config)?;
 
+        let mut config_dir = self.jail_root();
+        config_dir.push("config");
+
+        // the brand's init mounts these before the jail boots
+        if config.dry_run {
+            println!("[dry-run] write {}/filesystems", config_dir.to_string_lossy());
+        } else {
+            let mut filesystems = config_dir.clone();
+            filesystems.push("filesystems");
+            let mut filesystems_file = File::create(filesystems)?;
+            // the metadata agent's socket folder comes first so the
+            // jail's own mounts can't hide it
+            let mdata_dir = mdata::socket_dir(config, &self.idx.uuid);
+            fs::create_dir_all(&mdata_dir)?;
+            let mdata_fs = mdata::filesystem(config, &self.idx.uuid);
+            filesystems_file.write_all(mdata_fs.mount_line().as_bytes())?;
+            filesystems_file.write_all(b"\n")?;
+            for fs in self.config.filesystems.iter() {
+                filesystems_file.write_all(fs.mount_line().as_bytes())?;
+                filesystems_file.write_all(b"\n")?;
+            }
+        }
+
         brand.init.output(self, config).expect("brand init failed");
 
         let CreateArgs { args, ifs } = self.create_args(config)?;
         debug!("Start jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
 
 
-        let mut config = self.jail_root();
-        config.push("config");
+        if config.dry_run {
+            println!("[dry-run] write {}/routes", config_dir.to_string_lossy());
+            println!("[dry-run] write {}/jail_params", config_dir.to_string_lossy());
+        } else {
+            // parameters of the inner jail, read by the brand's boot
+            let mut params = config_dir.clone();
+            params.push("jail_params");
+            let mut params_file = File::create(params)?;
+            for param in self.config.jail_params(&config.settings.jail_params) {
+                params_file.write_all(param.as_bytes())?;
+                params_file.write_all(b"\n")?;
+            }
 
-        {
-            let mut routes = config.clone();
+            let mut routes = config_dir.clone();
             routes.push("routes");
             debug!("preparing routes file";
                    "vm" => self.idx.uuid.hyphenated().to_string(),
@@ -85,10 +119,12 @@ impl<'a> Jail<'a> {
             let mut routes_file = File::create(routes)?;
             for nic in self.config.nics.clone() {
                 if nic.primary {
-                    routes_file.write_all(b"default")?;
-                    routes_file.write_all(b"\t")?;
-                    routes_file.write_all(nic.gateway.as_bytes())?;
-                    routes_file.write_all(b"\n")?;
+                    for gateway in nic.all_gateways() {
+                        routes_file.write_all(b"default")?;
+                        routes_file.write_all(b"\t")?;
+                        routes_file.write_all(gateway.as_bytes())?;
+                        routes_file.write_all(b"\n")?;
+                    }
                 }
             }
 
@@ -103,7 +139,13 @@ impl<'a> Jail<'a> {
 
         }
 
-        let id = start_jail(&self.idx.uuid, args)?;
+        let id = start_jail(config, &self.idx.uuid, args)?;
+        if self.config.cpuset.is_some() {
+            // the inner jail was created by the outer one's exec.start
+            let inner = format!("{u}.{u}", u = self.idx.uuid.hyphenated());
+            let inner = list(config)?.get(&inner).map(|e| e.id);
+            set_cpuset(config, &self.idx.uuid, id, inner, &[], &self.config.cores())?;
+        }
         let id_str = id.to_string();
         let mut jprefix = String::from("j");
 
@@ -114,26 +156,33 @@ impl<'a> Jail<'a> {
             epair.push('a');
             let mut target_name = jprefix.clone();
             target_name.push_str(iface.iface.as_str());
-            let args = vec![epair, String::from("name"), target_name];
+            let args = vec![epair.as_str(), "name", target_name.as_str()];
             debug!("renaming epair";
                    "vm" => self.idx.uuid.hyphenated().to_string(),
                    "args" => args.clone().join(" "));
-            let output = Command::new(IFCONFIG).args(args.clone()).output().expect(
-                "ifconfig failed",
-            );
+            let output = config.executor.output(IFCONFIG, &args)?;
             if !output.status.success() {
                 crit!("failed to rename interface"; "vm" => self.idx.uuid.hyphenated().to_string());
             }
         }
+        if self.config.delegate_dataset {
+            self.attach_delegated(config)?;
+        }
+        mdata::start(config, self)?;
         Ok(0)
     }
 
-    pub fn init(&self, _config: &Config) -> Result<i32, Box<Error>> {
+    /// prepares the config folder of a freshly created jail
+    pub fn init(&self, conf: &Config) -> Result<i32, Box<Error>> {
         let mut config = self.jail_root();
         config.push("config");
         debug!("initializing jail";
                "dir" => config.to_str(),
                "vm" => self.idx.uuid.hyphenated().to_string());
+        if conf.dry_run {
+            println!("[dry-run] write {}", config.to_string_lossy());
+            return Ok(0);
+        }
         fs::create_dir(config.clone())?;
         if !self.config.resolvers.is_empty() {
             let mut resolvers = config.clone();
@@ -183,10 +232,10 @@ impl<'a> Jail<'a> {
 
         brand.halt.output(self, config).expect("brand halt failed");;
 
-        let output = Command::new(JAIL)
-            .args(&["-r", self.idx.uuid.hyphenated().to_string().as_str()])
-            .output()
-            .expect("jail stop failed");
+        let output = config.executor.output(
+            JAIL,
+            &["-r", self.idx.uuid.hyphenated().to_string().as_str()],
+        )?;
         if !output.status.success() {
             crit!("Failed to stop jail"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not stop jail"));
@@ -196,7 +245,8 @@ impl<'a> Jail<'a> {
             "brand halted failed",
         );;
 
-        let _ = self.remove_rctl();
+        let _ = self.remove_rctl(config);
+        let _ = mdata::stop(config, self);
         match self.outer {
             Some(outer) => {
                 let id_str = outer.id.to_string();
@@ -206,11 +256,9 @@ impl<'a> Jail<'a> {
                 for nic in self.config.nics.clone() {
                     let mut target_name = jprefix.clone();
                     target_name.push_str(nic.interface.as_str());
-                    let args = vec![target_name, String::from("destroy")];
+                    let args = vec![target_name.as_str(), "destroy"];
                     debug!("renaiming epair"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
-                    let output = Command::new(IFCONFIG).args(args.clone()).output().expect(
-                        "ifconfig failed",
-                    );
+                    let output = config.executor.output(IFCONFIG, &args)?;
                     if !output.status.success() {
                         crit!("failed to rename interface"; "vm" => self.idx.uuid.hyphenated().to_string());
                     }
@@ -224,12 +272,31 @@ impl<'a> Jail<'a> {
         Ok(0)
     }
 
-    fn set_rctl(&self) -> Result<i32, Box<Error>> {
+    /// Attaches the delegated dataset to the inner jail and mounts it
+    fn attach_delegated(&self, config: &Config) -> Result<i32, Box<Error>> {
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        let inner = format!("{}.{}", uuid, uuid);
+        let jid = match list(config)?.get(inner.as_str()) {
+            Some(entry) => entry.id,
+            None => return Err(GenericError::bx("inner jail is not running")),
+        };
+        let data = delegated_dataset(self.idx);
+        zfs::jail(config, jid, data.as_str())?;
+        // the guest booted before the dataset was attached
+        let jid = jid.to_string();
+        let output = config.executor.output(JEXEC, &[jid.as_str(), "zfs", "mount", data.as_str()])?;
+        if !output.status.success() {
+            warn!("failed to mount delegated dataset, it can be mounted from inside the jail";
+                  "vm" => uuid, "dataset" => data);
+        }
+        Ok(0)
+    }
+
+    fn set_rctl(&self, config: &Config) -> Result<i32, Box<Error>> {
         let limits = self.config.rctl_limits();
         debug!("Setting jail limits"; "vm" => self.idx.uuid.hyphenated().to_string(), "limits" => limits.clone().join(" "));
-        let output = Command::new(RCTL).args(limits.clone()).output().expect(
-            "limit failed",
-        );
+        let args: Vec<&str> = limits.iter().map(|l| l.as_str()).collect();
+        let output = config.executor.output(RCTL, &args)?;
         if !output.status.success() {
             crit!("failed to set resource limits"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not set jail limits"));
@@ -237,14 +304,12 @@ impl<'a> Jail<'a> {
         Ok(0)
     }
 
-    fn remove_rctl(&self) -> Result<i32, Box<Error>> {
+    fn remove_rctl(&self, config: &Config) -> Result<i32, Box<Error>> {
         let mut prefix = String::from("jail:");
         prefix.push_str(self.idx.uuid.hyphenated().to_string().as_str());
         let limit_args = vec!["-r", prefix.as_str()];
         debug!("removing rctl limits"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => limit_args.clone().join(" "));
-        let output = Command::new(RCTL).args(limit_args).output().expect(
-            "rctl failed",
-        );
+        let output = config.executor.output(RCTL, &limit_args)?;
 
         if !output.status.success() {
             crit!("failed to remove resource limits"; "vm" => self.idx.uuid.hyphenated().to_string());
@@ -287,13 +352,10 @@ impl<'a> Jail<'a> {
         let mut devfs_ruleset = String::from("devfs_ruleset=");
         devfs_ruleset.push_str(config.settings.devfs_ruleset.to_string().as_str());
         args.push(devfs_ruleset);
-        args.push(String::from("securelevel=2"));
-        args.push(String::from("sysvmsg=new"));
-        args.push(String::from("sysvsem=new"));
-        args.push(String::from("sysvshm=new"));
+        // the inner jail can't be allowed more than the outer one
+        args.extend(self.config.jail_params(&config.settings.jail_params));
 
         // for nested jails
-        args.push(String::from("allow.raw_sockets"));
         args.push(String::from("children.max=1"));
 
         // let mut exec_stop = String::from("exec.stop=");
@@ -322,25 +384,564 @@ impl<'a> Jail<'a> {
     }
 }
 
-#[cfg(not(target_os = "freebsd"))]
-fn start_jail(_uuid: &Uuid, _args: Vec<String>) -> Result<u64, Box<Error>> {
-    Ok(42)
+/// Creates a new jail, this inserts it into the `JDB`, clones the
+/// image, applies the quota and installs the brand. All steps are
+/// rolled back if one of them fails.
+pub fn create(conf: &Config, mut jail: JailConfig) -> Result<i32, Box<Error>> {
+    // only new jails get the default, older ones keep what their
+    // dataset already has
+    if jail.zfs_root_compression.is_none() {
+        jail.zfs_root_compression = Some(String::from("lz4"));
+    }
+    let mut dataset = conf.settings.pool.clone();
+    dataset.push('/');
+    dataset.push_str(jail.image_uuid.hyphenated().to_string().as_str());
+
+    #[derive(Debug, Clone)]
+    struct CreateState<'a> {
+        conf: &'a Config,
+        uuid: Uuid,
+        dataset: String,
+        config: JailConfig,
+        entry: Option<IdxEntry>,
+        snapshot: Option<String>,
+        root: Option<String>,
+    }
+
+    let state = CreateState {
+        conf,
+        uuid: jail.uuid.clone(),
+        dataset,
+        config: jail.clone(),
+        entry: None,
+        snapshot: None,
+        root: None,
+    };
+    fn insert_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        match JDB::open(state.conf) {
+            Ok(mut db) => {
+                match db.insert(state.config.clone()) {
+                    Ok(entry) => Ok(CreateState {
+                        conf: state.conf,
+                        uuid: state.uuid,
+                        dataset: state.dataset,
+                        config: state.config,
+                        entry: Some(entry),
+                        snapshot: state.snapshot,
+                        root: state.root,
+                    }),
+                    Err(error) => Err(Failure::new(state, error)),
+                }
+            }
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    };
+    fn insert_down(state: CreateState) -> CreateState {
+        crit!("Rolling back insert");
+        // the entry is kept so the failed jail shows up until it is
+        // deleted or created again, there is nothing to do if the
+        // insert itself failed
+        if state.entry.is_some() {
+            if let Ok(mut db) = JDB::open(state.conf) {
+                let _ = db.set_state(&state.uuid, JailState::Failed);
+            }
+        };
+        state
+    };
+
+    fn snap_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        match zfs::snapshot(
+            state.conf,
+            state.dataset.as_str(),
+            state.uuid.hyphenated().to_string().as_str(),
+        ) {
+            Ok(snap) => Ok(CreateState {
+                conf: state.conf,
+                uuid: state.uuid,
+                dataset: state.dataset,
+                config: state.config,
+                entry: state.entry,
+                snapshot: Some(snap),
+                root: state.root,
+            }),
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    }
+    fn snap_down(state: CreateState) -> CreateState {
+        crit!("Rolling back snapshot");
+        match state.snapshot.clone() {
+            Some(snap) => {
+                let _ = zfs::destroy(state.conf, snap.as_str());
+                state
+            }
+            None => state,
+        }
+    }
+
+    fn clone_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        match state.snapshot.clone() {
+            Some(snap) => {
+                match state.entry.clone() {
+                    Some(entry) => {
+                        match zfs::clone(state.conf, snap.as_str(), entry.root.as_str()) {
+                            Ok(_) => Ok(CreateState {
+                                conf: state.conf,
+                                uuid: state.uuid,
+                                dataset: state.dataset,
+                                config: state.config,
+                                entry: state.entry,
+                                snapshot: state.snapshot,
+                                root: Some(entry.root),
+                            }),
+                            Err(error) => Err(Failure::new(state, error)),
+                        }
+                    }
+                    None => Err(Failure::new(state, GenericError::bx("No root to clone"))),
+                }
+            }
+            None => Err(Failure::new(state, GenericError::bx("No snap to clone"))),
+        }
+    }
+    fn clone_down(state: CreateState) -> CreateState {
+        crit!("Rolling back clone");
+        match state.root.clone() {
+            Some(root) => {
+                let _ = zfs::destroy(state.conf, root.as_str());
+                state
+            }
+            None => state,
+        }
+    }
+    fn quota_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let s1 = state.clone();
+        let entry = s1.entry.unwrap();
+        let root = entry.root.as_str();
+        let quota = s1.config.quota;
+        match zfs::quota(s1.conf, root, quota) {
+            Ok(_) => Ok(state),
+            Err(e) => Err(Failure::new(state, e)),
+        }
+
+    }
+    fn quota_down(state: CreateState) -> CreateState {
+        crit!("Rolling back init");
+        state
+    }
+    fn properties_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let props = state.config.zfs_properties();
+        let result = zfs::set(state.conf, state.entry.as_ref().unwrap().root.as_str(), &props);
+        match result {
+            Ok(_) => Ok(state),
+            Err(e) => Err(Failure::new(state, e)),
+        }
+    }
+    fn properties_down(state: CreateState) -> CreateState {
+        crit!("Rolling back zfs properties");
+        state
+    }
+    fn delegate_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        if !state.config.delegate_dataset {
+            return Ok(state);
+        }
+        let data = delegated_dataset(state.entry.as_ref().unwrap());
+        // jailed datasets are never mounted on the host
+        match zfs::create(state.conf, data.as_str(), &["jailed=on", "mountpoint=/data"]) {
+            Ok(_) => Ok(state),
+            Err(e) => Err(Failure::new(state, e)),
+        }
+    }
+    fn delegate_down(state: CreateState) -> CreateState {
+        crit!("Rolling back delegated dataset");
+        if state.config.delegate_dataset {
+            if let Some(ref entry) = state.entry {
+                let _ = zfs::destroy(state.conf, delegated_dataset(entry).as_str());
+            }
+        }
+        state
+    }
+    fn init_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let s1 = state.clone();
+        let jail = Jail{
+            idx: & s1.entry.unwrap(),
+            config: s1.config,
+            inner: None,
+            outer: None,
+        };
+        let state1 = CreateState{
+            conf: state.conf,
+            uuid: state.uuid,
+            dataset: state.dataset,
+            config: state.config,
+            entry: state.entry,
+            snapshot: state.snapshot,
+            root: state.root,
+         };
+        match jail.init(state1.conf) {
+            Ok(_) => Ok(state1),
+            Err(err) => Err(Failure::new(state1, err))
+        }
+    }
+    fn init_down(state: CreateState) -> CreateState {
+        crit!("Rolling back init");
+        state
+    }
+    fn brand_install_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let s1 = state.clone();
+        let jail = Jail{
+            idx: & s1.entry.unwrap().clone(),
+            config: s1.config,
+            inner: None,
+            outer: None,
+        };
+        match Brand::load(state.config.brand.as_str(), state.conf) {
+            Err(_) => Err(Failure::new(state, GenericError::bx("invalid brand"))),
+            Ok(brand)  => {
+                match brand.install.output(&jail, state.conf) {
+                    Ok(_) => Ok(state),
+                    Err(_) => Err(Failure::new(state, GenericError::bx("failed to initilize brand")))
+                }
+
+            }
+        }
+    }
+    fn brand_install_down(state: CreateState) -> CreateState {
+        crit!("Rolling back clone");
+        state
+    }
+    let _lock = lock::jail(conf, &jail.uuid)?;
+    let saga = Saga::new(vec![
+        Adventure::new(insert_up, insert_down),
+        Adventure::new(snap_up, snap_down),
+        Adventure::new(clone_up, clone_down),
+        Adventure::new(quota_up, quota_down),
+        Adventure::new(properties_up, properties_down),
+        Adventure::new(delegate_up, delegate_down),
+        Adventure::new(init_up, init_down),
+        Adventure::new(brand_install_up, brand_install_down),
+    ]);
+    match jail.errors(conf) {
+        Some(errors) => Err(ValidationErrors::bx(errors)),
+        None => 
+            match saga.tell(state) {
+                Ok(state) => {
+                    JDB::open(conf)?.set_state(&state.uuid, JailState::Installed)?;
+                    info!("Created jail {}", state.uuid);
+                    Ok(0)
+                }
+                Err(failure) => Err(failure.to_error()),
+            }
+    }
+
+}
+
+/// Applies an update to a jail and stores the new config in the `JDB`.
+pub fn update(conf: &Config, uuid: &Uuid, update: &JailUpdate) -> Result<i32, Box<Error>> {
+    apply_update(conf, uuid, update, "update")
+}
+
+/// Applies a metadata write from inside the jail, a row of them takes
+/// a single version in the history.
+pub fn guest_update(conf: &Config, uuid: &Uuid, update: &JailUpdate) -> Result<i32, Box<Error>> {
+    apply_update(conf, uuid, update, jdb::GUEST_METADATA)
+}
+
+fn apply_update(conf: &Config, uuid: &Uuid, update: &JailUpdate, action: &str) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(conf, uuid)?;
+    let db = JDB::open(conf)?;
+    match db.get(uuid) {
+        Err(e) => Err(e),
+        Ok(jail) => {
+            if let Some(errors) = update.errors(conf, &jail.config) {
+                return Err(ValidationErrors::bx(errors));
+            }
+            let c = update.apply(jail.config.clone());
+            let payload = serde_json::to_value(update)?;
+            reconfigure(conf, &jail, &jail.config, &c)?;
+            let res = record(conf, c.clone(), action, Some(payload));
+            if res.is_err() {
+                undo(conf, &jail, &c, &jail.config);
+            }
+            res
+        }
+    }
+}
+
+/// Restores the config of a jail to a version from its history, this
+/// is recorded as a new version.
+pub fn rollback(conf: &Config, uuid: &Uuid, version: u32) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(conf, uuid)?;
+    let db = JDB::open(conf)?;
+    let jail = db.get(uuid)?;
+    let entry = match db.history(uuid)?.into_iter().find(|e| e.version == version) {
+        Some(entry) => entry,
+        None => {
+            return Err(GenericError::bx(
+                format!("jail {} has no config version {}", uuid, version).as_str(),
+            ))
+        }
+    };
+    reconfigure(conf, &jail, &jail.config, &entry.config)?;
+    let mut payload = serde_json::Map::new();
+    payload.insert(String::from("version"), Value::from(version));
+    let config = entry.config;
+    let res = record(conf, config.clone(), "rollback", Some(Value::Object(payload)));
+    if res.is_err() {
+        undo(conf, &jail, &config, &jail.config);
+    }
+    res
+}
+
+/// Writes a new version of a jail's config to the `JDB`
+fn record(conf: &Config, config: JailConfig, action: &str, payload: Option<Value>) -> Result<i32, Box<Error>> {
+    // TODO: This is ugly ...
+    let mut db = JDB::open(conf)?;
+    db.update(config, action, payload)
+}
+
+type Step = fn(&Config, &Jail, &JailConfig, &JailConfig) -> Result<i32, Box<Error>>;
+
+/// Changes of a config that reach the host, in the order they are made
+static STEPS: [Step; 4] = [change_quota, change_properties, change_cpuset, change_rctl];
+
+/// Brings the host side of a jail from `from` to `to`: its quota and
+/// dataset properties and, while it runs, its cpuset and rctl limits.
+/// When a step fails the steps up to it are undone, so the host is
+/// left matching the config in the `JDB`.
+fn reconfigure(conf: &Config, jail: &Jail, from: &JailConfig, to: &JailConfig) -> Result<i32, Box<Error>> {
+    for (i, step) in STEPS.iter().enumerate() {
+        if let Err(e) = step(conf, jail, from, to) {
+            crit!("failed to reconfigure jail: {}", e; "vm" => jail.idx.uuid.hyphenated().to_string());
+            revert(conf, jail, &STEPS[..i + 1], to, from);
+            return Err(e);
+        }
+    }
+    Ok(0)
+}
+
+/// Takes back all of `reconfigure`, for when the `JDB` could not be
+/// written after it
+fn undo(conf: &Config, jail: &Jail, from: &JailConfig, to: &JailConfig) {
+    revert(conf, jail, &STEPS, from, to)
+}
+
+fn revert(conf: &Config, jail: &Jail, steps: &[Step], from: &JailConfig, to: &JailConfig) {
+    for step in steps.iter().rev() {
+        if let Err(e) = step(conf, jail, from, to) {
+            crit!("failed to undo a change: {}", e; "vm" => jail.idx.uuid.hyphenated().to_string());
+        }
+    }
+}
+
+fn change_quota(conf: &Config, jail: &Jail, from: &JailConfig, to: &JailConfig) -> Result<i32, Box<Error>> {
+    if from.quota != to.quota {
+        zfs::quota(conf, jail.idx.root.as_str(), to.quota)?;
+    }
+    Ok(0)
+}
+
+fn change_properties(conf: &Config, jail: &Jail, from: &JailConfig, to: &JailConfig) -> Result<i32, Box<Error>> {
+    set_changed_properties(conf, jail.idx, &from.zfs_properties(), to)
+}
+
+fn change_cpuset(conf: &Config, jail: &Jail, from: &JailConfig, to: &JailConfig) -> Result<i32, Box<Error>> {
+    match jail.outer {
+        Some(outer) if from.cores() != to.cores() => {
+            let inner = jail.inner.map(|e| e.id);
+            set_cpuset(conf, &jail.idx.uuid, outer.id, inner, &from.cores(), &to.cores())
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Only the rules that changed are touched, the jail is never left
+/// without its limits. A rule that is added replaces the one for the
+/// same resource and action.
+fn change_rctl(conf: &Config, jail: &Jail, from: &JailConfig, to: &JailConfig) -> Result<i32, Box<Error>> {
+    if jail.outer.is_none() {
+        return Ok(0);
+    }
+    let old = from.rctl_limits();
+    let new = to.rctl_limits();
+    let mut added = vec!["-a"];
+    added.extend(new.iter().filter(|r| *r != "-a" && !old.contains(r)).map(|r| r.as_str()));
+    if added.len() > 1 {
+        rctl(conf, jail, &added)?;
+    }
+    let kept: Vec<&str> = new.iter().map(|r| rule_filter(r)).collect();
+    for rule in old.iter().filter(|r| *r != "-a") {
+        let filter = rule_filter(rule);
+        if !kept.contains(&filter) {
+            rctl(conf, jail, &["-r", filter])?;
+        }
+    }
+    Ok(0)
+}
+
+/// A rule without its amount, like `jail:<uuid>:memoryuse:deny`
+fn rule_filter(rule: &str) -> &str {
+    rule.split('=').next().unwrap_or(rule)
+}
+
+fn rctl(conf: &Config, jail: &Jail, args: &[&str]) -> Result<i32, Box<Error>> {
+    debug!("changing rctl limits"; "vm" => jail.idx.uuid.hyphenated().to_string(), "args" => args.join(" "));
+    let output = conf.executor.output(RCTL, args)?;
+    if !output.status.success() {
+        crit!("failed to change resource limits"; "vm" => jail.idx.uuid.hyphenated().to_string());
+        return Err(GenericError::bx("Could not change resource limits"));
+    }
+    Ok(0)
 }
 
-#[cfg(target_os = "freebsd")]
-fn start_jail(uuid: &Uuid, args: Vec<String>) -> Result<u64, Box<Error>> {
-    let output = Command::new(JAIL).args(args.clone()).output().expect(
-        "jail failed",
-    );
+/// Deletes a jail, stopping it first when it is running, and removes
+/// its datasets and `JDB` entry.
+pub fn delete(conf: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(conf, uuid)?;
+    let mut db = JDB::open(conf)?;
+    debug!("deleteing jail {}", uuid.hyphenated());
+    if let Ok(jail) = db.get(uuid) {
+        if jail.config.delegate_dataset && jail.config.indestructible_delegated == Some(true) {
+            return Err(GenericError::bx(
+                "the delegated dataset is indestructible, unset indestructible_delegated first",
+            ));
+        }
+    }
+    let res = match db.get(uuid) {
+        Ok(jail) => {
+            if jail.outer.is_some() {
+                info!("Stopping jail {}", uuid);
+                transition(conf, uuid, JailState::Stopping, JailState::Stopped, || {
+                    jail.stop(conf)
+                })?;
+            };
+            if jail.config.delegate_dataset {
+                let data = delegated_dataset(jail.idx);
+                match zfs::destroy_all(conf, data.as_str()) {
+                    Ok(_) => debug!("delegated dataset deleted: {}", data),
+                    Err(e) => warn!("failed to delete delegated dataset: {}", e),
+                };
+            }
+            let origin = zfs::origin(conf, jail.idx.root.as_str());
+            match zfs::destroy(conf, jail.idx.root.as_str()) {
+                Ok(_) => debug!("zfs dataset deleted: {}", jail.idx.root),
+                // the entry is kept as long as the dataset is around
+                Err(ref e) if zfs::is_present(conf, jail.idx.root.as_str()) => {
+                    crit!("failed to delete dataset: {}", e);
+                    return Err(GenericError::bx(
+                        format!("could not delete dataset {}", jail.idx.root).as_str(),
+                    ));
+                }
+                Err(e) => warn!("failed to delete dataset: {}", e),
+            };
+            match origin {
+                // imported jails are not cloned from an image
+                Ok(ref origin) if origin == "-" => (),
+                Ok(origin) => {
+                    match zfs::destroy(conf, origin.as_str()) {
+                        Ok(_) => debug!("zfs snapshot deleted: {}", origin),
+                        Err(e) => warn!("failed to delete origin {}: {}", origin, e),
+                    }
+                }
+                Err(e) => warn!("failed to delete origin: {}", e),
+            };
+            Ok(0)
+        }
+        Err(e) => Err(e),
+    };
+    // the lock file stays, a waiter holds its inode and would otherwise
+    // race a new caller that creates a fresh one; it finds the jail gone
+    db.remove(uuid)?;
+    res
+}
+
+/// Starts a jail that is not running yet.
+pub fn start(conf: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(conf, uuid)?;
+    let db = JDB::open(conf)?;
+    debug!("Starting jail {}", uuid.hyphenated());
+    match db.get(uuid)? {
+        Jail { outer: Some(_), .. } => Err(GenericError::bx("VM is already started")),
+        jail => {
+            transition(conf, uuid, JailState::Starting, JailState::Running, || {
+                jail.start(conf)
+            })
+        }
+    }
+}
+
+/// Stops a running jail.
+pub fn stop(conf: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(conf, uuid)?;
+    let db = JDB::open(conf)?;
+    debug!("stopping jail {}", uuid.hyphenated());
+    match db.get(uuid)? {
+        Jail { outer: None, .. } => Err(GenericError::bx("VM is already stopped")),
+        jail => {
+            transition(conf, uuid, JailState::Stopping, JailState::Stopped, || {
+                jail.stop(conf)
+            })
+        }
+    }
+}
+
+/// Stops and starts a running jail again.
+pub fn reboot(conf: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let _lock = lock::jail(conf, uuid)?;
+    let db = JDB::open(conf)?;
+    debug!("rebooting jail {}", uuid.hyphenated());
+    match db.get(uuid)? {
+        Jail { outer: None, .. } => Err(GenericError::bx("The vm is not running")),
+        jail => {
+            transition(conf, uuid, JailState::Stopping, JailState::Stopped, || {
+                jail.stop(conf)
+            })?;
+            transition(conf, uuid, JailState::Starting, JailState::Running, || {
+                jail.start(conf)
+            })
+        }
+    }
+}
+
+/// Runs `op` on a jail, recording `during` while it runs and `after`
+/// or failed once it is done.
+fn transition<F>(
+    conf: &Config,
+    uuid: &Uuid,
+    during: JailState,
+    after: JailState,
+    op: F,
+) -> Result<i32, Box<Error>>
+where
+    F: FnOnce() -> Result<i32, Box<Error>>,
+{
+    JDB::open(conf)?.set_state(uuid, during)?;
+    match op() {
+        Ok(res) => {
+            JDB::open(conf)?.set_state(uuid, after)?;
+            Ok(res)
+        }
+        Err(e) => {
+            crit!("Jail operation failed"; "vm" => uuid.hyphenated().to_string(), "state" => during.to_string());
+            if let Ok(mut db) = JDB::open(conf) {
+                let _ = db.set_state(uuid, JailState::Failed);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn start_jail(config: &Config, uuid: &Uuid, args: Vec<String>) -> Result<u64, Box<Error>> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let output = config.executor.output(JAIL, &args)?;
     let reply = String::from_utf8_lossy(&output.stdout).into_owned();
     if output.status.success() {
         // the Jail command has a bug that it will not honor -q
         // so everything but the first line might be garbage we have to
         // ignore.
-        let mut lines = reply.lines();
-        let first = lines.next().unwrap();
+        let first = reply.lines().next().ok_or_else(
+            || GenericError::bx("jail did not return a jid"),
+        )?;
         // this seems odd but we guarnatee our ID is a int this way
-        let id: u64 = first.trim().parse().unwrap();
+        let id: u64 = first.trim().parse()?;
         Ok(id)
     } else {
         crit!("Failed to start jail"; "vm" => uuid.hyphenated().to_string().as_str());
@@ -349,32 +950,122 @@ fn start_jail(uuid: &Uuid, args: Vec<String>) -> Result<u64, Box<Error>> {
 }
 
 
-/// reads the zfs datasets in a pool
-#[cfg(target_os = "freebsd")]
-pub fn list() -> Result<HashMap<String, JailOSEntry>, Box<Error>> {
-    debug!("Listing jails");
-    let output = Command::new("jls")
-        .args(&["-q", "jid", "name"])
-        .output()
-        .expect("zfs list failed");
-    let reply = String::from_utf8_lossy(&output.stdout);
-    let mut res = HashMap::new();
+/// Dataset delegated to a jail, snapshots taken in the jail live below it
+fn delegated_dataset(idx: &IdxEntry) -> String {
+    format!("{}/data", idx.root)
+}
 
+/// Sets the zfs properties of `config` that differ from `current`,
+/// properties it no longer has are reset
+fn set_changed_properties(
+    conf: &Config,
+    idx: &IdxEntry,
+    current: &[String],
+    config: &JailConfig,
+) -> Result<i32, Box<Error>> {
+    let props = config.zfs_properties();
+    let mut changed: Vec<String> = props.iter().filter(|p| !current.contains(p)).cloned().collect();
+    for prop in current.iter() {
+        let name = prop.split('=').next().unwrap_or_default();
+        if props.iter().any(|p| p.starts_with(&format!("{}=", name))) {
+            continue;
+        }
+        match name {
+            // space guarantees and limits can't be inherited
+            "reservation" | "refreservation" | "refquota" => changed.push(format!("{}=none", name)),
+            _ => {
+                zfs::inherit(conf, idx.root.as_str(), name)?;
+            }
+        }
+    }
+    if changed.is_empty() {
+        return Ok(0);
+    }
+    zfs::set(conf, idx.root.as_str(), &changed)
+}
 
-    for line in reply.split('\n').filter(|x| *x != "") {
-        let entry = deconstruct_entry(line)?;
-        res.insert(entry.uuid.clone(), entry);
-        ()
+/// Moves a running jail from the cores in `from` to the ones in `to`,
+/// empty meaning all cores. The cores of the inner jail have to stay
+/// within the outer jail's, so the outer jail is widened before and
+/// narrowed after the inner one is moved.
+fn set_cpuset(
+    config: &Config,
+    uuid: &Uuid,
+    outer: u64,
+    inner: Option<u64>,
+    from: &[u32],
+    to: &[u32],
+) -> Result<i32, Box<Error>> {
+    let wide = if from.is_empty() || to.is_empty() {
+        Vec::new()
+    } else {
+        let mut wide = from.to_vec();
+        wide.extend_from_slice(to);
+        wide.sort();
+        wide.dedup();
+        wide
+    };
+    if wide.as_slice() != from {
+        pin(config, uuid, outer, &wide)?;
     }
-    Ok(res)
+    if let Some(inner) = inner {
+        pin(config, uuid, inner, to)?;
+    }
+    if wide.as_slice() != to {
+        pin(config, uuid, outer, to)?;
+    }
+    Ok(0)
 }
 
-/// Reads a dummy jail
-#[cfg(not(target_os = "freebsd"))]
-pub fn list() -> Result<HashMap<String, JailOSEntry>, Box<Error>> {
-    let reply = "1 00000000-1f3e-4b11-b0ae-8494bb6ecd52\n2 00000000-1f3e-4b11-b0ae-8494bb6ecd52.00000000-1f3e-4b11-b0ae-8494bb6ecd52\n";
+/// Pins a single jail to `cores`, empty releases it to all of them
+fn pin(config: &Config, uuid: &Uuid, jid: u64, cores: &[u32]) -> Result<i32, Box<Error>> {
+    let cpus = if cores.is_empty() {
+        String::from("all")
+    } else {
+        format_cores(cores)
+    };
+    let jid = jid.to_string();
+    debug!("setting cpuset"; "vm" => uuid.hyphenated().to_string(), "jid" => jid.clone(), "cpus" => cpus.clone());
+    let output = config.executor.output(CPUSET, &["-l", cpus.as_str(), "-j", jid.as_str()])?;
+    if !output.status.success() {
+        crit!("failed to set cpuset"; "vm" => uuid.hyphenated().to_string());
+        return Err(GenericError::bx("Could not set cpuset"));
+    }
+    Ok(0)
+}
+
+/// Formats sorted cores as a list like `0-3,8`
+fn format_cores(cores: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for core in cores {
+        match ranges.last_mut() {
+            Some(ref mut range) if range.1 + 1 == *core => {
+                range.1 = *core;
+                continue;
+            }
+            _ => (),
+        }
+        ranges.push((*core, *core));
+    }
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|&(first, last)| if first == last {
+            first.to_string()
+        } else {
+            format!("{}-{}", first, last)
+        })
+        .collect();
+    ranges.join(",")
+}
+
+/// reads the running jails
+pub fn list(config: &Config) -> Result<HashMap<String, JailOSEntry>, Box<Error>> {
+    debug!("Listing jails");
+    let output = config.executor.query(JLS, &["-q", "jid", "name"])?;
+    let reply = String::from_utf8_lossy(&output.stdout);
     let mut res = HashMap::new();
 
+
     for line in reply.split('\n').filter(|x| *x != "") {
         let entry = deconstruct_entry(line)?;
         res.insert(entry.uuid.clone(), entry);
@@ -399,3 +1090,503 @@ fn deconstruct_entry(line: &str) -> Result<JailOSEntry, Box<Error>> {
         id: id,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+    use config::{Config, TestConfig};
+    use executor::{RecordingExecutor, DryRunExecutor};
+    use jail_config::{Filesystem, JailConfig};
+    use jdb::{JDB, JailState};
+    use update::JailUpdate;
+    use jails;
+    use mdata;
+
+    static UUID: &'static str = "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53";
+    static IMAGE: &'static str = "616d4ab2-832c-11e7-9392-784f438c8d54";
+
+    fn setup() -> (RecordingExecutor, TestConfig) {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec.clone()));
+        // zfs would create this when cloning
+        fs::create_dir_all(jail_root(&conf)).unwrap();
+        (rec, conf)
+    }
+
+    fn jail_root(conf: &Config) -> PathBuf {
+        let mut root = PathBuf::from("/");
+        root.push(conf.settings.pool.as_str());
+        root.push(UUID);
+        root.push("root");
+        root
+    }
+
+    fn brand(conf: &Config) -> String {
+        format!("{}/jail", conf.settings.brand_dir)
+    }
+
+    fn provision(conf: &Config) {
+        let jail = JailConfig::from_reader(File::open("examples/example.json").unwrap()).unwrap();
+        jails::create(conf, jail).unwrap();
+    }
+
+    #[test]
+    fn create() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let pool = conf.settings.pool.clone();
+        assert_eq!(
+            vec![
+                // every JDB::open reads the running jails
+                String::from("jls -q jid name"),
+                String::from("jls -q jid name"),
+                format!("zfs snapshot {}/{}@{}", pool, IMAGE, UUID),
+                format!("zfs clone {}/{}@{} {}/{}", pool, IMAGE, UUID, pool, UUID),
+                format!("zfs set quota=100G {}/{}", pool, UUID),
+                format!("zfs set compression=lz4 {}/{}", pool, UUID),
+                format!("{b}/install {b} /{}/{}", pool, UUID, b = brand(&conf)),
+                String::from("jls -q jid name"),
+            ],
+            rec.calls()
+        );
+        let db = JDB::open(&conf).unwrap();
+        let jail = db.get(&Uuid::parse_str(UUID).unwrap()).unwrap();
+        assert_eq!(JailState::Installed, jail.idx.state);
+    }
+
+    #[test]
+    fn create_failed() {
+        let (rec, conf) = setup();
+        rec.fail("zfs clone");
+        let jail = JailConfig::from_reader(File::open("examples/example.json").unwrap()).unwrap();
+        assert!(jails::create(&conf, jail.clone()).is_err());
+        let pool = conf.settings.pool.clone();
+        assert!(rec.calls().contains(&format!("zfs destroy {}/{}@{}", pool, IMAGE, UUID)));
+        {
+            let db = JDB::open(&conf).unwrap();
+            let entry = db.get(&Uuid::parse_str(UUID).unwrap()).unwrap();
+            assert_eq!(JailState::Failed, entry.idx.state);
+        }
+        // the next create of the same jail replaces the failed entry
+        rec.reply("zfs clone", "");
+        jails::create(&conf, jail.clone()).unwrap();
+        {
+            let db = JDB::open(&conf).unwrap();
+            let entry = db.get(&Uuid::parse_str(UUID).unwrap()).unwrap();
+            assert_eq!(JailState::Installed, entry.idx.state);
+            assert_eq!(1, db.iter().count());
+            assert_eq!(1, db.history(&Uuid::parse_str(UUID).unwrap()).unwrap().len());
+        }
+        // a jail that was created is never replaced
+        assert!(jails::create(&conf, jail).is_err());
+    }
+
+    #[test]
+    fn create_dry_run() {
+        let (rec, mut conf) = setup();
+        conf.executor = Box::new(DryRunExecutor::new(Box::new(rec.clone())));
+        conf.dry_run = true;
+        provision(&conf);
+        assert_eq!(
+            vec![
+                // every JDB::open reads the running jails
+                String::from("jls -q jid name"),
+                String::from("jls -q jid name"),
+                String::from("jls -q jid name"),
+            ],
+            rec.calls()
+        );
+        let db = JDB::open(&conf).unwrap();
+        assert!(db.get(&Uuid::parse_str(UUID).unwrap()).is_err());
+        assert!(!jail_root(&conf).join("config").exists());
+    }
+
+    #[test]
+    fn start() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        rec.reply("/sbin/ifconfig epair create", "epair7a\n");
+        rec.reply("jail -i", "42\n");
+        rec.clear();
+        let db = JDB::open(&conf).unwrap();
+        let jail = db.get(&Uuid::parse_str(UUID).unwrap()).unwrap();
+        jail.start(&conf).unwrap();
+        let pool = conf.settings.pool.clone();
+        let b = brand(&conf);
+        assert_eq!(
+            vec![
+                String::from("jls -q jid name"),
+                format!(
+                    "rctl -a jail:{u}:memoryuse:deny=1024M jail:{u}:memorylocked:deny=1024M \
+                     jail:{u}:shmsize:deny=1024M jail:{u}:pcpu:deny=100 jail:{u}:maxproc:deny=2000",
+                    u = UUID
+                ),
+                format!("{b}/init {b} /{}/{}", pool, UUID, b = b),
+                String::from("/sbin/ifconfig epair create up"),
+                String::from("/sbin/ifconfig bridge0 addm epair7a"),
+                format!("/sbin/ifconfig epair7a description VNic from jail {}", UUID),
+                format!(
+                    "jail -i -c persist name={u} path=/{p}/{u}/root host.hostuuid={u} \
+                     host.hostname=test devfs_ruleset=4 allow.raw_sockets securelevel=2 sysvmsg=new \
+                     sysvsem=new sysvshm=new children.max=1 vnet=new vnet.interface=epair7b \
+                     exec.start=/sbin/ifconfig epair7b name net0; \
+                     /sbin/ifconfig net0 inet 192.168.1.234 netmask 255.255.255.0; \
+                     /sbin/ifconfig lo0 127.0.0.1 up; {b}/boot '{b}' '{u}' 'test'",
+                    u = UUID,
+                    p = pool,
+                    b = b
+                ),
+                String::from("/sbin/ifconfig epair7a name j42:net0"),
+                format!(
+                    "daemon -f -p {}/mdata/{u}.pid {} metadata serve {u}",
+                    conf.settings.run_dir,
+                    env::current_exe().unwrap().to_string_lossy(),
+                    u = UUID
+                ),
+            ],
+            rec.calls()
+        );
+    }
+
+    #[test]
+    fn stop() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        rec.reply("jls", format!("42 {u}\n43 {u}.{u}\n", u = UUID).as_str());
+        rec.clear();
+        let db = JDB::open(&conf).unwrap();
+        let jail = db.get(&Uuid::parse_str(UUID).unwrap()).unwrap();
+        jail.stop(&conf).unwrap();
+        let b = brand(&conf);
+        assert_eq!(
+            vec![
+                String::from("jls -q jid name"),
+                format!("{}/halt 43", b),
+                format!("jail -r {}", UUID),
+                format!("{b}/halted {b} /{}/{}", conf.settings.pool, UUID, b = b),
+                format!("rctl -r jail:{}", UUID),
+                format!("pkill -F {}/mdata/{}.pid", conf.settings.run_dir, UUID),
+                String::from("/sbin/ifconfig j42:net0 destroy"),
+            ],
+            rec.calls()
+        );
+    }
+
+    #[test]
+    fn cpuset() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        rec.reply("jls", format!("42 {u}\n43 {u}.{u}\n", u = UUID).as_str());
+        rec.clear();
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let cpusets = |rec: &RecordingExecutor| -> Vec<String> {
+            rec.calls().into_iter().filter(|c| c.starts_with("cpuset")).collect()
+        };
+        // narrowing moves the inner jail first
+        let pin = JailUpdate::from_reader(r#"{"cpuset": "0-3,8"}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &pin).unwrap();
+        assert_eq!(vec!["cpuset -l 0-3,8 -j 43", "cpuset -l 0-3,8 -j 42"], cpusets(&rec));
+        rec.clear();
+        // an unchanged cpuset is not applied again
+        jails::update(&conf, &uuid, &pin).unwrap();
+        assert!(cpusets(&rec).is_empty());
+        // moving to other cores widens the outer jail to both sets first
+        let repin = JailUpdate::from_reader(r#"{"cpuset": "4-5"}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &repin).unwrap();
+        assert_eq!(
+            vec!["cpuset -l 0-5,8 -j 42", "cpuset -l 4-5 -j 43", "cpuset -l 4-5 -j 42"],
+            cpusets(&rec)
+        );
+        rec.clear();
+        // widening moves the outer jail first
+        let unpin = JailUpdate::from_reader(r#"{"cpuset": ""}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &unpin).unwrap();
+        assert_eq!(vec!["cpuset -l all -j 42", "cpuset -l all -j 43"], cpusets(&rec));
+        assert_eq!(None, JDB::open(&conf).unwrap().get(&uuid).unwrap().config.cpuset);
+        rec.clear();
+        // re-pinning after the unpin narrows again
+        jails::update(&conf, &uuid, &repin).unwrap();
+        assert_eq!(vec!["cpuset -l 4-5 -j 43", "cpuset -l 4-5 -j 42"], cpusets(&rec));
+    }
+
+    #[test]
+    fn update_failed() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        rec.reply("jls", format!("42 {u}\n43 {u}.{u}\n", u = UUID).as_str());
+        rec.fail("cpuset");
+        rec.clear();
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let root = format!("{}/{}", conf.settings.pool, UUID);
+        let update = JailUpdate::from_reader(
+            r#"{"quota": 20, "atime": false, "cpuset": "0-3"}"#.as_bytes(),
+        ).unwrap();
+        assert!(jails::update(&conf, &uuid, &update).is_err());
+        // the changes made before the cpuset are taken back
+        let calls = rec.calls();
+        let changed = calls.iter().position(|c| *c == format!("zfs set quota=20G {}", root));
+        let restored = calls.iter().position(|c| *c == format!("zfs set quota=100G {}", root));
+        assert!(changed.is_some() && changed < restored);
+        assert!(calls.contains(&format!("zfs inherit atime {}", root)));
+        let db = JDB::open(&conf).unwrap();
+        assert_eq!(100, db.get(&uuid).unwrap().config.quota);
+        assert_eq!(1, db.history(&uuid).unwrap().len());
+    }
+
+    #[test]
+    fn rctl() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let prefix = format!("jail:{}", UUID);
+        // limits of a stopped jail are set when it starts
+        let grow = JailUpdate::from_reader(r#"{"max_physical_memory": 2048}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &grow).unwrap();
+        assert!(!rec.calls().iter().any(|c| c.starts_with("rctl")));
+        rec.reply("jls", format!("42 {u}\n43 {u}.{u}\n", u = UUID).as_str());
+        rec.clear();
+        let shrink = JailUpdate::from_reader(r#"{"max_physical_memory": 1024}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &shrink).unwrap();
+        let rctls = |rec: &RecordingExecutor| -> Vec<String> {
+            rec.calls().into_iter().filter(|c| c.starts_with("rctl")).collect()
+        };
+        // only the changed rules are added, none are removed
+        assert_eq!(
+            vec![format!("rctl -a {}:memoryuse:deny=1024M", prefix)],
+            rctls(&rec)
+        );
+        rec.clear();
+        // rules that are gone after a rollback are removed
+        let swap = JailUpdate::from_reader(r#"{"max_swap": 512}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &swap).unwrap();
+        assert_eq!(vec![format!("rctl -a {}:swapuse:deny=512M", prefix)], rctls(&rec));
+        rec.clear();
+        let version = JDB::open(&conf).unwrap().history(&uuid).unwrap().len() as u32 - 1;
+        jails::rollback(&conf, &uuid, version).unwrap();
+        assert_eq!(vec![format!("rctl -r {}:swapuse:deny", prefix)], rctls(&rec));
+        rec.clear();
+        // limits that can't be changed are put back
+        rec.fail("rctl -a");
+        assert!(jails::update(&conf, &uuid, &grow).is_err());
+        let calls = rctls(&rec);
+        assert_eq!(2, calls.len());
+        assert!(calls[0].contains(format!("{}:memoryuse:deny=2048M", prefix).as_str()));
+        assert!(calls[1].contains(format!("{}:memoryuse:deny=1024M", prefix).as_str()));
+        assert_eq!(1024, JDB::open(&conf).unwrap().get(&uuid).unwrap().config.max_physical_memory);
+        rec.reply("rctl -a", "");
+        rec.clear();
+        // unchanged limits are not applied again
+        let alias = JailUpdate::from_reader(r#"{"alias": "renamed"}"#.as_bytes()).unwrap();
+        jails::update(&conf, &uuid, &alias).unwrap();
+        assert!(!rec.calls().iter().any(|c| c.starts_with("rctl")));
+    }
+
+    #[test]
+    fn delegate() {
+        let (rec, conf) = setup();
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap())
+            .unwrap();
+        jail.delegate_dataset = true;
+        jail.indestructible_delegated = Some(true);
+        jails::create(&conf, jail).unwrap();
+        let pool = conf.settings.pool.clone();
+        let data = format!("{}/{}/data", pool, UUID);
+        assert!(rec.calls().contains(&format!(
+            "zfs create -o jailed=on -o mountpoint=/data {}",
+            data
+        )));
+        rec.reply("/sbin/ifconfig epair create", "epair7a\n");
+        rec.reply("jail -i", "42\n");
+        rec.reply("jls", format!("42 {u}\n43 {u}.{u}\n", u = UUID).as_str());
+        rec.clear();
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        {
+            let db = JDB::open(&conf).unwrap();
+            db.get(&uuid).unwrap().start(&conf).unwrap();
+        }
+        let calls = rec.calls();
+        assert!(calls.iter().any(|c| {
+            c.starts_with("jail -i -c") &&
+                c.contains(" allow.mount allow.mount.zfs allow.raw_sockets enforce_statfs=1 ")
+        }));
+        assert!(calls.contains(&format!("zfs jail 43 {}", data)));
+        assert!(calls.contains(&format!("jexec 43 zfs mount {}", data)));
+        let mut params = String::new();
+        File::open(jail_root(&conf).join("config").join("jail_params"))
+            .unwrap()
+            .read_to_string(&mut params)
+            .unwrap();
+        assert_eq!(
+            "allow.mount\nallow.mount.zfs\nallow.raw_sockets\nenforce_statfs=1\nsecurelevel=2\n\
+             sysvmsg=new\nsysvsem=new\nsysvshm=new\n",
+            params
+        );
+        // an indestructible delegated dataset blocks the delete
+        rec.clear();
+        assert!(jails::delete(&conf, &uuid).is_err());
+        assert!(!rec.calls().iter().any(|c| c.starts_with("zfs destroy")));
+        let update = JailUpdate::from_reader(r#"{"indestructible_delegated": false}"#.as_bytes())
+            .unwrap();
+        jails::update(&conf, &uuid, &update).unwrap();
+        rec.reply("jls", "");
+        rec.clear();
+        jails::delete(&conf, &uuid).unwrap();
+        assert!(rec.calls().contains(&format!("zfs destroy -r {}", data)));
+    }
+
+    #[test]
+    fn filesystems() {
+        let (rec, conf) = setup();
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap())
+            .unwrap();
+        jail.filesystems = vec![Filesystem {
+            source: String::new(),
+            target: String::from("/tmp"),
+            fs_type: String::from("tmpfs"),
+            options: vec![String::from("nosuid"), String::from("size=1g")],
+        }];
+        jails::create(&conf, jail).unwrap();
+        rec.reply("/sbin/ifconfig epair create", "epair7a\n");
+        rec.reply("jail -i", "42\n");
+        {
+            let db = JDB::open(&conf).unwrap();
+            db.get(&Uuid::parse_str(UUID).unwrap()).unwrap().start(&conf).unwrap();
+        }
+        let mut filesystems = String::new();
+        File::open(jail_root(&conf).join("config").join("filesystems"))
+            .unwrap()
+            .read_to_string(&mut filesystems)
+            .unwrap();
+        let mdata_dir = mdata::socket_dir(&conf, &Uuid::parse_str(UUID).unwrap());
+        assert_eq!(
+            format!(
+                "nullfs\t{}\t/.zonecontrol\tro\ntmpfs\ttmpfs\t/tmp\tnosuid,size=1g\n",
+                mdata_dir.to_string_lossy()
+            ),
+            filesystems
+        );
+    }
+
+    #[test]
+    fn clear_zfs_properties() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let root = format!("{}/{}", conf.settings.pool, UUID);
+        let set = JailUpdate::from_reader(
+            r#"{"zfs_root_recordsize": 8192, "refquota": 10}"#.as_bytes(),
+        ).unwrap();
+        jails::update(&conf, &uuid, &set).unwrap();
+        rec.clear();
+        let clear = JailUpdate::from_reader(
+            r#"{"zfs_root_recordsize": null, "refquota": null}"#.as_bytes(),
+        ).unwrap();
+        jails::update(&conf, &uuid, &clear).unwrap();
+        assert!(rec.calls().contains(&format!("zfs inherit recordsize {}", root)));
+        assert!(rec.calls().contains(&format!("zfs set refquota=none {}", root)));
+        let config = JDB::open(&conf).unwrap().get(&uuid).unwrap().config;
+        assert_eq!(None, config.zfs_root_recordsize);
+        assert_eq!(None, config.refquota);
+        assert_eq!(Some(String::from("lz4")), config.zfs_root_compression);
+    }
+
+    #[test]
+    fn history() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let update = JailUpdate::from_reader(
+            r#"{"alias": "changed", "quota": 20, "atime": false, "refreservation": 10}"#.as_bytes(),
+        ).unwrap();
+        jails::update(&conf, &uuid, &update).unwrap();
+        let root = format!("{}/{}", conf.settings.pool, UUID);
+        assert!(rec.calls().contains(&format!("zfs set atime=off refreservation=10G {}", root)));
+        {
+            let db = JDB::open(&conf).unwrap();
+            assert_eq!("changed", db.get(&uuid).unwrap().config.alias);
+            let history = db.history(&uuid).unwrap();
+            assert_eq!(
+                vec!["create", "update"],
+                history.iter().map(|e| e.action.as_str()).collect::<Vec<&str>>()
+            );
+            assert_eq!(Some(20), history[1].payload.as_ref().unwrap()["quota"].as_u64());
+            assert_eq!(conf.req_id, history[1].req_id);
+        }
+        rec.clear();
+        jails::rollback(&conf, &uuid, 1).unwrap();
+        assert!(rec.calls().contains(&format!("zfs set quota=100G {}", root)));
+        assert!(rec.calls().contains(&format!("zfs inherit atime {}", root)));
+        assert!(rec.calls().contains(&format!("zfs set refreservation=none {}", root)));
+        {
+            let db = JDB::open(&conf).unwrap();
+            assert_eq!("test", db.get(&uuid).unwrap().config.alias);
+            let history = db.history(&uuid).unwrap();
+            assert_eq!(3, history[2].version);
+            assert_eq!("rollback", history[2].action);
+        }
+        assert!(jails::rollback(&conf, &uuid, 7).is_err());
+    }
+
+    #[test]
+    fn delete() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let pool = conf.settings.pool.clone();
+        let snap = format!("{}/{}@{}", pool, IMAGE, UUID);
+        rec.reply("zfs get", format!("{}/{}\torigin\t{}\t-\n", pool, UUID, snap).as_str());
+        rec.clear();
+        jails::delete(&conf, &Uuid::parse_str(UUID).unwrap()).unwrap();
+        assert_eq!(
+            vec![
+                String::from("jls -q jid name"),
+                format!("zfs get -p -H origin {}/{}", pool, UUID),
+                format!("zfs destroy {}/{}", pool, UUID),
+                format!("zfs destroy {}", snap),
+            ],
+            rec.calls()
+        );
+        let db = JDB::open(&conf).unwrap();
+        assert!(db.get(&Uuid::parse_str(UUID).unwrap()).is_err());
+    }
+
+    #[test]
+    fn delete_failed() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let pool = conf.settings.pool.clone();
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let snap = format!("{}/{}@{}", pool, IMAGE, UUID);
+        rec.reply("zfs get", format!("{}/{}\torigin\t{}\t-\n", pool, UUID, snap).as_str());
+        // a snapshot that can't be destroyed doesn't keep the entry around
+        rec.fail(format!("zfs destroy {}", snap).as_str());
+        jails::delete(&conf, &uuid).unwrap();
+        assert!(JDB::open(&conf).unwrap().get(&uuid).is_err());
+
+        // a dataset that is still there does
+        fs::remove_dir_all(jail_root(&conf).join("config")).unwrap();
+        provision(&conf);
+        rec.fail(format!("zfs destroy {}/{}", pool, UUID).as_str());
+        rec.reply("zfs list", format!("{}/{}\t1024\t2048\t1024\t/{}/{}\n", pool, UUID, pool, UUID).as_str());
+        assert!(jails::delete(&conf, &uuid).is_err());
+        assert!(JDB::open(&conf).unwrap().get(&uuid).is_ok());
+    }
+
+    #[test]
+    fn state() {
+        let (rec, conf) = setup();
+        provision(&conf);
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        rec.reply("jail -i", "42\n");
+        jails::start(&conf, &uuid).unwrap();
+        assert_eq!(JailState::Running, JDB::open(&conf).unwrap().get(&uuid).unwrap().idx.state);
+        rec.reply("jls", format!("42 {u}\n43 {u}.{u}\n", u = UUID).as_str());
+        rec.fail("jail -r");
+        assert!(jails::stop(&conf, &uuid).is_err());
+        assert_eq!(JailState::Failed, JDB::open(&conf).unwrap().get(&uuid).unwrap().idx.state);
+    }
+}
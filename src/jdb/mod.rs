@@ -3,10 +3,12 @@
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::cmp::PartialEq;
 use std::collections::HashMap;
+use std::fmt;
 use std::slice::Iter;
 
 use prettytable::Table;
@@ -14,15 +16,61 @@ use prettytable::format;
 use prettytable::row::Row;
 use prettytable::cell::Cell;
 use uuid::Uuid;
+use serde::Serialize;
 use serde_json;
+use serde_json::Value;
+use chrono::{DateTime, Utc};
+use tempfile::NamedTempFile;
 
 use jails::Jail;
 use jails;
 use jail_config::JailConfig;
+use list;
+use list::{Filter, Sort};
+use lock;
 
-use errors::{NotFoundError, ConflictError, GenericError};
+use errors::{NotFoundError, ConflictError, GenericError, LookupError};
 use config::Config;
 
+/// Version of the index and config layout written by this vmadm,
+/// older databases are migrated on open, newer ones are refused.
+pub const VERSION: u32 = 2;
+
+/// Lifecycle state of a jail
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JailState {
+    /// being created, if it stays in this state creation was interrupted
+    Provisioning,
+    /// created but never started
+    Installed,
+    /// being started
+    Starting,
+    /// running
+    Running,
+    /// being stopped
+    Stopping,
+    /// stopped
+    Stopped,
+    /// an operation on the jail failed
+    Failed,
+}
+
+impl fmt::Display for JailState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            JailState::Provisioning => "provisioning",
+            JailState::Installed => "installed",
+            JailState::Starting => "starting",
+            JailState::Running => "running",
+            JailState::Stopping => "stopping",
+            JailState::Stopped => "stopped",
+            JailState::Failed => "failed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 /// `JailDB` index entry
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IdxEntry {
@@ -31,18 +79,36 @@ pub struct IdxEntry {
     pub uuid: Uuid,
     /// ZFS dataset root
     pub root: String,
-    state: String,
+    /// lifecycle state as last recorded by vmadm
+    pub state: JailState,
+    /// time of the last state transition
+    pub state_changed: DateTime<Utc>,
     jail_type: String,
 }
 
+impl IdxEntry {
+    /// State combined with what `jls` reports, jails can be started
+    /// or stopped outside of vmadm.
+    pub fn current_state(&self, running: bool) -> JailState {
+        match (running, self.state) {
+            (false, JailState::Running) => JailState::Stopped,
+            (true, JailState::Installed) |
+            (true, JailState::Stopped) => JailState::Running,
+            (_, state) => state,
+        }
+    }
+}
+
 #[cfg(test)]
 impl IdxEntry {
+    /// Empty entry for tests
     pub fn empty() -> Self {
         IdxEntry {
-            version: 1,
+            version: VERSION,
             uuid: Uuid::nil(),
             root: String::from("zroot"),
-            state: String::from("stopped"),
+            state: JailState::Stopped,
+            state_changed: Utc::now(),
             jail_type: String::from("jail"),
         }
     }
@@ -55,6 +121,28 @@ impl PartialEq for IdxEntry {
     }
 }
 
+/// History action of metadata writes from inside the jail, a row of
+/// them is kept as a single version
+pub static GUEST_METADATA: &'static str = "guest-metadata";
+
+/// A version of a jail config in its history
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    /// version of the config, counting from 1
+    pub version: u32,
+    /// when this version was written
+    pub timestamp: DateTime<Utc>,
+    /// ID of the vmadm run that wrote it
+    pub req_id: Uuid,
+    /// what wrote it: create, update, rollback or initial for configs
+    /// that existed before the history was kept
+    pub action: String,
+    /// payload of the action, like the update
+    pub payload: Option<Value>,
+    /// the config itself
+    pub config: JailConfig,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Index {
     pub version: u32,
@@ -71,44 +159,42 @@ pub struct JDB<'a> {
 
 impl<'a> JDB<'a> {
     /// Opens an JDB index file.
-    ///„ # Arguments
+    /// # Arguments
     ///
-    /// * `path` - Path of the **index file**, the locatio of the
-    ///            file is also where the seperate configs live.
+    /// * `config` - vmadm config, the index file and the seperate
+    ///              configs live in `settings.conf_dir`.
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
     /// // Open jail config folder in /usr/local/etc/vmadm
-    /// use jdb::JDB;
-    /// let db = JDB::open("/usr/local/etc/vmadm/index");
+    /// use vmadm::{Config, JDB};
+    /// let config = Config::new().unwrap();
+    /// let db = JDB::open(&config).unwrap();
     /// ```
 
     pub fn open(config: &'a Config) -> Result<Self, Box<Error>> {
-        let mut idx_file = PathBuf::from(config.settings.conf_dir.as_str());
-        idx_file.push("index");
-        debug!("Opening jdb"; "index" => idx_file.to_string_lossy().as_ref());
-        match File::open(idx_file) {
-            Ok(file) => {
-                let index: Index = serde_json::from_reader(file)?;
+        let _lock = lock::db(config)?;
+        match JDB::read_index(config)? {
+            Some(index) => {
                 debug!("Found {} entries", index.entries.len());
                 Ok(JDB {
                     index: index,
                     config: config,
-                    jails: jails::list()?,
+                    jails: jails::list(config)?,
                 })
             }
-            Err(_) => {
+            None => {
                 warn!("No database found creating new one.");
                 let entries: Vec<IdxEntry> = Vec::new();
                 let index: Index = Index {
-                    version: 0,
+                    version: VERSION,
                     entries: entries,
                 };
                 let db = JDB {
                     index: index,
                     config: config,
-                    jails: jails::list()?,
+                    jails: jails::list(config)?,
                 };
                 db.save()?;
                 Ok(db)
@@ -118,49 +204,105 @@ impl<'a> JDB<'a> {
         }
     }
 
+    /// Reads the index file, `None` if there is none yet. Callers need
+    /// to hold the database lock.
+    fn read_index(config: &Config) -> Result<Option<Index>, Box<Error>> {
+        let mut idx_file = PathBuf::from(config.settings.conf_dir.as_str());
+        idx_file.push("index");
+        debug!("Opening jdb"; "index" => idx_file.to_string_lossy().as_ref());
+        if !idx_file.exists() {
+            return Ok(None);
+        }
+        let file = File::open(idx_file)?;
+        let mut index: Value = serde_json::from_reader(file)?;
+        let version = layout_version(&index);
+        if version > VERSION {
+            crit!("Database is newer than this vmadm"; "version" => version);
+            return Err(GenericError::bx(
+                format!(
+                    "jdb has version {} but this vmadm only supports up to {}, \
+                     please upgrade vmadm",
+                    version,
+                    VERSION
+                ).as_str(),
+            ));
+        }
+        if version < VERSION {
+            migrate(config, &mut index, version)?;
+        }
+        let index: Index = serde_json::from_value(index)?;
+        Ok(Some(index))
+    }
+
+    /// Re-reads the index so changes by other vmadm processes since we
+    /// opened the database are not lost when saving.
+    fn reload(&mut self) -> Result<usize, Box<Error>> {
+        if let Some(index) = JDB::read_index(self.config)? {
+            self.index = index;
+        }
+        Ok(self.index.entries.len())
+    }
+
     /// Inserts a config into the database, writes the config file
     /// and adds it to the index.
     pub fn insert(self: &'a mut JDB<'a>, config: JailConfig) -> Result<IdxEntry, Box<Error>> {
         debug!("Inserting new vm"; "vm" => &config.uuid.hyphenated().to_string());
-        match self.find(&config.uuid) {
-            None => {
-                let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
-                path.push(config.uuid.hyphenated().to_string());
-                path.set_extension("json");
-                let file = File::create(path)?;
-                let mut root = String::from(self.config.settings.pool.as_str());
-                root.push('/');
-                root.push_str(&config.uuid.hyphenated().to_string());
-                let e = IdxEntry {
-                    version: 0,
-                    uuid: config.uuid.clone(),
-                    state: String::from("stopped"),
-                    jail_type: String::from("base"),
-                    root: root.clone(),
-                };
-                self.index.entries.push(e);
-                self.save()?;
-                serde_json::to_writer(file, &config)?;
-                // This is ugly but I don't know any better.
-                Ok(IdxEntry {
-                    version: 0,
-                    uuid: config.uuid.clone(),
-                    state: String::from("stopped"),
-                    jail_type: String::from("base"),
-                    root: root.clone(),
-                })
+        let _lock = lock::db(self.config)?;
+        self.reload()?;
+        // a failed create only leaves its entry behind to be seen, the
+        // next create of the same jail takes its place
+        let existing = match self.find(&config.uuid) {
+            Some(index) if self.index.entries[index].state == JailState::Failed => {
+                warn!("Replacing failed entry {}", config.uuid);
+                Some(index)
             }
             Some(_) => {
                 warn!("Doublicate entry {}", config.uuid);
-                Err(ConflictError::bx(&config.uuid))
+                return Err(ConflictError::bx(&config.uuid));
+            }
+            None => None,
+        };
+        let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
+        path.push(config.uuid.hyphenated().to_string());
+        path.set_extension("json");
+        let mut root = String::from(self.config.settings.pool.as_str());
+        root.push('/');
+        root.push_str(&config.uuid.hyphenated().to_string());
+        let e = IdxEntry {
+            version: VERSION,
+            uuid: config.uuid.clone(),
+            state: JailState::Provisioning,
+            state_changed: Utc::now(),
+            jail_type: String::from("base"),
+            root: root.clone(),
+        };
+        if self.config.dry_run {
+            println!("[dry-run] insert {} into jdb", config.uuid);
+        } else {
+            // write the config first so the index never points
+            // to a missing file
+            write_json(&path, &config)?;
+            match existing {
+                Some(index) => self.index.entries[index] = e.clone(),
+                None => self.index.entries.push(e.clone()),
             }
+            self.save()?;
+            self.record(&config, "create", None)?;
         }
+        Ok(e)
     }
 
-    /// Inserts a config into the database, writes the config file
-    /// and adds it to the index.
-    pub fn update(self: &'a mut JDB<'a>, config: JailConfig) -> Result<i32, Box<Error>> {
+    /// Replaces the config of a jail, the new version is added to its
+    /// history along with the action and payload that produced it.
+    pub fn update(
+        self: &'a mut JDB<'a>,
+        config: JailConfig,
+        action: &str,
+        payload: Option<Value>,
+    ) -> Result<i32, Box<Error>> {
         debug!("Updating vm"; "vm" => &config.uuid.hyphenated().to_string());
+        let _lock = lock::db(self.config)?;
+        self.reload()?;
         match self.find(&config.uuid) {
             None => {
                 warn!("Missing entry {}", config.uuid; "vm" => &config.uuid.hyphenated().to_string());
@@ -172,33 +314,148 @@ impl<'a> JDB<'a> {
                 path.push(config.uuid.hyphenated().to_string());
                 path.set_extension("json");
                 debug!("Updating config file"; "file" => path.to_str(), "vm" => &config.uuid.hyphenated().to_string());
-                let file = File::create(path)?;
-                serde_json::to_writer(file, &config)?;
-                // This is ugly but I don't know any better.
+                if self.config.dry_run {
+                    println!("[dry-run] update {} in jdb", config.uuid);
+                    return Ok(0);
+                }
+                let mut history = self.history(&config.uuid)?;
+                if history.is_empty() {
+                    // keep the config from before the history existed
+                    let old = JailConfig::from_reader(File::open(&path)?)?;
+                    history.push(self.history_entry(1, old, "initial", None));
+                }
+                write_json(&path, &config)?;
+                // the jail writes its metadata as often as it likes, only
+                // its latest write is kept in a row of them
+                let coalesce = match history.last() {
+                    Some(last) => action == GUEST_METADATA && last.action == action,
+                    None => false,
+                };
+                if coalesce {
+                    history.pop();
+                }
+                let version = history.len() as u32 + 1;
+                history.push(self.history_entry(version, config.clone(), action, payload));
+                write_json(&self.history_path(&config.uuid), &history)?;
                 Ok(0)
             }
         }
     }
 
+    /// Records a state transition of a jail.
+    pub fn set_state(&mut self, uuid: &Uuid, state: JailState) -> Result<JailState, Box<Error>> {
+        debug!("Changing state"; "vm" => uuid.hyphenated().to_string(), "state" => state.to_string());
+        if self.config.dry_run {
+            return Ok(state);
+        }
+        let _lock = lock::db(self.config)?;
+        self.reload()?;
+        match self.index.entries.iter_mut().find(|e| e.uuid == *uuid) {
+            None => return Err(NotFoundError::bx(uuid)),
+            Some(entry) => {
+                entry.state = state;
+                entry.state_changed = Utc::now();
+            }
+        }
+        self.save()?;
+        Ok(state)
+    }
+
     /// Removes a jail with a given uuid from the index and removes it's
     /// config file.
     pub fn remove(self: &'a mut JDB<'a>, uuid: &Uuid) -> Result<usize, Box<Error>> {
         debug!("Removing vm"; "vm" => uuid.hyphenated().to_string());
+        let _lock = lock::db(self.config)?;
+        self.reload()?;
         match self.find(uuid) {
             None => Err(NotFoundError::bx(uuid)),
             Some(index) => {
-                // remove the config file first
+                if self.config.dry_run {
+                    println!("[dry-run] remove {} from jdb", uuid);
+                    return Ok(index);
+                }
+                // remove the index entry first so the index never
+                // points to a missing file
+                self.index.entries.remove(index);
+                self.save()?;
                 let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
                 path.push(uuid.hyphenated().to_string());
                 path.set_extension("json");
-                fs::remove_file(&path)?;
-                self.index.entries.remove(index);
-                self.save()?;
+                if path.exists() {
+                    fs::remove_file(&path)?;
+                }
+                let history = self.history_path(uuid);
+                if history.exists() {
+                    fs::remove_file(&history)?;
+                }
                 Ok(index)
             }
         }
     }
 
+    /// All versions of the config of a jail, oldest first
+    pub fn history(&self, uuid: &Uuid) -> Result<Vec<HistoryEntry>, Box<Error>> {
+        let path = self.history_path(uuid);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let history: Vec<HistoryEntry> = serde_json::from_reader(File::open(path)?)?;
+        Ok(history)
+    }
+
+    /// Prints the history of a jail, as a JSON array if `json` is set
+    pub fn print_history(&self, uuid: &Uuid, json: bool) -> Result<i32, Box<Error>> {
+        let history = self.history(uuid)?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&history)?);
+            return Ok(0);
+        }
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_CLEAN);
+        table.add_row(row!["VERSION", "TIMESTAMP", "REQ_ID", "ACTION"]);
+        for entry in history.iter() {
+            table.add_row(Row::new(vec![
+                Cell::new(entry.version.to_string().as_str()),
+                Cell::new(entry.timestamp.to_rfc3339().as_str()),
+                Cell::new(entry.req_id.hyphenated().to_string().as_str()),
+                Cell::new(entry.action.as_str()),
+            ]));
+        }
+        table.printstd();
+        Ok(0)
+    }
+
+    /// Starts the history of a new jail
+    fn record(&self, config: &JailConfig, action: &str, payload: Option<Value>) -> Result<usize, Box<Error>> {
+        let history = vec![self.history_entry(1, config.clone(), action, payload)];
+        write_json(&self.history_path(&config.uuid), &history)?;
+        Ok(history.len())
+    }
+
+    fn history_entry(
+        &self,
+        version: u32,
+        config: JailConfig,
+        action: &str,
+        payload: Option<Value>,
+    ) -> HistoryEntry {
+        HistoryEntry {
+            version: version,
+            timestamp: Utc::now(),
+            req_id: self.config.req_id,
+            action: String::from(action),
+            payload: payload,
+            config: config,
+        }
+    }
+
+    fn history_path(&self, uuid: &Uuid) -> PathBuf {
+        let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
+        path.push(uuid.hyphenated().to_string());
+        path.set_extension("history");
+        path
+    }
+
     /// Reads the config file for a given entry
     fn config(self: &'a JDB<'a>, entry: &IdxEntry) -> Result<JailConfig, Box<Error>> {
         debug!("Loading vm config"; "vm" => &entry.uuid.hyphenated().to_string());
@@ -210,13 +467,15 @@ impl<'a> JDB<'a> {
             None => Err(GenericError::bx("could not generate vm config path")),
         }
     }
-    /// Saves the database
+    /// Saves the database, callers need to hold the database lock.
     fn save(self: &'a JDB<'a>) -> Result<usize, Box<Error>> {
         debug!("Saving database");
+        if self.config.dry_run {
+            return Ok(self.index.entries.len());
+        }
         let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
         path.push("index");
-        let file = File::create(path)?;
-        serde_json::to_writer(file, &self.index)?;
+        write_json(&path, &self.index)?;
         Ok(self.index.entries.len())
     }
 
@@ -243,6 +502,47 @@ impl<'a> JDB<'a> {
         }
     }
 
+    /// Resolves a full UUID, an exact alias or a unique UUID prefix,
+    /// in that order, to the UUID of a jail.
+    pub fn resolve(self: &'a JDB<'a>, name: &str) -> Result<Uuid, Box<Error>> {
+        if let Ok(uuid) = Uuid::parse_str(name) {
+            return match self.find(&uuid) {
+                Some(_) => Ok(uuid),
+                None => Err(NotFoundError::bx(&uuid)),
+            };
+        }
+        let mut aliases = Vec::new();
+        for entry in self.iter() {
+            match self.config(entry) {
+                Ok(ref conf) if conf.alias == name => aliases.push(entry.uuid),
+                Ok(_) => (),
+                Err(e) => warn!("Could not read config: {}", e; "vm" => entry.uuid.hyphenated().to_string()),
+            }
+        }
+        if !aliases.is_empty() {
+            return single(name, aliases);
+        }
+        let prefix = name.to_lowercase();
+        let prefixed = self.iter()
+            .filter(|e| !prefix.is_empty() && e.uuid.hyphenated().to_string().starts_with(prefix.as_str()))
+            .map(|e| e.uuid)
+            .collect();
+        single(name, prefixed)
+    }
+
+    /// Configs of all jails, unreadable ones are logged and skipped
+    pub fn configs(self: &'a JDB<'a>) -> Vec<JailConfig> {
+        self.iter()
+            .filter_map(|entry| match self.config(entry) {
+                Ok(conf) => Some(conf),
+                Err(e) => {
+                    warn!("Could not read config: {}", e; "vm" => entry.uuid.hyphenated().to_string());
+                    None
+                }
+            })
+            .collect()
+    }
+
     /// Finds an entry for a given uuid
     fn find(self: &'a JDB<'a>, uuid: &Uuid) -> Option<usize> {
         self.index.entries.iter().position(|x| x.uuid == *uuid)
@@ -253,19 +553,57 @@ impl<'a> JDB<'a> {
         self.index.entries.iter()
     }
 
+    /// All jails matching every filter as JSON, sorted by `sort`.
+    /// Besides the config fields each jail has the `state`, `type`
+    /// and `ram` fields SmartOS lists.
+    pub fn list(
+        self: &'a JDB<'a>,
+        filters: &[Filter],
+        sort: &[Sort],
+    ) -> Result<Vec<Value>, Box<Error>> {
+        let mut jails = Vec::new();
+        for e in self.iter() {
+            let jail = self.list_entry(e)?;
+            if filters.iter().all(|f| f.matches(&jail)) {
+                jails.push(jail);
+            }
+        }
+        list::sort(&mut jails, sort);
+        Ok(jails)
+    }
+
     /// Prints the jdb database
-    pub fn print(self: &'a JDB<'a>, headerless: bool, parsable: bool) -> Result<i32, Box<Error>> {
+    pub fn print(
+        self: &'a JDB<'a>,
+        filters: &[Filter],
+        sort: &[Sort],
+        columns: &[String],
+        headerless: bool,
+        parsable: bool,
+    ) -> Result<i32, Box<Error>> {
         let mut table = Table::new();
         table.set_format(*format::consts::FORMAT_CLEAN);
         if !headerless {
+            let header: Vec<String> = columns.iter().map(|c| c.to_uppercase()).collect();
             if parsable {
-                println!("{}:{}:{}:{}:{}", "UUID", "TYPE", "RAM", "STATE", "ALIAS");
+                println!("{}", header.join(":"));
             } else {
-                table.add_row(row!["UUID", "TYPE", "RAM", "STATE", "ALIAS"]);
+                table.add_row(Row::new(header.iter().map(|h| Cell::new(h)).collect()));
             }
         }
-        for e in self.iter() {
-            self.print_entry(e, &mut table, parsable)?;
+        for jail in self.list(filters, sort)? {
+            let row: Vec<String> = columns
+                .iter()
+                .map(|c| match list::field(&jail, c.as_str()) {
+                    Some(value) => list::show(value),
+                    None => String::from("-"),
+                })
+                .collect();
+            if parsable {
+                println!("{}", row.join(":"));
+            } else {
+                table.add_row(Row::new(row.iter().map(|c| Cell::new(c)).collect()));
+            }
         }
         if !parsable {
             table.printstd()
@@ -273,13 +611,8 @@ impl<'a> JDB<'a> {
         Ok(0)
     }
 
-    /// Gets the config and prints an etry
-    fn print_entry(
-        self: &'a JDB<'a>,
-        entry: &IdxEntry,
-        table: &mut Table,
-        parsable: bool,
-    ) -> Result<i32, Box<Error>> {
+    /// Gets the config of an entry as JSON with the listing fields
+    fn list_entry(self: &'a JDB<'a>, entry: &IdxEntry) -> Result<Value, Box<Error>> {
         let conf = self.config(entry)?;
         let id = match self.jails.get(&conf.uuid.hyphenated().to_string()) {
             Some(jail) => jail.id,
@@ -293,28 +626,333 @@ impl<'a> JDB<'a> {
                 "OS"
             }
         };
-        let state = match id {
-            0 => &entry.state,
-            _ => "running",
-        };
-        if parsable {
-            println!(
-                "{}:{}:{}:{}:{}",
-                conf.uuid,
-                os,
-                conf.max_physical_memory,
-                state,
-                conf.alias
+        let state = entry.current_state(id != 0);
+        let mut jail = serde_json::to_value(&conf)?;
+        if let Some(obj) = jail.as_object_mut() {
+            obj.insert(String::from("type"), Value::from(os));
+            obj.insert(String::from("ram"), Value::from(conf.max_physical_memory));
+            obj.insert(String::from("state"), serde_json::to_value(state)?);
+            obj.insert(
+                String::from("state_changed"),
+                serde_json::to_value(entry.state_changed)?,
             );
+        }
+        Ok(jail)
+    }
+}
+
+/// The only match of a lookup
+fn single(name: &str, matches: Vec<Uuid>) -> Result<Uuid, Box<Error>> {
+    if matches.len() == 1 {
+        Ok(matches[0])
+    } else {
+        Err(LookupError::bx(name, matches))
+    }
+}
+
+/// A step in upgrading the layout of the index and configs.
+struct Migration {
+    /// version this migration upgrades to
+    version: u32,
+    /// upgrades a single index entry
+    entry: fn(&mut Value) -> Result<(), Box<Error>>,
+    /// upgrades the config of a jail
+    config: fn(&mut Value) -> Result<(), Box<Error>>,
+}
+
+/// All migrations in order, the last one has to upgrade to `VERSION`.
+static MIGRATIONS: &'static [Migration] = &[
+    Migration {
+        version: 1,
+        entry: migrate_entry_1,
+        config: migrate_none,
+    },
+    Migration {
+        version: 2,
+        entry: migrate_entry_2,
+        config: migrate_none,
+    },
+];
+
+/// Version 0 entries could miss their version, state and type.
+fn migrate_entry_1(entry: &mut Value) -> Result<(), Box<Error>> {
+    let entry = object(entry)?;
+    entry.entry("state").or_insert_with(|| Value::from("stopped"));
+    entry.entry("jail_type").or_insert_with(|| Value::from("base"));
+    Ok(())
+}
+
+/// Version 2 tracks the lifecycle state and when it last changed.
+fn migrate_entry_2(entry: &mut Value) -> Result<(), Box<Error>> {
+    let entry = object(entry)?;
+    let known = match entry.get("state") {
+        Some(state) => serde_json::from_value::<JailState>(state.clone()).is_ok(),
+        None => false,
+    };
+    if !known {
+        entry.insert(String::from("state"), Value::from("stopped"));
+    }
+    entry.insert(
+        String::from("state_changed"),
+        serde_json::to_value(Utc::now())?,
+    );
+    Ok(())
+}
+
+fn migrate_none(_value: &mut Value) -> Result<(), Box<Error>> {
+    Ok(())
+}
+
+fn object(value: &mut Value) -> Result<&mut serde_json::Map<String, Value>, Box<Error>> {
+    match value.as_object_mut() {
+        Some(map) => Ok(map),
+        None => Err(GenericError::bx("jdb entry is not an object")),
+    }
+}
+
+/// Version of an index or entry, databases written before versioning
+/// was enforced count as 0.
+fn layout_version(value: &Value) -> u32 {
+    match value.get("version").and_then(|v| v.as_u64()) {
+        Some(version) => version as u32,
+        None => 0,
+    }
+}
+
+/// Upgrades the index and all jail configs from `from` to `VERSION` in
+/// place, the old files are backed up first.
+fn migrate(config: &Config, index: &mut Value, from: u32) -> Result<(), Box<Error>> {
+    info!("Migrating jdb"; "from" => from, "to" => VERSION);
+    let conf_dir = PathBuf::from(config.settings.conf_dir.as_str());
+    if config.dry_run {
+        println!("[dry-run] migrate jdb from version {} to {}", from, VERSION);
+    } else {
+        backup(&conf_dir, from)?;
+    }
+    let entries = match index.get_mut("entries").and_then(|e| e.as_array_mut()) {
+        Some(entries) => entries,
+        None => return Err(GenericError::bx("jdb index has no entries")),
+    };
+    for entry in entries.iter_mut() {
+        let version = layout_version(entry);
+        if version > VERSION {
+            return Err(GenericError::bx("jdb entry is newer than this vmadm"));
+        }
+        let uuid = match entry.get("uuid").and_then(|u| u.as_str()) {
+            Some(uuid) => String::from(uuid),
+            None => return Err(GenericError::bx("jdb entry without uuid")),
+        };
+        let mut path = conf_dir.clone();
+        path.push(uuid.as_str());
+        path.set_extension("json");
+        let mut jail: Option<Value> = if path.exists() {
+            Some(serde_json::from_reader(File::open(&path)?)?)
         } else {
-            table.add_row(Row::new(vec![
-                Cell::new(conf.uuid.hyphenated().to_string().as_str()),
-                Cell::new(os),
-                Cell::new(conf.max_physical_memory.to_string().as_str()),
-                Cell::new(state),
-                Cell::new(conf.alias.as_str()),
-            ]));
+            warn!("Config missing, only migrating index"; "vm" => uuid.as_str());
+            None
         };
-        Ok(0)
+        for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+            debug!("Migrating entry"; "vm" => uuid.as_str(), "to" => migration.version);
+            (migration.entry)(entry)?;
+            if let Some(ref mut jail) = jail {
+                (migration.config)(jail)?;
+            }
+        }
+        object(entry)?.insert(String::from("version"), Value::from(VERSION));
+        if let Some(jail) = jail {
+            if !config.dry_run {
+                write_json(&path, &jail)?;
+            }
+        }
+    }
+    object(index)?.insert(String::from("version"), Value::from(VERSION));
+    if !config.dry_run {
+        let mut path = conf_dir.clone();
+        path.push("index");
+        write_json(&path, index)?;
+    }
+    Ok(())
+}
+
+/// Copies the index and all configs into `backup-v<version>-<time>`.
+fn backup(conf_dir: &Path, version: u32) -> Result<PathBuf, Box<Error>> {
+    let mut dir = conf_dir.to_path_buf();
+    dir.push(format!(
+        "backup-v{}-{}",
+        version,
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    fs::create_dir(&dir)?;
+    for entry in fs::read_dir(conf_dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path.file_name() {
+            if name == "index" || path.extension().map_or(false, |e| e == "json") {
+                fs::copy(&path, dir.join(name))?;
+            }
+        }
+    }
+    info!("Backed up jdb"; "backup" => dir.to_string_lossy().as_ref());
+    Ok(dir)
+}
+
+/// Writes `value` as JSON to `path` atomically: it is written to a
+/// temporary file in the same folder, synced and then renamed over
+/// `path`, so a crash leaves either the old or the new file.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<Error>> {
+    let dir = match path.parent() {
+        Some(dir) => dir,
+        None => return Err(GenericError::bx("file has no parent folder")),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    file.sync_all()?;
+    file.persist(path)?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use uuid::Uuid;
+    use config::Config;
+    use executor::RecordingExecutor;
+    use jail_config::JailConfig;
+    use jdb::*;
+
+    fn jail(uuid: Uuid) -> JailConfig {
+        aliased(uuid, "test")
+    }
+
+    fn aliased(uuid: Uuid, alias: &str) -> JailConfig {
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap())
+            .unwrap();
+        jail.uuid = uuid;
+        jail.alias = String::from(alias);
+        jail
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let conf = Config::test(Box::new(RecordingExecutor::new()));
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut db1 = JDB::open(&conf).unwrap();
+        let mut db2 = JDB::open(&conf).unwrap();
+        db1.insert(jail(a)).unwrap();
+        db2.insert(jail(b)).unwrap();
+        let db = JDB::open(&conf).unwrap();
+        assert_eq!(vec![a, b], db.iter().map(|e| e.uuid).collect::<Vec<Uuid>>());
+        // no temporary files are left behind
+        let mut files: Vec<String> = fs::read_dir(conf.settings.conf_dir.as_str())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        let mut expected = vec![
+            String::from(".lock"),
+            format!("{}.history", a),
+            format!("{}.history", b),
+            format!("{}.json", a),
+            format!("{}.json", b),
+            String::from("index"),
+        ];
+        expected.sort();
+        assert_eq!(expected, files);
+    }
+
+    fn write_index(conf: &Config, index: &str) {
+        let mut path = PathBuf::from(conf.settings.conf_dir.as_str());
+        path.push("index");
+        File::create(path).unwrap().write_all(index.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn migrate_v0() {
+        let conf = Config::test(Box::new(RecordingExecutor::new()));
+        let uuid = Uuid::new_v4();
+        write_index(
+            &conf,
+            format!(
+                r#"{{"version": 0, "entries": [{{"uuid": "{}", "root": "zroot/{}"}}]}}"#,
+                uuid,
+                uuid
+            ).as_str(),
+        );
+        let mut path = PathBuf::from(conf.settings.conf_dir.as_str());
+        path.push(format!("{}.json", uuid));
+        serde_json::to_writer(File::create(path).unwrap(), &jail(uuid)).unwrap();
+
+        let db = JDB::open(&conf).unwrap();
+        let entry = db.iter().next().unwrap();
+        assert_eq!(VERSION, entry.version);
+        assert_eq!(JailState::Stopped, entry.state);
+        assert!(db.get(&uuid).is_ok());
+
+        let mut path = PathBuf::from(conf.settings.conf_dir.as_str());
+        path.push("index");
+        let index: Value = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        assert_eq!(Some(VERSION as u64), index["version"].as_u64());
+        let backups: Vec<PathBuf> = fs::read_dir(conf.settings.conf_dir.as_str())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.is_dir())
+            .collect();
+        assert_eq!(1, backups.len());
+        assert!(backups[0].join("index").exists());
+        assert!(backups[0].join(format!("{}.json", uuid)).exists());
+    }
+
+    #[test]
+    fn refuse_newer() {
+        let conf = Config::test(Box::new(RecordingExecutor::new()));
+        write_index(
+            &conf,
+            format!(r#"{{"version": {}, "entries": []}}"#, VERSION + 1).as_str(),
+        );
+        assert!(JDB::open(&conf).is_err());
+    }
+
+    #[test]
+    fn resolve() {
+        let conf = Config::test(Box::new(RecordingExecutor::new()));
+        let a = Uuid::parse_str("aaaa1111-0000-4000-8000-000000000000").unwrap();
+        let b = Uuid::parse_str("aaaa2222-0000-4000-8000-000000000000").unwrap();
+        let c = Uuid::parse_str("cccc0000-0000-4000-8000-000000000000").unwrap();
+        JDB::open(&conf).unwrap().insert(aliased(a, "web")).unwrap();
+        JDB::open(&conf).unwrap().insert(aliased(b, "db")).unwrap();
+        JDB::open(&conf).unwrap().insert(aliased(c, "db")).unwrap();
+        let db = JDB::open(&conf).unwrap();
+        assert_eq!(a, db.resolve("aaaa1111-0000-4000-8000-000000000000").unwrap());
+        assert_eq!(a, db.resolve("AAAA1").unwrap());
+        assert_eq!(a, db.resolve("web").unwrap());
+        assert_eq!(c, db.resolve("cc").unwrap());
+        assert!(db.resolve("aaaa").is_err());
+        assert!(db.resolve("db").is_err());
+        assert!(db.resolve("ffff").is_err());
+        assert!(db.resolve("").is_err());
+        assert!(db.resolve("bbbb0000-0000-4000-8000-000000000000").is_err());
+    }
+
+    #[test]
+    fn list() {
+        let conf = Config::test(Box::new(RecordingExecutor::new()));
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        JDB::open(&conf).unwrap().insert(aliased(a, "web")).unwrap();
+        JDB::open(&conf).unwrap().insert(aliased(b, "db")).unwrap();
+        let db = JDB::open(&conf).unwrap();
+        let jails = db.list(&[Filter::parse("alias=~b$").unwrap()], &[]).unwrap();
+        assert_eq!(2, jails.len());
+        let jails = db.list(&[Filter::parse("alias=db").unwrap()], &[]).unwrap();
+        assert_eq!(1, jails.len());
+        assert_eq!(Some("provisioning"), jails[0]["state"].as_str());
+        assert_eq!(Some("OS"), jails[0]["type"].as_str());
+        assert_eq!(jails[0]["max_physical_memory"], jails[0]["ram"]);
+        let jails = db.list(&[], &Sort::parse("alias")).unwrap();
+        assert_eq!(Some("db"), jails[0]["alias"].as_str());
     }
 }
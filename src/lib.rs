@@ -0,0 +1,79 @@
+//! vmadm compatible jail manager
+//!
+//! The library behind the `vmadm` binary, it can be embedded to manage
+//! jails directly instead of shelling out to `vmadm`.
+//!
+//! ```no_run
+//! use vmadm::{Config, JDB};
+//!
+//! let config = Config::new().unwrap();
+//! let db = JDB::open(&config).unwrap();
+//! for entry in db.iter() {
+//!     let jail = db.get(&entry.uuid).unwrap();
+//!     println!("{} {}", jail.idx.uuid, jail.config.alias);
+//! }
+//! ```
+
+#![deny(trivial_numeric_casts,
+        missing_docs,
+        unstable_features,
+        unused_import_braces,
+)]
+
+#![cfg_attr(feature="clippy", feature(plugin))]
+#![cfg_attr(feature="clippy", plugin(clippy))]
+
+extern crate aud;
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+extern crate toml;
+#[macro_use]
+extern crate lazy_static;
+extern crate regex;
+extern crate rand;
+extern crate reqwest;
+extern crate chrono;
+extern crate tempfile;
+extern crate bzip2;
+extern crate flate2;
+extern crate base64;
+extern crate crc;
+
+#[macro_use]
+extern crate prettytable;
+
+extern crate uuid;
+extern crate libc;
+
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate slog_scope;
+
+pub mod executor;
+pub mod brand;
+pub mod zfs;
+pub mod images;
+pub mod jails;
+pub mod jail_config;
+pub mod update;
+pub mod jdb;
+pub mod list;
+pub mod lock;
+pub mod config;
+pub mod errors;
+pub mod doctor;
+pub mod archive;
+pub mod metadata;
+pub mod mdata;
+
+pub use brand::Brand;
+pub use config::Config;
+pub use jails::Jail;
+pub use jail_config::JailConfig;
+pub use jdb::{JDB, IdxEntry, JailState, HistoryEntry};
+pub use update::JailUpdate;
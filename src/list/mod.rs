@@ -0,0 +1,223 @@
+//! SmartOS style filters, columns and sorting for `vmadm list`
+
+use std::cmp::Ordering;
+use std::error::Error;
+
+use regex::Regex;
+use serde_json::Value;
+
+use errors::GenericError;
+
+/// Columns shown when none are selected
+pub static DEFAULT_COLUMNS: &'static [&'static str] = &["uuid", "type", "ram", "state", "alias"];
+
+#[derive(Debug)]
+enum Op {
+    Eq(String),
+    Match(Regex),
+    Gt(f64),
+    Ge(f64),
+    Lt(f64),
+    Le(f64),
+}
+
+/// A filter on a field of a jail, one of `field=value`,
+/// `field=~regex` or the numeric `field>n`, `field>=n`, `field<n`
+/// and `field<=n`.
+#[derive(Debug)]
+pub struct Filter {
+    field: String,
+    op: Op,
+}
+
+impl Filter {
+    /// Parses a filter from the command line
+    pub fn parse(filter: &str) -> Result<Self, Box<Error>> {
+        let pos = match filter.find(|c| c == '=' || c == '<' || c == '>') {
+            Some(0) | None => {
+                return Err(GenericError::bx(
+                    format!("invalid filter {}, expected field=value", filter).as_str(),
+                ))
+            }
+            Some(pos) => pos,
+        };
+        let (field, rest) = filter.split_at(pos);
+        let op = if rest.starts_with("=~") {
+            Op::Match(Regex::new(&rest[2..])?)
+        } else if rest.starts_with(">=") {
+            Op::Ge(number(filter, &rest[2..])?)
+        } else if rest.starts_with("<=") {
+            Op::Le(number(filter, &rest[2..])?)
+        } else if rest.starts_with('>') {
+            Op::Gt(number(filter, &rest[1..])?)
+        } else if rest.starts_with('<') {
+            Op::Lt(number(filter, &rest[1..])?)
+        } else {
+            Op::Eq(String::from(&rest[1..]))
+        };
+        Ok(Filter {
+            field: String::from(field),
+            op: op,
+        })
+    }
+
+    /// Checks if a jail, as JSON, matches the filter
+    pub fn matches(&self, jail: &Value) -> bool {
+        let value = field(jail, self.field.as_str());
+        match self.op {
+            Op::Eq(ref expected) => value.map_or(false, |v| show(v) == *expected),
+            Op::Match(ref re) => value.map_or(false, |v| re.is_match(show(v).as_str())),
+            Op::Gt(n) => value.and_then(as_number).map_or(false, |v| v > n),
+            Op::Ge(n) => value.and_then(as_number).map_or(false, |v| v >= n),
+            Op::Lt(n) => value.and_then(as_number).map_or(false, |v| v < n),
+            Op::Le(n) => value.and_then(as_number).map_or(false, |v| v <= n),
+        }
+    }
+}
+
+fn number(filter: &str, n: &str) -> Result<f64, Box<Error>> {
+    match n.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(GenericError::bx(
+            format!("invalid filter {}, {} is not a number", filter, n).as_str(),
+        )),
+    }
+}
+
+/// A sort key, prefixed with `-` to sort in descending order
+#[derive(Debug)]
+pub struct Sort {
+    field: String,
+    descending: bool,
+}
+
+impl Sort {
+    /// Parses a comma separated list of sort keys
+    pub fn parse(keys: &str) -> Vec<Self> {
+        keys.split(',')
+            .filter(|k| !k.is_empty())
+            .map(|k| if k.starts_with('-') {
+                Sort {
+                    field: String::from(&k[1..]),
+                    descending: true,
+                }
+            } else {
+                Sort {
+                    field: String::from(k),
+                    descending: false,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Sorts jails, as JSON, by all keys in order
+pub fn sort(jails: &mut Vec<Value>, keys: &[Sort]) {
+    jails.sort_by(|a, b| {
+        for key in keys {
+            let a = field(a, key.field.as_str());
+            let b = field(b, key.field.as_str());
+            let order = match (a.and_then(as_number), b.and_then(as_number)) {
+                (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                _ => a.map(show).cmp(&b.map(show)),
+            };
+            let order = if key.descending {
+                order.reverse()
+            } else {
+                order
+            };
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        Ordering::Equal
+    })
+}
+
+/// Looks up a, possibly nested, field like `nics.0.ip`
+pub fn field<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.').fold(Some(value), |value, key| match value {
+        Some(&Value::Object(ref map)) => map.get(key),
+        Some(&Value::Array(ref list)) => key.parse::<usize>().ok().and_then(|i| list.get(i)),
+        _ => None,
+    })
+}
+
+/// Renders a field for output and comparison, strings are shown without
+/// quotes, missing values as `-`.
+pub fn show(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        Value::Null => String::from("-"),
+        ref other => other.to_string(),
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match *value {
+        Value::Number(ref n) => n.as_f64(),
+        Value::String(ref s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use list::*;
+
+    fn jails() -> Vec<Value> {
+        vec![
+            json!({"alias": "web", "ram": 1024, "autoboot": true,
+                   "nics": [{"ip": "10.0.0.1"}]}),
+            json!({"alias": "db", "ram": 4096, "autoboot": true,
+                   "nics": [{"ip": "10.0.0.2"}]}),
+            json!({"alias": "cache", "ram": 4096, "autoboot": false,
+                   "nics": []}),
+        ]
+    }
+
+    fn aliases(jails: &[Value]) -> Vec<String> {
+        jails.iter().map(|j| show(&j["alias"])).collect()
+    }
+
+    fn filter(filters: &[&str]) -> Vec<String> {
+        let filters: Vec<Filter> = filters.iter().map(|f| Filter::parse(f).unwrap()).collect();
+        let jails: Vec<Value> = jails()
+            .into_iter()
+            .filter(|j| filters.iter().all(|f| f.matches(j)))
+            .collect();
+        aliases(&jails)
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(vec!["db"], filter(&["alias=db"]));
+        assert_eq!(vec!["web", "db"], filter(&["autoboot=true"]));
+        assert_eq!(vec!["db", "cache"], filter(&["ram>2048"]));
+        assert_eq!(vec!["db"], filter(&["autoboot=true", "ram>=4096"]));
+        assert_eq!(vec!["web"], filter(&["ram<=1024"]));
+        assert_eq!(vec!["web", "cache"], filter(&["alias=~^(w|c)"]));
+        assert_eq!(vec!["db"], filter(&["nics.0.ip=10.0.0.2"]));
+        assert!(Filter::parse("alias").is_err());
+        assert!(Filter::parse("=db").is_err());
+        assert!(Filter::parse("ram>lots").is_err());
+        assert!(Filter::parse("alias=~(").is_err());
+    }
+
+    #[test]
+    fn nested() {
+        let jail = &jails()[0];
+        assert_eq!("10.0.0.1", show(field(jail, "nics.0.ip").unwrap()));
+        assert!(field(jail, "nics.1.ip").is_none());
+        assert!(field(jail, "alias.x").is_none());
+    }
+
+    #[test]
+    fn sorting() {
+        let mut list = jails();
+        sort(&mut list, &Sort::parse("-ram,alias"));
+        assert_eq!(vec!["cache", "db", "web"], aliases(&list));
+        sort(&mut list, &Sort::parse("autoboot,-alias"));
+        assert_eq!(vec!["cache", "web", "db"], aliases(&list));
+    }
+}
@@ -0,0 +1,109 @@
+//! Advisory locks on the jail database and on single jails
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use libc;
+use uuid::Uuid;
+
+use config::Config;
+
+/// An exclusive `flock(2)` lock, it is released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    // closing the file releases the lock
+    _file: Option<File>,
+}
+
+impl Lock {
+    /// Takes an exclusive lock on `path`, creating the file if it does
+    /// not exist. Waits until other holders released it.
+    pub fn exclusive<P: AsRef<Path>>(path: P) -> Result<Self, Box<Error>> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(path)?;
+        let fd = file.as_raw_fd();
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(Box::new(error));
+            }
+            info!("Waiting for lock"; "lock" => path.to_string_lossy().as_ref());
+            if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+                return Err(Box::new(io::Error::last_os_error()));
+            }
+        }
+        debug!("Acquired lock"; "lock" => path.to_string_lossy().as_ref());
+        Ok(Lock { _file: Some(file) })
+    }
+
+    /// A lock that guards nothing, used for `--dry-run`
+    fn none() -> Self {
+        Lock { _file: None }
+    }
+}
+
+/// Locks the `JDB` index, held while the index is read or written.
+pub fn db(config: &Config) -> Result<Lock, Box<Error>> {
+    if config.dry_run {
+        return Ok(Lock::none());
+    }
+    let mut path = PathBuf::from(config.settings.conf_dir.as_str());
+    path.push(".lock");
+    Lock::exclusive(path)
+}
+
+/// Locks a single jail, held for the whole of an operation on it
+/// (create, update, start, stop, delete) so they do not interleave.
+pub fn jail(config: &Config, uuid: &Uuid) -> Result<Lock, Box<Error>> {
+    if config.dry_run {
+        return Ok(Lock::none());
+    }
+    Lock::exclusive(jail_path(config, uuid))
+}
+
+/// Path of the lock file for a jail
+pub fn jail_path(config: &Config, uuid: &Uuid) -> PathBuf {
+    let mut path = PathBuf::from(config.settings.conf_dir.as_str());
+    path.push(uuid.hyphenated().to_string());
+    path.set_extension("lock");
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use lock::*;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn exclusive() {
+        let mut path = env::temp_dir();
+        path.push(format!("vmadm-{}.lock", Uuid::new_v4().simple()));
+        let lock = Lock::exclusive(&path).unwrap();
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let path = path.clone();
+            let acquired = acquired.clone();
+            thread::spawn(move || {
+                let _lock = Lock::exclusive(&path).unwrap();
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!acquired.load(Ordering::SeqCst));
+        drop(lock);
+        waiter.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -10,29 +10,14 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
+extern crate vmadm;
+
 #[macro_use]
 extern crate clap;
-extern crate aud;
 
 #[macro_use]
 extern crate serde_derive;
-extern crate serde;
 extern crate serde_json;
-extern crate toml;
-#[macro_use]
-extern crate lazy_static;
-extern crate regex;
-extern crate rand;
-extern crate reqwest;
-extern crate chrono;
-extern crate tempfile;
-extern crate bzip2;
-extern crate flate2;
-
-//extern crate indicatif;
-
-#[macro_use]
-extern crate prettytable;
 
 extern crate uuid;
 use uuid::Uuid;
@@ -47,33 +32,17 @@ extern crate slog_bunyan;
 use slog::Drain;
 
 use std::result;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::io;
+use std::io::Read;
 use std::fs::OpenOptions;
 use std::fs::File;
 
-use aud::{Failure, Adventure, Saga};
-
-mod brand;
-use brand::Brand;
-mod zfs;
-mod images;
-mod jails;
-use jails::Jail;
-
-mod jail_config;
-mod update;
-
-use jail_config::JailConfig;
-
-mod jdb;
-use jdb::{JDB, IdxEntry};
-
-mod config;
-use config::Config;
-
-mod errors;
-use errors::{GenericError, ValidationErrors};
+use vmadm::{archive, doctor, images, jails, list, mdata, metadata};
+use vmadm::list::{Filter, Sort};
+use vmadm::{Config, Jail, JailConfig, JailUpdate, JDB};
+use vmadm::errors::{GenericError, ValidationErrors};
 
 /// Custom Drain logic
 struct RuntimeLevelFilter<D> {
@@ -155,14 +124,20 @@ fn run() -> i32 {
 
     let drain = slog::Duplicate::new(file_drain, term_drain).fuse();
 
+    let req_id = Uuid::new_v4();
     let root = slog::Logger::root(
         drain,
-        o!("req_id" => Uuid::new_v4().hyphenated().to_string()),
+        o!("req_id" => req_id.hyphenated().to_string()),
     );
 
     let _guard = slog_scope::set_global_logger(root);
 
-    let config: Config = Config::new().unwrap();
+    let mut config: Config = Config::new().unwrap();
+    config.req_id = req_id;
+    if is_dry_run(&matches) {
+        config.dry_run();
+    }
+    let config = config;
     let r = if matches.is_present("startup") {
         match matches.subcommand() {
             ("", None) => startup(&config),
@@ -182,6 +157,18 @@ fn run() -> i32 {
             ("console", Some(console_matches)) => console(&config, console_matches),
             ("images", Some(image_matches)) => images(&config, image_matches),
             ("config", Some(config_matches)) => hv_config(&config, config_matches),
+            ("lookup", Some(lookup_matches)) => lookup(&config, lookup_matches),
+            ("history", Some(history_matches)) => history(&config, history_matches),
+            ("rollback-config", Some(rollback_matches)) => rollback(&config, rollback_matches),
+            ("export", Some(export_matches)) => export(&config, export_matches),
+            ("import-jail", Some(import_matches)) => {
+                archive::import(&config, import_matches.value_of("file").unwrap())
+            }
+            ("doctor", Some(doctor_matches)) => {
+                doctor::doctor(&config, doctor_matches.is_present("fix"))
+            }
+            ("metadata", Some(metadata_matches)) => hv_metadata(&config, metadata_matches),
+            ("validate", Some(validate_matches)) => validate(&config, validate_matches),
 
             ("", None) => {
                 help_app.print_help().unwrap();
@@ -203,13 +190,29 @@ fn run() -> i32 {
     }
 }
 
+/// Checks if `--dry-run` was given to the (nested) subcommand
+fn is_dry_run(matches: &clap::ArgMatches) -> bool {
+    match matches.subcommand() {
+        (_, Some(sub_matches)) => sub_matches.is_present("dry_run") || is_dry_run(sub_matches),
+        _ => false,
+    }
+}
+
+/// Resolves the `uuid` argument, a UUID, UUID prefix or alias, to
+/// the UUID of a jail.
+fn jail_uuid(conf: &Config, matches: &clap::ArgMatches) -> Result<Uuid, Box<Error>> {
+    let name = value_t!(matches, "uuid", String)?;
+    let db = JDB::open(conf)?;
+    db.resolve(name.as_str())
+}
+
 fn startup(conf: &Config) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
     for e in db.iter() {
         let jail = db.get(&e.uuid)?;
         if jail.config.autoboot && jail.outer.is_none() {
             println!("Starting jail {}", jail.idx.uuid);
-            jail.start(conf)?;
+            jails::start(conf, &jail.idx.uuid)?;
         } else {
             println!("Skipping startup for jail {}", jail.idx.uuid);
 
@@ -219,50 +222,34 @@ fn startup(conf: &Config) -> Result<i32, Box<Error>> {
 }
 
 fn start(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
-    debug!("Starting jail {}", uuid.hyphenated());
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(Jail { outer: Some(_), .. }) => {
-            println!("The vm is alredy started");
-            Err(GenericError::bx("VM is already started"))
-        }
-        Ok(jail) => {
-            println!("Starting jail {}", jail.idx.uuid);
-            jail.start(conf)
-        }
-    }
+    let uuid = jail_uuid(conf, matches)?;
+    println!("Starting jail {}", uuid);
+    jails::start(conf, &uuid)
 }
 
 fn reboot(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
-    debug!("deleteing jail {}", uuid.hyphenated());
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(Jail { outer: None, .. }) => {
-            println!("The vm is not running");
-            Err(GenericError::bx("The vm is not running"))
-        }
-        Ok(jail) => {
-            println!("Rebooting jail {}", uuid);
-            jail.stop(conf)?;
-            jail.start(conf)
-        }
-    }
+    let uuid = jail_uuid(conf, matches)?;
+    println!("Rebooting jail {}", uuid);
+    jails::reboot(conf, &uuid)
 }
 
 fn get(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
-    let uuid = value_t!(matches, "uuid", Uuid).unwrap();
+    let uuid = jail_uuid(conf, matches)?;
     debug!("Starting jail {}", uuid.hyphenated().to_string());
     match db.get(&uuid) {
         Err(e) => Err(e),
-        Ok(Jail { config: conf, .. }) => {
-            let j = serde_json::to_string_pretty(&conf)?;
+        Ok(jail) => {
+            let mut value = serde_json::to_value(&jail.config)?;
+            if let Some(obj) = value.as_object_mut() {
+                let state = jail.idx.current_state(jail.outer.is_some());
+                obj.insert(String::from("state"), serde_json::to_value(state)?);
+                obj.insert(
+                    String::from("state_changed"),
+                    serde_json::to_value(jail.idx.state_changed)?,
+                );
+            }
+            let j = serde_json::to_string_pretty(&value)?;
             println!("{}", j);
             Ok(0)
         }
@@ -271,8 +258,7 @@ fn get(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
 
 fn info(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = jail_uuid(conf, matches)?;
     debug!("Getting jail info {}", uuid.hyphenated());
     match db.get(&uuid) {
         Err(e) => Err(e),
@@ -285,12 +271,21 @@ fn info(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
 
 #[derive(Serialize)]
 struct Info {
-    networks: Vec<String>
+    networks: Vec<String>,
+    /// cores of the jails that are pinned, by UUID
+    cpusets: BTreeMap<String, Vec<u32>>,
 }
 
 fn hv_config(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let db = JDB::open(conf)?;
+    let cpusets = db.configs()
+        .into_iter()
+        .filter(|c| c.cpuset.is_some())
+        .map(|c| (c.uuid.hyphenated().to_string(), c.cores()))
+        .collect();
     let info = Info{
-        networks: conf.settings.networks.keys().map(|v| v.clone()).collect::<Vec<String>>()
+        networks: conf.settings.networks.keys().map(|v| v.clone()).collect::<Vec<String>>(),
+        cpusets: cpusets,
     };
     debug!("Getting hypervisor info.");
     let j = serde_json::to_string_pretty(&info)?;
@@ -300,8 +295,7 @@ fn hv_config(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Erro
 
 fn console(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = jail_uuid(conf, matches)?;
     debug!("Starting jail {}", uuid.hyphenated());
     match db.get(&uuid) {
         Err(e) => Err(e),
@@ -311,9 +305,7 @@ fn console(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
         }
         Ok(jail) => {
             let brand = jail.brand(conf)?;
-            let mut child = brand.login.spawn(&jail, conf)
-                .expect("failed to execute jexec");
-            let ecode = child.wait().expect("failed to wait on child");
+            let ecode = brand.login.status(&jail, conf)?;
             if ecode.success() {
                 Ok(0)
             } else {
@@ -324,299 +316,214 @@ fn console(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
 }
 
 fn stop(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
-    debug!("stopping jail {}", uuid.hyphenated());
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(Jail { outer: None, .. }) => {
-            println!("The vm is alredy stopped");
-            Err(GenericError::bx("VM is already stooped"))
-        }
-        Ok(jail) => {
-            println!("Stopping jail {}", uuid);
-            jail.stop(conf)
-        }
-    }
+    let uuid = jail_uuid(conf, matches)?;
+    println!("Stopping jail {}", uuid);
+    jails::stop(conf, &uuid)
 }
 
 fn list(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let filters = filters(matches)?;
+    let sort = match matches.value_of("sort") {
+        Some(keys) => Sort::parse(keys),
+        None => Vec::new(),
+    };
+    let columns: Vec<String> = match matches.value_of("output") {
+        Some(columns) => columns.split(',').map(String::from).collect(),
+        None => list::DEFAULT_COLUMNS.iter().map(|c| String::from(*c)).collect(),
+    };
     let db = JDB::open(conf)?;
+    if matches.is_present("json") {
+        let jails = db.list(&filters, &sort)?;
+        println!("{}", serde_json::to_string_pretty(&jails)?);
+        return Ok(0);
+    }
     db.print(
+        &filters,
+        &sort,
+        &columns,
         matches.is_present("headerless"),
         matches.is_present("parsable"),
     )
 }
 
-fn update(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+fn history(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let uuid = jail_uuid(conf, matches)?;
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
-    let update = match value_t!(matches, "file", String) {
+    db.print_history(&uuid, matches.is_present("json"))
+}
+
+fn rollback(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let uuid = jail_uuid(conf, matches)?;
+    let version = value_t!(matches, "version", u32)?;
+    jails::rollback(conf, &uuid, version)?;
+    println!("Rolled back jail {} to config version {}", uuid, version);
+    Ok(0)
+}
+
+fn export(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let uuid = jail_uuid(conf, matches)?;
+    archive::export(conf, &uuid, matches.value_of("output").unwrap())
+}
+
+fn lookup(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let filters = filters(matches)?;
+    let db = JDB::open(conf)?;
+    let jails = db.list(&filters, &[])?;
+    if matches.is_present("unique") && jails.len() != 1 {
+        return Err(GenericError::bx(
+            format!("expected exactly one jail but found {}", jails.len()).as_str(),
+        ));
+    }
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&jails)?);
+    } else {
+        for jail in jails {
+            println!("{}", list::show(&jail["uuid"]));
+        }
+    }
+    Ok(0)
+}
+
+/// Parses the `filter` arguments of list and lookup
+fn filters(matches: &clap::ArgMatches) -> Result<Vec<Filter>, Box<Error>> {
+    let mut filters = Vec::new();
+    if let Some(values) = matches.values_of("filter") {
+        for filter in values {
+            filters.push(Filter::parse(filter)?);
+        }
+    }
+    Ok(filters)
+}
+
+fn read_update(matches: &clap::ArgMatches) -> Result<JailUpdate, Box<Error>> {
+    match value_t!(matches, "file", String) {
         Err(_) => {
             debug!("Reading from STDIN");
-            update::JailUpdate::from_reader(io::stdin())?
+            JailUpdate::from_reader(io::stdin())
         }
         Ok(file) => {
             debug!("Reading from file"; "file" => file.clone() );
-            update::JailUpdate::from_reader(File::open(file)?)?
-        }
-    };
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(jail) => {
-            let c = update.apply(jail.config, jail.idx)?;
-            // TODO: This is ugly ...
-            let mut db = JDB::open(conf)?;
-            db.update(c)
+            JailUpdate::from_reader(File::open(file)?)
         }
     }
 }
 
-fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let jail = match value_t!(matches, "file", String) {
+fn update(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let uuid = jail_uuid(conf, matches)?;
+    let update = read_update(matches)?;
+    jails::update(conf, &uuid, &update)
+}
+
+fn read_jail(matches: &clap::ArgMatches) -> Result<JailConfig, Box<Error>> {
+    match value_t!(matches, "file", String) {
         Err(_) => {
             debug!("Reading from STDIN");
-            jail_config::JailConfig::from_reader(io::stdin())?
+            JailConfig::from_reader(io::stdin())
         }
         Ok(file) => {
             debug!("Reading from file"; "file" => file.clone() );
-            jail_config::JailConfig::from_reader(File::open(file)?)?
+            JailConfig::from_reader(File::open(file)?)
         }
-    };
-    let mut dataset = conf.settings.pool.clone();
-    dataset.push('/');
-    dataset.push_str(jail.image_uuid.hyphenated().to_string().as_str());
-
-    #[derive(Debug, Clone)]
-    struct CreateState<'a> {
-        conf: &'a Config,
-        uuid: Uuid,
-        dataset: String,
-        config: JailConfig,
-        entry: Option<IdxEntry>,
-        snapshot: Option<String>,
-        root: Option<String>,
     }
+}
 
-    let state = CreateState {
-        conf,
-        uuid: jail.uuid.clone(),
-        dataset,
-        config: jail.clone(),
-        entry: None,
-        snapshot: None,
-        root: None,
-    };
-    fn insert_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
-        match JDB::open(state.conf) {
-            Ok(mut db) => {
-                match db.insert(state.config.clone()) {
-                    Ok(entry) => Ok(CreateState {
-                        conf: state.conf,
-                        uuid: state.uuid,
-                        dataset: state.dataset,
-                        config: state.config,
-                        entry: Some(entry),
-                        snapshot: state.snapshot,
-                        root: state.root,
-                    }),
-                    Err(error) => Err(Failure::new(state, error)),
-                }
-            }
-            Err(error) => Err(Failure::new(state, error)),
-        }
-    };
-    fn insert_down(state: CreateState) -> CreateState {
-        crit!("Rolling back insert");
-        match JDB::open(state.conf) {
-            Ok(mut db) => {
-                let _ = db.remove(&state.uuid);
-            }
-            Err(_error) => (),
-        };
-        state
-    };
+fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let jail = read_jail(matches)?;
+    let uuid = jail.uuid;
+    jails::create(conf, jail)?;
+    println!("Created jail {}", uuid);
+    Ok(0)
+}
 
-    fn snap_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
-        match zfs::snapshot(
-            state.dataset.as_str(),
-            state.uuid.hyphenated().to_string().as_str(),
-        ) {
-            Ok(snap) => Ok(CreateState {
-                conf: state.conf,
-                uuid: state.uuid,
-                dataset: state.dataset,
-                config: state.config,
-                entry: state.entry,
-                snapshot: Some(snap),
-                root: state.root,
-            }),
-            Err(error) => Err(Failure::new(state, error)),
+fn hv_metadata(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    match matches.subcommand() {
+        ("list", Some(list_matches)) => {
+            let uuid = jail_uuid(conf, list_matches)?;
+            metadata::list(conf, &uuid, list_matches.is_present("json"))
         }
-    }
-    fn snap_down(state: CreateState) -> CreateState {
-        crit!("Rolling back snapshot");
-        match state.snapshot.clone() {
-            Some(snap) => {
-                let _ = zfs::destroy(snap.as_str());
-                state
-            }
-            None => state,
+        ("get", Some(get_matches)) => {
+            let uuid = jail_uuid(conf, get_matches)?;
+            metadata::get(conf, &uuid, get_matches.value_of("key").unwrap())
         }
-    }
-
-    fn clone_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
-        match state.snapshot.clone() {
-            Some(snap) => {
-                match state.entry.clone() {
-                    Some(entry) => {
-                        match zfs::clone(snap.as_str(), entry.root.as_str()) {
-                            Ok(_) => Ok(CreateState {
-                                conf: state.conf,
-                                uuid: state.uuid,
-                                dataset: state.dataset,
-                                config: state.config,
-                                entry: state.entry,
-                                snapshot: state.snapshot,
-                                root: Some(entry.root),
-                            }),
-                            Err(error) => Err(Failure::new(state, error)),
-                        }
-                    }
-                    None => Err(Failure::new(state, GenericError::bx("No root to clone"))),
+        ("set", Some(set_matches)) => {
+            let uuid = jail_uuid(conf, set_matches)?;
+            let value = match set_matches.value_of("value") {
+                Some(value) => String::from(value),
+                None => {
+                    debug!("Reading from STDIN");
+                    let mut value = String::new();
+                    io::stdin().read_to_string(&mut value)?;
+                    value
                 }
-            }
-            None => Err(Failure::new(state, GenericError::bx("No snap to clone"))),
-        }
-    }
-    fn clone_down(state: CreateState) -> CreateState {
-        crit!("Rolling back clone");
-        match state.root.clone() {
-            Some(root) => {
-                let _ = zfs::destroy(root.as_str());
-                state
-            }
-            None => state,
+            };
+            metadata::set(
+                conf,
+                &uuid,
+                set_matches.value_of("key").unwrap(),
+                value.as_str(),
+                set_matches.is_present("internal"),
+            )
         }
-    }
-    fn quota_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
-        let s1 = state.clone();
-        let entry = s1.entry.unwrap();
-        let root = entry.root.as_str();
-        let quota = s1.config.quota;
-        match zfs::quota(root, quota) {
-            Ok(_) => Ok(state),
-            Err(e) => Err(Failure::new(state, e)),
+        ("serve", Some(serve_matches)) => {
+            let uuid = jail_uuid(conf, serve_matches)?;
+            mdata::serve(conf, &uuid)
         }
-
-    }
-    fn quota_down(state: CreateState) -> CreateState {
-        crit!("Rolling back init");
-        state
-    }
-    fn init_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
-        let s1 = state.clone();
-        let jail = Jail{
-            idx: & s1.entry.unwrap(),
-            config: s1.config,
-            inner: None,
-            outer: None,
-        };
-        let state1 = CreateState{
-            conf: state.conf,
-            uuid: state.uuid,
-            dataset: state.dataset,
-            config: state.config,
-            entry: state.entry,
-            snapshot: state.snapshot,
-            root: state.root,
-         };
-        match jail.init(state1.conf) {
-            Ok(_) => Ok(state1),
-            Err(err) => Err(Failure::new(state1, err))
+        ("delete", Some(delete_matches)) => {
+            let uuid = jail_uuid(conf, delete_matches)?;
+            metadata::delete(
+                conf,
+                &uuid,
+                delete_matches.value_of("key").unwrap(),
+                delete_matches.is_present("internal"),
+            )
         }
+        ("", None) => Ok(0),
+        _ => unreachable!(),
     }
-    fn init_down(state: CreateState) -> CreateState {
-        crit!("Rolling back init");
-        state
-    }
-    fn brand_install_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
-        let s1 = state.clone();
-        let jail = Jail{
-            idx: & s1.entry.unwrap().clone(),
-            config: s1.config,
-            inner: None,
-            outer: None,
-        };
-        match Brand::load(state.config.brand.as_str(), state.conf) {
-            Err(_) => Err(Failure::new(state, GenericError::bx("invalid brand"))),
-            Ok(brand)  => {
-                match brand.install.output(&jail, state.conf) {
-                    Ok(_) => Ok(state),
-                    Err(_) => Err(Failure::new(state, GenericError::bx("failed to initilize brand")))
-                }
+}
 
-            }
+fn validate(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    // nothing on the network is probed, so this is safe to run anywhere
+    let (errors, json) = match matches.subcommand() {
+        ("create", Some(create_matches)) => {
+            let jail = read_jail(create_matches)?;
+            (jail.offline_errors(conf), create_matches.is_present("json"))
         }
-    }
-    fn brand_install_down(state: CreateState) -> CreateState {
-        crit!("Rolling back clone");
-        state
-    }
-    let saga = Saga::new(vec![
-        Adventure::new(insert_up, insert_down),
-        Adventure::new(snap_up, snap_down),
-        Adventure::new(clone_up, clone_down),
-        Adventure::new(quota_up, quota_down),
-        Adventure::new(init_up, init_down),
-        Adventure::new(brand_install_up, brand_install_down),
-    ]);
-    match jail.errors(conf) {
-        Some(errors) => Err(ValidationErrors::bx(errors)),
-        None => 
-            match saga.tell(state) {
-                Ok(state) => {
-                    println!("Created jail {}", state.uuid);
-                    Ok(0)
-                }
-                Err(failure) => Err(failure.to_error()),
+        ("update", Some(update_matches)) => {
+            let uuid = jail_uuid(conf, update_matches)?;
+            let update = read_update(update_matches)?;
+            let db = JDB::open(conf)?;
+            let jail = db.get(&uuid)?;
+            (update.offline_errors(conf, &jail.config), update_matches.is_present("json"))
+        }
+        ("", None) => return Ok(0),
+        _ => unreachable!(),
+    };
+    match errors {
+        Some(errors) => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&ValidationErrors::new(errors))?);
+                Ok(1)
+            } else {
+                Err(ValidationErrors::bx(errors))
             }
+        }
+        None if json => {
+            println!("{}", serde_json::to_string_pretty(&ValidationErrors::new(vec![]))?);
+            Ok(0)
+        }
+        None => {
+            println!("Valid");
+            Ok(0)
+        }
     }
-
 }
 
 fn delete(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let mut db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
-    debug!("deleteing jail {}", uuid.hyphenated());
-    let res = match db.get(&uuid) {
-        Ok(jail) => {
-            if jail.outer.is_some() {
-                println!("Stopping jail {}", uuid);
-                jail.stop(conf)?;
-            };
-            let origin = zfs::origin(jail.idx.root.as_str());
-            match zfs::destroy(jail.idx.root.as_str()) {
-                Ok(_) => debug!("zfs dataset deleted: {}", jail.idx.root),
-                Err(e) => warn!("failed to delete dataset: {}", e),
-            };
-            match origin {
-                Ok(origin) => {
-                    zfs::destroy(origin.as_str())?;
-                    debug!("zfs snapshot deleted: {}", origin)
-                }
-                Err(e) => warn!("failed to delete origin: {}", e),
-            };
-            println!("deleted jail {}", uuid);
-            Ok(0)
-        }
-        Err(e) => Err(e),
-    };
-    db.remove(&uuid)?;
-    res
+    let uuid = jail_uuid(conf, matches)?;
+    jails::delete(conf, &uuid)?;
+    println!("deleted jail {}", uuid);
+    Ok(0)
 }
 
 fn images(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
@@ -633,28 +540,25 @@ fn images(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
         }
 }
 
-fn images_avail(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    images::avail(conf)
+fn images_avail(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    images::avail(conf, matches.is_present("json"))
 }
 
-fn images_list(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    images::list(conf)
+fn images_list(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    images::list(conf, matches.is_present("json"))
 }
 
 fn images_get(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = value_t!(matches, "uuid", Uuid)?;
     images::get(conf, uuid)
 }
 
 fn images_show(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = value_t!(matches, "uuid", Uuid)?;
     images::show(conf, uuid)
 }
 
 fn images_import(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = value_t!(matches, "uuid", Uuid)?;
     images::import(conf, uuid)
 }
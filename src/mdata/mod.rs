@@ -0,0 +1,424 @@
+//! Metadata agent speaking the SmartOS metadata protocol (v2)
+//!
+//! Every running jail gets an agent listening on
+//! `/.zonecontrol/metadata.sock` inside the guest, so `mdata-get`,
+//! `mdata-put`, `mdata-list` and `mdata-delete` work unchanged. The
+//! socket lives in a folder on the host that the brand mounts into the
+//! jail, the agent never touches paths inside the guest. Reads and
+//! writes go to the `JDB`, a row of writes is one version in the history.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use base64;
+use crc::crc32;
+use serde_json;
+use uuid::Uuid;
+
+use config::Config;
+use jail_config::{Filesystem, JailConfig};
+use jails;
+use jails::Jail;
+use jdb::JDB;
+use metadata;
+use metadata::Scope;
+use update::JailUpdate;
+
+static DAEMON: &'static str = "daemon";
+static PKILL: &'static str = "pkill";
+
+/// Longest key the jail can write, in bytes
+const MAX_KEY: usize = 256;
+/// Longest value the jail can write, in bytes
+const MAX_VALUE: usize = 64 * 1024;
+/// Most keys `customer_metadata` can have through the agent
+const MAX_KEYS: usize = 1024;
+/// Longest request line, a base64 encoded key and value fit
+const MAX_LINE: u64 = 128 * 1024;
+/// Clients that send nothing for this long are dropped
+const READ_TIMEOUT: u64 = 5;
+/// Clients served at the same time, more are turned away
+const MAX_CONNECTIONS: usize = 16;
+
+/// Folder of the agent's socket on the host
+pub fn socket_dir(config: &Config, uuid: &Uuid) -> PathBuf {
+    let mut path = PathBuf::from(config.settings.run_dir.as_str());
+    path.push("mdata");
+    path.push(uuid.hyphenated().to_string());
+    path
+}
+
+/// Mount of the socket folder on `/.zonecontrol` in the jail, read only
+/// as connecting to the socket needs no more
+pub fn filesystem(config: &Config, uuid: &Uuid) -> Filesystem {
+    Filesystem {
+        source: socket_dir(config, uuid).to_string_lossy().into_owned(),
+        target: String::from("/.zonecontrol"),
+        fs_type: String::from("nullfs"),
+        options: vec![String::from("ro")],
+    }
+}
+
+fn pid_file(config: &Config, uuid: &Uuid) -> String {
+    let mut path = PathBuf::from(config.settings.run_dir.as_str());
+    path.push("mdata");
+    path.push(format!("{}.pid", uuid.hyphenated()));
+    path.to_string_lossy().into_owned()
+}
+
+/// Starts the agent of a jail in the background
+pub fn start(config: &Config, jail: &Jail) -> Result<i32, Box<Error>> {
+    let uuid = jail.idx.uuid.hyphenated().to_string();
+    let exe = env::current_exe()?;
+    let exe = exe.to_string_lossy();
+    let pid = pid_file(config, &jail.idx.uuid);
+    debug!("starting metadata agent"; "vm" => uuid.clone(), "pid_file" => pid.clone());
+    let args = ["-f", "-p", pid.as_str(), exe.as_ref(), "metadata", "serve", uuid.as_str()];
+    let output = config.executor.output(DAEMON, &args)?;
+    if !output.status.success() {
+        crit!("failed to start metadata agent"; "vm" => uuid);
+    }
+    Ok(0)
+}
+
+/// Stops the agent of a jail
+pub fn stop(config: &Config, jail: &Jail) -> Result<i32, Box<Error>> {
+    let pid = pid_file(config, &jail.idx.uuid);
+    debug!("stopping metadata agent";
+           "vm" => jail.idx.uuid.hyphenated().to_string(),
+           "pid_file" => pid.clone());
+    let output = config.executor.output(PKILL, &["-F", pid.as_str()])?;
+    if !output.status.success() {
+        warn!("metadata agent was not running"; "vm" => jail.idx.uuid.hyphenated().to_string());
+    }
+    Ok(0)
+}
+
+type Request = (String, Sender<String>);
+
+/// A connection that is being served, frees its place when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Takes a place for a new connection if not all are taken
+fn admit(active: &Arc<AtomicUsize>) -> Option<Slot> {
+    if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+        active.fetch_sub(1, Ordering::SeqCst);
+        return None;
+    }
+    Some(Slot(active.clone()))
+}
+
+/// Runs the agent of a jail in the foreground. Every connection is read
+/// on its own thread and its requests are answered here one by one, so
+/// a slow client holds up no one. At most `MAX_CONNECTIONS` are served
+/// at once.
+pub fn serve(config: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    JDB::open(config)?.get(uuid)?;
+    let dir = socket_dir(config, uuid);
+    fs::create_dir_all(&dir)?;
+    let mut path = dir.clone();
+    path.push("metadata.sock");
+    // left behind by an agent that was killed
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    info!("metadata agent listening"; "vm" => uuid.hyphenated().to_string(),
+          "socket" => path.to_string_lossy().into_owned());
+    let (requests, queue) = mpsc::channel::<Request>();
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let slot = match admit(&active) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("too many metadata connections, dropping one");
+                        continue;
+                    }
+                };
+                let requests = requests.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = connection(stream, requests) {
+                        debug!("metadata connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("failed to accept metadata connection: {}", e),
+        }
+    });
+    for (line, reply) in queue.iter() {
+        let _ = reply.send(handle(config, uuid, line.as_str()));
+    }
+    Ok(0)
+}
+
+/// Passes the requests of a client to the agent
+fn connection(stream: UnixStream, requests: Sender<Request>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT)))?;
+    let reader = BufReader::new(stream.try_clone()?);
+    session(reader, stream, |line| {
+        let (reply, answer) = mpsc::channel();
+        requests.send((String::from(line), reply)).ok()?;
+        answer.recv().ok()
+    })
+}
+
+/// Answers requests with `answer` until the client hangs up or sends a
+/// line longer than any request can be
+pub fn session<R, W, F>(mut reader: R, mut writer: W, mut answer: F) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(&str) -> Option<String>,
+{
+    loop {
+        let mut line = Vec::new();
+        (&mut reader).take(MAX_LINE).read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        if line.last() != Some(&b'\n') && line.len() as u64 == MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too long"));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let reply = match answer(line.trim_right_matches('\n').trim_right_matches('\r')) {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        writer.write_all(reply.as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+}
+
+/// Answers a single request line
+pub fn handle(config: &Config, uuid: &Uuid, line: &str) -> String {
+    if line == "NEGOTIATE V2" {
+        return String::from("V2_OK");
+    }
+    if !line.starts_with("V2 ") {
+        // v1 clients are not supported, they fall back to v2 anyway
+        return String::from("invalid command");
+    }
+    let mut parts = line[3..].splitn(3, ' ');
+    let (len, crc, body) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(len), Some(crc), Some(body)) => (len, crc, body),
+        _ => return String::from("invalid command"),
+    };
+    if len.parse::<usize>().ok() != Some(body.len()) ||
+        u32::from_str_radix(crc, 16).ok() != Some(crc32::checksum_ieee(body.as_bytes()))
+    {
+        return String::from("invalid command");
+    }
+    let mut parts = body.splitn(3, ' ');
+    let (id, code, payload) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(id), Some(code), payload) => (id, code, payload.and_then(decode)),
+        _ => return String::from("invalid command"),
+    };
+    let (code, payload) = match request(config, uuid, code, payload) {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("metadata request failed: {}", e; "vm" => uuid.hyphenated().to_string());
+            ("FAILURE", None)
+        }
+    };
+    frame(id, code, payload)
+}
+
+type Reply = (&'static str, Option<String>);
+
+fn request(config: &Config, uuid: &Uuid, code: &str, payload: Option<String>) -> Result<Reply, Box<Error>> {
+    let current = {
+        let db = JDB::open(config)?;
+        let jail = db.get(uuid)?;
+        jail.config
+    };
+    match (code, payload) {
+        ("GET", Some(key)) => {
+            match metadata::value(&current, key.as_str()).cloned().or_else(|| builtin(&current, key.as_str())) {
+                Some(value) => Ok(("SUCCESS", Some(value))),
+                None => Ok(("NOTFOUND", None)),
+            }
+        }
+        ("KEYS", _) => {
+            let keys: Vec<&str> = current.customer_metadata.keys().map(|k| k.as_str()).collect();
+            Ok(("SUCCESS", Some(keys.join("\n"))))
+        }
+        ("PUT", Some(payload)) => {
+            let mut parts = payload.splitn(2, ' ');
+            let (key, value) = match (parts.next().and_then(decode), parts.next().and_then(decode)) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Ok(("FAILURE", None)),
+            };
+            if !writable(&current, key.as_str()) || key.len() > MAX_KEY || value.len() > MAX_VALUE {
+                return Ok(("FAILURE", None));
+            }
+            if !current.customer_metadata.contains_key(&key) && current.customer_metadata.len() >= MAX_KEYS {
+                return Ok(("FAILURE", None));
+            }
+            let update = JailUpdate::metadata(Scope::Customer, key.as_str(), Some(value.as_str()));
+            jails::guest_update(config, uuid, &update)?;
+            Ok(("SUCCESS", None))
+        }
+        ("DELETE", Some(key)) => {
+            if !writable(&current, key.as_str()) {
+                return Ok(("FAILURE", None));
+            }
+            if current.customer_metadata.contains_key(&key) {
+                let update = JailUpdate::metadata(Scope::Customer, key.as_str(), None);
+                jails::guest_update(config, uuid, &update)?;
+            }
+            Ok(("SUCCESS", None))
+        }
+        _ => Ok(("FAILURE", None)),
+    }
+}
+
+/// Keys the jail can change, `sdc:` keys describe the jail itself
+fn writable(jail: &JailConfig, key: &str) -> bool {
+    !metadata::is_protected(jail, key) && metadata::namespace(key) != Some("sdc")
+}
+
+/// Properties of the jail that are readable as `sdc:` keys
+fn builtin(jail: &JailConfig, key: &str) -> Option<String> {
+    match key {
+        "sdc:uuid" => Some(jail.uuid.hyphenated().to_string()),
+        "sdc:alias" => Some(jail.alias.clone()),
+        "sdc:hostname" => Some(jail.hostname.clone()),
+        "sdc:dns_domain" => Some(jail.dns_domain.clone()),
+        "sdc:resolvers" => serde_json::to_string(&jail.resolvers).ok(),
+        "sdc:nics" => serde_json::to_string(&jail.nics).ok(),
+        "sdc:routes" => serde_json::to_string(&jail.routes).ok(),
+        _ => None,
+    }
+}
+
+fn decode(data: &str) -> Option<String> {
+    base64::decode(data).ok().and_then(|d| String::from_utf8(d).ok())
+}
+
+fn frame(id: &str, code: &str, payload: Option<String>) -> String {
+    let body = match payload {
+        Some(payload) => format!("{} {} {}", id, code, base64::encode(payload.as_bytes())),
+        None => format!("{} {}", id, code),
+    };
+    format!("V2 {} {:08x} {}", body.len(), crc32::checksum_ieee(body.as_bytes()), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use executor::RecordingExecutor;
+    use mdata::*;
+
+    fn request(code: &str, payload: Option<&str>) -> String {
+        frame("dc4fae17", code, payload.map(String::from))
+    }
+
+    fn reply(line: &str) -> (String, Option<String>) {
+        assert!(line.starts_with("V2 "), "not a v2 reply: {}", line);
+        let body = line.splitn(4, ' ').nth(3).unwrap();
+        let mut parts = body.splitn(3, ' ');
+        assert_eq!(Some("dc4fae17"), parts.next());
+        let code = String::from(parts.next().unwrap());
+        (code, parts.next().and_then(decode))
+    }
+
+    #[test]
+    fn connections() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut slots: Vec<Slot> = (0..MAX_CONNECTIONS).map(|_| admit(&active).unwrap()).collect();
+        assert!(admit(&active).is_none());
+        slots.pop();
+        assert!(admit(&active).is_some());
+        assert_eq!(MAX_CONNECTIONS - 1, active.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn protocol() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap())
+            .unwrap();
+        jail.internal_metadata_namespaces = vec![String::from("sdc")];
+        jail.customer_metadata.insert(String::from("motd"), String::from("hello"));
+        jail.internal_metadata.insert(String::from("sdc:owner"), String::from("ops"));
+        let uuid = jail.uuid;
+        JDB::open(&conf).unwrap().insert(jail).unwrap();
+
+        assert_eq!("V2_OK", handle(&conf, &uuid, "NEGOTIATE V2"));
+        assert_eq!("invalid command", handle(&conf, &uuid, "GET motd"));
+        let get = |key: &str| reply(&handle(&conf, &uuid, &request("GET", Some(key))));
+        assert_eq!((String::from("SUCCESS"), Some(String::from("hello"))), get("motd"));
+        assert_eq!((String::from("SUCCESS"), Some(String::from("ops"))), get("sdc:owner"));
+        assert_eq!((String::from("SUCCESS"), Some(uuid.hyphenated().to_string())), get("sdc:uuid"));
+        assert_eq!((String::from("NOTFOUND"), None), get("nope"));
+
+        let put = format!("{} {}", base64::encode(b"color"), base64::encode(b"blue"));
+        let (code, _) = reply(&handle(&conf, &uuid, &request("PUT", Some(put.as_str()))));
+        assert_eq!("SUCCESS", code);
+        let (_, keys) = reply(&handle(&conf, &uuid, &request("KEYS", None)));
+        assert_eq!(Some(String::from("color\nmotd")), keys);
+
+        // the internal namespace is read only for the jail
+        let put = format!("{} {}", base64::encode(b"sdc:owner"), base64::encode(b"me"));
+        let (code, _) = reply(&handle(&conf, &uuid, &request("PUT", Some(put.as_str()))));
+        assert_eq!("FAILURE", code);
+        let (code, _) = reply(&handle(&conf, &uuid, &request("DELETE", Some("sdc:owner"))));
+        assert_eq!("FAILURE", code);
+
+        let mut session_out = Vec::new();
+        let input = format!("NEGOTIATE V2\n{}\n", request("DELETE", Some("motd")));
+        session(input.as_bytes(), &mut session_out, |l| Some(handle(&conf, &uuid, l))).unwrap();
+        let out = String::from_utf8(session_out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!("V2_OK", lines[0]);
+        assert_eq!("SUCCESS", reply(lines[1]).0);
+        {
+            let db = JDB::open(&conf).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(None, jail.config.customer_metadata.get("motd"));
+            assert_eq!(Some(&String::from("blue")), jail.config.customer_metadata.get("color"));
+            assert_eq!(Some(&String::from("ops")), jail.config.internal_metadata.get("sdc:owner"));
+        }
+
+        // a row of writes from the jail is a single version
+        {
+            let db = JDB::open(&conf).unwrap();
+            let history = db.history(&uuid).unwrap();
+            assert_eq!(2, history.len());
+            assert_eq!("guest-metadata", history[1].action);
+        }
+
+        // keys, values and requests are limited
+        let long = "x".repeat(MAX_KEY + 1);
+        let put = format!("{} {}", base64::encode(long.as_bytes()), base64::encode(b"v"));
+        let (code, _) = reply(&handle(&conf, &uuid, &request("PUT", Some(put.as_str()))));
+        assert_eq!("FAILURE", code);
+        let line = "x".repeat(MAX_LINE as usize + 1);
+        assert!(session(line.as_bytes(), Vec::new(), |_| Some(String::new())).is_err());
+
+        // a corrupted frame is rejected
+        let bad = request("GET", Some("motd")).replace("GET", "GOT");
+        assert_eq!("invalid command", handle(&conf, &uuid, bad.as_str()));
+    }
+}
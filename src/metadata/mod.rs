@@ -0,0 +1,171 @@
+//! SmartOS metadata namespaces
+//!
+//! Keys in `customer_metadata` can be changed from inside the jail,
+//! keys like `sdc:owner` whose namespace is listed in
+//! `internal_metadata_namespaces` are read from `internal_metadata`
+//! instead and are read only for the jail.
+
+use std::collections::BTreeMap as Map;
+use std::error::Error;
+
+use prettytable::Table;
+use prettytable::format;
+use prettytable::row::Row;
+use prettytable::cell::Cell;
+use serde_json;
+use uuid::Uuid;
+
+use config::Config;
+use errors::GenericError;
+use jail_config::JailConfig;
+use jails;
+use jdb::JDB;
+use update::JailUpdate;
+
+/// Which metadata map a key lives in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `customer_metadata`, writable by the jail
+    Customer,
+    /// `internal_metadata`, only writable by the operator
+    Internal,
+}
+
+/// Namespace of a key, the part before the first `:`
+pub fn namespace(key: &str) -> Option<&str> {
+    key.find(':').map(|i| &key[..i])
+}
+
+/// If the key belongs to one of the jail's internal namespaces
+pub fn is_protected(jail: &JailConfig, key: &str) -> bool {
+    match namespace(key) {
+        Some(ns) => jail.internal_metadata_namespaces.iter().any(|n| n == ns),
+        None => false,
+    }
+}
+
+/// Scope a key is stored in, keys in internal namespaces always are
+/// internal
+pub fn scope(jail: &JailConfig, key: &str, internal: bool) -> Scope {
+    if internal || is_protected(jail, key) {
+        Scope::Internal
+    } else {
+        Scope::Customer
+    }
+}
+
+/// Value of a key as the jail sees it
+pub fn value<'c>(jail: &'c JailConfig, key: &str) -> Option<&'c String> {
+    if is_protected(jail, key) {
+        jail.internal_metadata.get(key)
+    } else {
+        jail.customer_metadata.get(key)
+    }
+}
+
+/// Prints the metadata keys of a jail
+pub fn list(config: &Config, uuid: &Uuid, json: bool) -> Result<i32, Box<Error>> {
+    let db = JDB::open(config)?;
+    let jail = db.get(uuid)?;
+    if json {
+        #[derive(Serialize)]
+        struct Metadata<'c> {
+            customer_metadata: &'c Map<String, String>,
+            internal_metadata: &'c Map<String, String>,
+        }
+        let metadata = Metadata {
+            customer_metadata: &jail.config.customer_metadata,
+            internal_metadata: &jail.config.internal_metadata,
+        };
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+        return Ok(0);
+    }
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.add_row(row!["KEY", "SCOPE"]);
+    let keys = jail.config
+        .customer_metadata
+        .keys()
+        .map(|k| (k, "customer"))
+        .chain(jail.config.internal_metadata.keys().map(|k| (k, "internal")));
+    for (key, scope) in keys {
+        table.add_row(Row::new(vec![Cell::new(key.as_str()), Cell::new(scope)]));
+    }
+    table.printstd();
+    Ok(0)
+}
+
+/// Prints the value of a key as the jail sees it
+pub fn get(config: &Config, uuid: &Uuid, key: &str) -> Result<i32, Box<Error>> {
+    let db = JDB::open(config)?;
+    let jail = db.get(uuid)?;
+    match value(&jail.config, key) {
+        Some(value) => {
+            println!("{}", value);
+            Ok(0)
+        }
+        None => Err(GenericError::bx(
+            format!("No metadata for '{}'", key).as_str(),
+        )),
+    }
+}
+
+/// Sets a key, this is recorded in the config history like any update
+pub fn set(config: &Config, uuid: &Uuid, key: &str, value: &str, internal: bool) -> Result<i32, Box<Error>> {
+    jails::update(config, uuid, &metadata_update(key, Some(value), internal))
+}
+
+/// Removes a key
+pub fn delete(config: &Config, uuid: &Uuid, key: &str, internal: bool) -> Result<i32, Box<Error>> {
+    jails::update(config, uuid, &metadata_update(key, None, internal))
+}
+
+/// Keys that aren't forced into `internal_metadata` are placed by the
+/// namespaces the jail has once it is locked
+fn metadata_update(key: &str, value: Option<&str>, internal: bool) -> JailUpdate {
+    if internal {
+        JailUpdate::metadata(Scope::Internal, key, value)
+    } else {
+        JailUpdate::namespaced_metadata(key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use executor::RecordingExecutor;
+    use metadata::*;
+
+    #[test]
+    fn namespaces() {
+        let rec = RecordingExecutor::new();
+        let conf = Config::test(Box::new(rec));
+        let mut jail = JailConfig::from_reader(File::open("examples/example.json").unwrap())
+            .unwrap();
+        jail.internal_metadata_namespaces = vec![String::from("sdc")];
+        let uuid = jail.uuid;
+        JDB::open(&conf).unwrap().insert(jail).unwrap();
+
+        set(&conf, &uuid, "motd", "hello", false).unwrap();
+        set(&conf, &uuid, "sdc:owner", "ops", false).unwrap();
+        set(&conf, &uuid, "billing", "42", true).unwrap();
+        {
+            let db = JDB::open(&conf).unwrap();
+            let jail = db.get(&uuid).unwrap().config;
+            assert_eq!(Some(&String::from("hello")), jail.customer_metadata.get("motd"));
+            // keys in an internal namespace never end up in customer_metadata
+            assert_eq!(None, jail.customer_metadata.get("sdc:owner"));
+            assert_eq!(Some(&String::from("ops")), value(&jail, "sdc:owner"));
+            assert_eq!(None, value(&jail, "billing"));
+            assert_eq!(Some(&String::from("42")), jail.internal_metadata.get("billing"));
+        }
+        let update = JailUpdate::from_reader(
+            r#"{"set_customer_metadata": {"sdc:owner": "me"}}"#.as_bytes(),
+        ).unwrap();
+        assert!(jails::update(&conf, &uuid, &update).is_err());
+        delete(&conf, &uuid, "motd", false).unwrap();
+        assert!(delete(&conf, &uuid, "motd", false).is_err());
+        assert!(JDB::open(&conf).unwrap().get(&uuid).unwrap().config.customer_metadata.is_empty());
+    }
+}
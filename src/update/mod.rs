@@ -1,12 +1,15 @@
 //! Update for a jail
-use jail_config::{JailConfig, NIC};
+use jail_config::{Filesystem, JailConfig, NIC};
 use std::error::Error;
 use std::io::Read;
 use serde_json;
 use uuid::Uuid;
-use jdb::IdxEntry;
-use zfs;
 use std::collections::BTreeMap as Map;
+use config::Config;
+use errors::{codes, ValidationError};
+use metadata;
+use metadata::Scope;
+use serde::{Deserialize, Deserializer};
 
 macro_rules! update {
     ( $src:ident, $target:ident; $($field:ident),+)  => (
@@ -31,13 +34,18 @@ macro_rules! update_option {
 
 
 /// update the nics
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct NICUpdate {
     mac: String,
     nic_tag: Option<String>,
     ip: Option<String>,
     gateway: Option<String>,
     netmask: Option<String>,
+    ips: Option<Vec<String>>,
+    gateways: Option<Vec<String>>,
+    ipv6: Option<String>,
+    ipv6_gateway: Option<String>,
+    accept_rtadv: Option<bool>,
     vlan: Option<u16>,
     primary: Option<bool>,
     mtu: Option<u32>,
@@ -53,6 +61,11 @@ impl NICUpdate {
             ip: None,
             gateway: None,
             netmask: None,
+            ips: None,
+            gateways: None,
+            ipv6: None,
+            ipv6_gateway: None,
+            accept_rtadv: None,
             vlan: None,
             primary: None,
             mtu: None,
@@ -71,9 +84,14 @@ impl NICUpdate {
                 ip,
                 netmask,
                 gateway,
+                ips,
+                gateways,
+                accept_rtadv,
                 primary
         );
         update_option!(self, nic;
+                       ipv6,
+                       ipv6_gateway,
                        vlan,
                        mtu,
                        network_uuid
@@ -83,7 +101,7 @@ impl NICUpdate {
 }
 
 /// Jail update
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JailUpdate {
     /// readable alias for the jail
     alias: Option<String>,
@@ -97,6 +115,24 @@ pub struct JailUpdate {
     cpu_cap: Option<u64>,
     /// max quota (zfs quota)
     quota: Option<u64>,
+    /// compression of the jail's dataset, `null` inherits it
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    zfs_root_compression: Option<Option<String>>,
+    /// record size of the jail's dataset in bytes, `null` inherits it
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    zfs_root_recordsize: Option<Option<u64>>,
+    /// update access times, `null` inherits it
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    atime: Option<Option<bool>>,
+    /// guaranteed space in GB including snapshots, `null` removes it
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    reservation: Option<Option<u64>>,
+    /// guaranteed space in GB, `null` removes it
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    refreservation: Option<Option<u64>>,
+    /// quota in GB not counting snapshots, `null` removes it
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    refquota: Option<Option<u64>>,
     /// SysV shared memory size, in bytes (shmsize)
     max_shm_memory: Option<u64>,
 
@@ -105,6 +141,40 @@ pub struct JailUpdate {
 
     /// maximum number of porocesses (maxproc)
     max_lwps: Option<u64>,
+    /// swap in MB (swapuse)
+    max_swap: Option<u64>,
+    /// virtual memory in MB (vmemoryuse)
+    max_virtual_memory: Option<u64>,
+    /// open file descriptors (openfiles)
+    max_open_files: Option<u64>,
+    /// threads (nthr)
+    max_threads: Option<u64>,
+    /// queued SysV messages (msgqqueued)
+    max_msg_queued: Option<u64>,
+    /// SysV semaphores (semcnt)
+    max_sem_count: Option<u64>,
+    /// disk reads in bytes per second (readbps)
+    read_bps: Option<u64>,
+    /// disk writes in bytes per second (writebps)
+    write_bps: Option<u64>,
+    /// disk reads per second (readiops)
+    read_iops: Option<u64>,
+    /// disk writes per second (writeiops)
+    write_iops: Option<u64>,
+    /// cores the jail is pinned to, an empty string unpins it
+    cpuset: Option<String>,
+    /// protect the delegated dataset from being destroyed
+    indestructible_delegated: Option<bool>,
+    /// allow locking memory (allow.mlock)
+    allow_mlock: Option<bool>,
+    /// share SysV IPC with the host
+    allow_sysvipc: Option<bool>,
+    /// filesystems the jail can mount, replaces the current list
+    allow_mount: Option<Vec<String>>,
+    /// mounts visible in the jail (enforce_statfs)
+    enforce_statfs: Option<u8>,
+    /// kernel securelevel of the jail
+    securelevel: Option<i8>,
 
     // Metadata fields w/o effect on vmadm at the moment
     archive_on_delete: Option<bool>,
@@ -127,6 +197,33 @@ pub struct JailUpdate {
     remove_routes: Vec<String>,
     #[serde(default = "empty_map")]
     set_routes: Map<String, String>,
+
+    #[serde(default = "empty_filesystems")]
+    add_filesystems: Vec<Filesystem>,
+    /// targets of the filesystems to remove
+    #[serde(default = "empty_svec")]
+    remove_filesystems: Vec<String>,
+
+    #[serde(default = "empty_map")]
+    set_jail_params: Map<String, String>,
+    #[serde(default = "empty_svec")]
+    remove_jail_params: Vec<String>,
+
+    #[serde(default = "empty_map")]
+    set_customer_metadata: Map<String, String>,
+    #[serde(default = "empty_svec")]
+    remove_customer_metadata: Vec<String>,
+    #[serde(default = "empty_map")]
+    set_internal_metadata: Map<String, String>,
+    #[serde(default = "empty_svec")]
+    remove_internal_metadata: Vec<String>,
+    internal_metadata_namespaces: Option<Vec<String>>,
+    /// metadata that goes to `customer_metadata` or `internal_metadata`
+    /// depending on the namespaces of the jail when the update is applied
+    #[serde(default = "empty_map", skip_serializing_if = "Map::is_empty")]
+    set_metadata: Map<String, String>,
+    #[serde(default = "empty_svec", skip_serializing_if = "Vec::is_empty")]
+    remove_metadata: Vec<String>,
 }
 
 impl JailUpdate {
@@ -138,7 +235,7 @@ impl JailUpdate {
         let update: JailUpdate = serde_json::from_reader(reader)?;
         return Ok(update);
     }
-    #[cfg(test)]
+    /// Update that changes nothing
     pub fn empty() -> Self {
         JailUpdate {
             alias: None,
@@ -149,6 +246,23 @@ impl JailUpdate {
             max_shm_memory: None,
             max_locked_memory: None,
             max_lwps: None,
+            max_swap: None,
+            max_virtual_memory: None,
+            max_open_files: None,
+            max_threads: None,
+            max_msg_queued: None,
+            max_sem_count: None,
+            read_bps: None,
+            write_bps: None,
+            read_iops: None,
+            write_iops: None,
+            cpuset: None,
+            indestructible_delegated: None,
+            allow_mlock: None,
+            allow_sysvipc: None,
+            allow_mount: None,
+            enforce_statfs: None,
+            securelevel: None,
             archive_on_delete: None,
             billing_id: None,
             do_not_inventory: None,
@@ -157,27 +271,204 @@ impl JailUpdate {
             package_name: None,
             package_version: None,
             quota: None,
+            zfs_root_compression: None,
+            zfs_root_recordsize: None,
+            atime: None,
+            reservation: None,
+            refreservation: None,
+            refquota: None,
             add_nics: vec![],
             remove_nics: vec![],
             update_nics: vec![],
             remove_routes: vec![],
-            set_routes: Map::new()
+            set_routes: Map::new(),
+            add_filesystems: vec![],
+            remove_filesystems: vec![],
+            set_jail_params: Map::new(),
+            remove_jail_params: vec![],
+            set_customer_metadata: Map::new(),
+            remove_customer_metadata: vec![],
+            set_internal_metadata: Map::new(),
+            remove_internal_metadata: vec![],
+            internal_metadata_namespaces: None,
+            set_metadata: Map::new(),
+            remove_metadata: vec![],
         }
     }
-    pub fn apply(&self, config: JailConfig, index: &IdxEntry) -> Result<JailConfig, Box<Error>> {
-        let mut c = config.clone();
+
+    /// Update that sets, or with `None` removes, a single metadata key
+    pub fn metadata(scope: Scope, key: &str, value: Option<&str>) -> Self {
+        let mut update = JailUpdate::empty();
+        let key = String::from(key);
+        match (scope, value) {
+            (Scope::Customer, Some(value)) => {
+                update.set_customer_metadata.insert(key, String::from(value));
+            }
+            (Scope::Customer, None) => update.remove_customer_metadata.push(key),
+            (Scope::Internal, Some(value)) => {
+                update.set_internal_metadata.insert(key, String::from(value));
+            }
+            (Scope::Internal, None) => update.remove_internal_metadata.push(key),
+        }
+        update
+    }
+    /// Update of a metadata key or its removal, the scope is resolved
+    /// with the namespaces of the jail when the update is applied, under
+    /// the jail's lock
+    pub fn namespaced_metadata(key: &str, value: Option<&str>) -> Self {
+        let mut update = JailUpdate::empty();
+        let key = String::from(key);
+        match value {
+            Some(value) => {
+                update.set_metadata.insert(key, String::from(value));
+            }
+            None => update.remove_metadata.push(key),
+        }
+        update
+    }
+    /// checks the update against the current config of a jail and then
+    /// checks the config it results in, new addresses are probed on the
+    /// network as set by `ip_probe`
+    pub fn errors(&self, config: &Config, current: &JailConfig) -> Option<Vec<ValidationError>> {
+        self.validate(config, current, true)
+    }
+
+    /// checks the update like `errors` without probing the network
+    pub fn offline_errors(&self, config: &Config, current: &JailConfig) -> Option<Vec<ValidationError>> {
+        self.validate(config, current, false)
+    }
+
+    fn validate(&self, config: &Config, current: &JailConfig, probe: bool) -> Option<Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        let known = |mac: &str| current.nics.iter().any(|nic| nic.mac == mac);
+        for (i, mac) in self.remove_nics.iter().enumerate() {
+            if !known(mac) {
+                errors.push(ValidationError::new(
+                    format!("remove_nics.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("The jail has no nic with mac {}", mac).as_str(),
+                ))
+            }
+        }
+        for (i, update) in self.update_nics.iter().enumerate() {
+            if !known(update.mac.as_str()) && !self.add_nics.iter().any(|n| n.mac == update.mac) {
+                errors.push(ValidationError::new(
+                    format!("update_nics.{}.mac", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("The jail has no nic with mac {}", update.mac).as_str(),
+                ))
+            }
+        }
+        for (i, route) in self.remove_routes.iter().enumerate() {
+            if !current.routes.contains_key(route) {
+                errors.push(ValidationError::new(
+                    format!("remove_routes.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("The jail has no route to {}", route).as_str(),
+                ))
+            }
+        }
+        for (i, target) in self.remove_filesystems.iter().enumerate() {
+            if !current.filesystems.iter().any(|fs| &fs.target == target) {
+                errors.push(ValidationError::new(
+                    format!("remove_filesystems.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("The jail has no filesystem mounted on {}", target).as_str(),
+                ))
+            }
+        }
+        for (i, name) in self.remove_jail_params.iter().enumerate() {
+            if !current.jail_params.contains_key(name) {
+                errors.push(ValidationError::new(
+                    format!("remove_jail_params.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("The jail does not set {}", name).as_str(),
+                ))
+            }
+        }
+        for (i, key) in self.remove_customer_metadata.iter().enumerate() {
+            if !current.customer_metadata.contains_key(key) {
+                errors.push(ValidationError::new(
+                    format!("remove_customer_metadata.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("No customer_metadata for {}", key).as_str(),
+                ))
+            }
+        }
+        for (i, key) in self.remove_internal_metadata.iter().enumerate() {
+            if !current.internal_metadata.contains_key(key) {
+                errors.push(ValidationError::new(
+                    format!("remove_internal_metadata.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("No internal_metadata for {}", key).as_str(),
+                ))
+            }
+        }
+        for (i, key) in self.remove_metadata.iter().enumerate() {
+            if metadata::value(current, key).is_none() {
+                errors.push(ValidationError::new(
+                    format!("remove_metadata.{}", i).as_str(),
+                    codes::UNKNOWN,
+                    format!("No metadata for {}", key).as_str(),
+                ))
+            }
+        }
+        // fields of the updated config are reported by their path in it
+        let updated = self.apply(current.clone());
+        let result = if probe {
+            updated.update_errors(config, current)
+        } else {
+            updated.offline_errors(config)
+        };
+        // a jail that already breaks a rule can still be updated, only
+        // the errors the update brings in are reported
+        let existing = current.offline_errors(config).unwrap_or_default();
+        errors.extend(result.unwrap_or_default().into_iter().filter(|e| !existing.contains(e)));
+        if errors.is_empty() {
+            None
+        } else {
+            Some(errors)
+        }
+    }
+
+    /// Applies the update to a config, the resulting config is returned
+    /// and side effects (like the zfs quota) are left to the caller.
+    pub fn apply(&self, config: JailConfig) -> JailConfig {
+        let mut c = config;
         update!(self, c;
                 autoboot,
                 alias,
                 hostname,
                 max_physical_memory,
                 cpu_cap,
+                quota,
+                zfs_root_compression,
+                zfs_root_recordsize,
+                atime,
+                reservation,
+                refreservation,
+                refquota,
                 max_lwps,
                 dns_domain
         );
         update_option!(self, c;
             max_shm_memory,
             max_locked_memory,
+            max_swap,
+            max_virtual_memory,
+            max_open_files,
+            max_threads,
+            max_msg_queued,
+            max_sem_count,
+            read_bps,
+            write_bps,
+            read_iops,
+            write_iops,
+            indestructible_delegated,
+            allow_mlock,
+            allow_sysvipc,
+            enforce_statfs,
+            securelevel,
             archive_on_delete,
             billing_id,
             do_not_inventory,
@@ -186,6 +477,14 @@ impl JailUpdate {
             package_version
         );
 
+        if let Some(ref cpuset) = self.cpuset {
+            c.cpuset = if cpuset.is_empty() {
+                None
+            } else {
+                Some(cpuset.clone())
+            };
+        }
+
         c.nics.retain(|nic| !self.remove_nics.contains(&nic.mac));
         for nic in self.add_nics.iter() {
             c.nics.push(nic.clone());
@@ -202,16 +501,57 @@ impl JailUpdate {
             };
         }
 
-        if self.quota.is_some() {
-            zfs::quota(index.root.as_str(), self.quota.unwrap())?;
-        }
         for remove_route in self.remove_routes.iter() {
             c.routes.remove(remove_route);
         }
         for (route, gw) in self.set_routes.iter() {
             c.routes.insert(route.clone(), gw.clone());
         }
-        return Ok(c);
+
+        c.filesystems.retain(|fs| !self.remove_filesystems.contains(&fs.target));
+        for fs in self.add_filesystems.iter() {
+            c.filesystems.push(fs.clone());
+        }
+
+        if let Some(ref allow_mount) = self.allow_mount {
+            c.allow_mount = allow_mount.clone();
+        }
+        for name in self.remove_jail_params.iter() {
+            c.jail_params.remove(name);
+        }
+        for (name, value) in self.set_jail_params.iter() {
+            c.jail_params.insert(name.clone(), value.clone());
+        }
+
+        if let Some(ref namespaces) = self.internal_metadata_namespaces {
+            c.internal_metadata_namespaces = namespaces.clone();
+        }
+        for key in self.remove_customer_metadata.iter() {
+            c.customer_metadata.remove(key);
+        }
+        for (key, value) in self.set_customer_metadata.iter() {
+            c.customer_metadata.insert(key.clone(), value.clone());
+        }
+        for key in self.remove_internal_metadata.iter() {
+            c.internal_metadata.remove(key);
+        }
+        for (key, value) in self.set_internal_metadata.iter() {
+            c.internal_metadata.insert(key.clone(), value.clone());
+        }
+        for key in self.remove_metadata.iter() {
+            match metadata::scope(&c, key, false) {
+                Scope::Customer => c.customer_metadata.remove(key),
+                Scope::Internal => c.internal_metadata.remove(key),
+            };
+        }
+        for (key, value) in self.set_metadata.iter() {
+            let (key, value) = (key.clone(), value.clone());
+            match metadata::scope(&c, key.as_str(), false) {
+                Scope::Customer => c.customer_metadata.insert(key, value),
+                Scope::Internal => c.internal_metadata.insert(key, value),
+            };
+        }
+        c
     }
 }
 
@@ -233,14 +573,28 @@ fn empty_nics() -> Vec<NIC> {
     Vec::new()
 }
 
+fn empty_filesystems() -> Vec<Filesystem> {
+    Vec::new()
+}
+
+/// Tells a field that is missing (`None`) from one that is `null`
+/// (`Some(None)`), so updates can clear optional fields
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
 
 #[cfg(test)]
 mod tests {
+    use executor::RecordingExecutor;
     use std::collections::BTreeMap as Map;
     use jail_config::JailConfig;
     use update::*;
     use uuid::Uuid;
-    use jdb::IdxEntry;
 
     fn nic00() -> NIC {
         NIC{
@@ -251,6 +605,11 @@ mod tests {
             ip: String::from("192.168.254.254"),
             netmask: String::from("255.255.255.0"),
             gateway: String::from("192.168.254.1"),
+            ips: vec![],
+            gateways: vec![],
+            ipv6: None,
+            ipv6_gateway: None,
+            accept_rtadv: false,
             primary: true,
             mtu: None,
             network_uuid: None
@@ -265,6 +624,11 @@ mod tests {
             ip: String::from("192.168.254.253"),
             netmask: String::from("255.255.255.0"),
             gateway: String::from("192.168.254.1"),
+            ips: vec![],
+            gateways: vec![],
+            ipv6: None,
+            ipv6_gateway: None,
+            accept_rtadv: false,
             primary: false,
             mtu: None,
             network_uuid: None
@@ -279,6 +643,11 @@ mod tests {
             ip: String::from("192.168.254.252"),
             netmask: String::from("255.255.255.0"),
             gateway: String::from("192.168.254.1"),
+            ips: vec![],
+            gateways: vec![],
+            ipv6: None,
+            ipv6_gateway: None,
+            accept_rtadv: false,
             primary: false,
             mtu: None,
             network_uuid: None
@@ -296,14 +665,32 @@ mod tests {
             max_physical_memory: 1024,
             cpu_cap: 100,
             quota: 5,
+            zfs_root_compression: Some(String::from("lz4")),
+            zfs_root_recordsize: None,
+            atime: None,
+            reservation: None,
+            refreservation: None,
+            refquota: None,
             max_shm_memory: None,
             max_locked_memory: None,
             nics: vec![nic00(), nic01()],
             max_lwps: 2000,
+            max_swap: None,
+            max_virtual_memory: None,
+            max_open_files: None,
+            max_threads: None,
+            max_msg_queued: None,
+            max_sem_count: None,
+            read_bps: None,
+            write_bps: None,
+            read_iops: None,
+            write_iops: None,
+            cpuset: None,
             archive_on_delete: None,
             billing_id: None,
             do_not_inventory: None,
             dns_domain: String::from("local"),
+            delegate_dataset: false,
             indestructible_delegated: None,
             indestructible_zoneroot: None,
             owner_uuid: None,
@@ -312,7 +699,15 @@ mod tests {
             resolvers: Vec::new(),
             customer_metadata: Map::new(),
             internal_metadata: Map::new(),
+            internal_metadata_namespaces: vec![],
             routes: Map::new(),
+            filesystems: vec![],
+            jail_params: Map::new(),
+            allow_mlock: None,
+            allow_sysvipc: None,
+            allow_mount: vec![],
+            enforce_statfs: None,
+            securelevel: None,
         }
     }
 
@@ -325,7 +720,7 @@ mod tests {
     fn empty() {
         let conf = conf();
         let update = JailUpdate::empty();
-        let conf1 = update.apply(conf.clone(), &IdxEntry::empty()).unwrap();
+        let conf1 = update.apply(conf.clone());
         assert_eq!(conf, conf1);
     }
     #[test]
@@ -334,7 +729,7 @@ mod tests {
         let mut update = JailUpdate::empty();
         let alias = String::from("changed");
         update.alias = Some(alias.clone());
-        assert_eq!(alias, update.apply(conf, &IdxEntry::empty()).unwrap().alias);
+        assert_eq!(alias, update.apply(conf).alias);
     }
     #[test]
     fn hostname() {
@@ -342,7 +737,7 @@ mod tests {
         let mut update = JailUpdate::empty();
         let hostname = String::from("changed");
         update.hostname = Some(hostname.clone());
-        assert_eq!(hostname, update.apply(conf, &IdxEntry::empty()).unwrap().hostname);
+        assert_eq!(hostname, update.apply(conf).hostname);
     }
     #[test]
     fn autoboot() {
@@ -350,7 +745,7 @@ mod tests {
         assert_eq!(true, conf.autoboot);
         let mut update = JailUpdate::empty();
         update.autoboot = Some(false);
-        assert_eq!(false, update.apply(conf, &IdxEntry::empty()).unwrap().autoboot);
+        assert_eq!(false, update.apply(conf).autoboot);
     }
     #[test]
     fn max_physical_memory() {
@@ -358,7 +753,7 @@ mod tests {
         assert_eq!(1024, conf.max_physical_memory);
         let mut update = JailUpdate::empty();
         update.max_physical_memory = Some(42);
-        assert_eq!(42, update.apply(conf, &IdxEntry::empty()).unwrap().max_physical_memory);
+        assert_eq!(42, update.apply(conf).max_physical_memory);
     }
     #[test]
     fn max_locked_memory() {
@@ -366,7 +761,30 @@ mod tests {
         assert_eq!(None, conf.max_locked_memory);
         let mut update = JailUpdate::empty();
         update.max_locked_memory = Some(42);
-        assert_eq!(42, update.apply(conf, &IdxEntry::empty()).unwrap().max_locked_memory.unwrap());
+        assert_eq!(42, update.apply(conf).max_locked_memory.unwrap());
+    }
+    #[test]
+    fn quota() {
+        let conf = conf();
+        assert_eq!(5, conf.quota);
+        let mut update = JailUpdate::empty();
+        update.quota = Some(42);
+        assert_eq!(42, update.apply(conf).quota);
+    }
+    #[test]
+    fn zfs_properties() {
+        let mut conf = conf();
+        conf.refquota = Some(2);
+        conf.atime = Some(false);
+        let update = JailUpdate::from_reader(
+            r#"{"refquota": null, "zfs_root_compression": null, "zfs_root_recordsize": 8192}"#.as_bytes(),
+        ).unwrap();
+        let conf1 = update.apply(conf);
+        assert_eq!(None, conf1.refquota);
+        assert_eq!(None, conf1.zfs_root_compression);
+        assert_eq!(Some(8192), conf1.zfs_root_recordsize);
+        // fields that are left out stay as they are
+        assert_eq!(Some(false), conf1.atime);
     }
     #[test]
     fn max_lwps() {
@@ -374,7 +792,18 @@ mod tests {
         assert_eq!(2000, conf.max_lwps);
         let mut update = JailUpdate::empty();
         update.max_lwps = Some(42);
-        assert_eq!(42, update.apply(conf, &IdxEntry::empty()).unwrap().max_lwps);
+        assert_eq!(42, update.apply(conf).max_lwps);
+    }
+    #[test]
+    fn rctl_limits() {
+        let conf = conf();
+        let mut update = JailUpdate::empty();
+        update.max_swap = Some(2048);
+        update.write_iops = Some(500);
+        let conf = update.apply(conf);
+        assert_eq!(Some(2048), conf.max_swap);
+        assert_eq!(Some(500), conf.write_iops);
+        assert_eq!(None, conf.read_iops);
     }
     #[test]
     fn archive_on_delete() {
@@ -382,7 +811,7 @@ mod tests {
         assert_eq!(None, conf.archive_on_delete);
         let mut update = JailUpdate::empty();
         update.archive_on_delete = Some(true);
-        assert_eq!(true, update.apply(conf, &IdxEntry::empty()).unwrap().archive_on_delete.unwrap());
+        assert_eq!(true, update.apply(conf).archive_on_delete.unwrap());
     }
     #[test]
     fn billing_id() {
@@ -390,7 +819,7 @@ mod tests {
         assert_eq!(None, conf.billing_id);
         let mut update = JailUpdate::empty();
         update.billing_id = Some(uuid());
-        assert_eq!(uuid(), update.apply(conf, &IdxEntry::empty()).unwrap().billing_id.unwrap());
+        assert_eq!(uuid(), update.apply(conf).billing_id.unwrap());
     }
     #[test]
     fn no_not_inventory() {
@@ -398,7 +827,7 @@ mod tests {
         assert_eq!(None, conf.do_not_inventory);
         let mut update = JailUpdate::empty();
         update.do_not_inventory = Some(true);
-        assert_eq!(true, update.apply(conf, &IdxEntry::empty()).unwrap().do_not_inventory.unwrap());
+        assert_eq!(true, update.apply(conf).do_not_inventory.unwrap());
     }
     #[test]
     fn dns_domain() {
@@ -406,7 +835,7 @@ mod tests {
         let mut update = JailUpdate::empty();
         let dns_domain = String::from("changed");
         update.dns_domain = Some(dns_domain.clone());
-        assert_eq!(dns_domain, update.apply(conf, &IdxEntry::empty()).unwrap().dns_domain);
+        assert_eq!(dns_domain, update.apply(conf).dns_domain);
     }
     #[test]
     fn owner_uuid() {
@@ -414,7 +843,7 @@ mod tests {
         assert_eq!(None, conf.owner_uuid);
         let mut update = JailUpdate::empty();
         update.owner_uuid = Some(uuid());
-        assert_eq!(uuid(), update.apply(conf, &IdxEntry::empty()).unwrap().owner_uuid.unwrap());
+        assert_eq!(uuid(), update.apply(conf).owner_uuid.unwrap());
     }
     #[test]
     fn package_name() {
@@ -422,7 +851,7 @@ mod tests {
         let mut update = JailUpdate::empty();
         let package_name = String::from("changed");
         update.package_name = Some(package_name.clone());
-        assert_eq!(package_name, update.apply(conf, &IdxEntry::empty()).unwrap().package_name.unwrap());
+        assert_eq!(package_name, update.apply(conf).package_name.unwrap());
     }
     #[test]
     fn package_version() {
@@ -430,7 +859,7 @@ mod tests {
         let mut update = JailUpdate::empty();
         let package_version = String::from("changed");
         update.package_version = Some(package_version.clone());
-        assert_eq!(package_version, update.apply(conf, &IdxEntry::empty()).unwrap().package_version.unwrap());
+        assert_eq!(package_version, update.apply(conf).package_version.unwrap());
     }
 
     #[test]
@@ -439,14 +868,14 @@ mod tests {
         let mut update = JailUpdate::empty();
         let mac = String::from("00:00:00:00:00:00");
         update.remove_nics = vec![mac];
-        assert_eq!(vec![nic01()], update.apply(conf, &IdxEntry::empty()).unwrap().nics);
+        assert_eq!(vec![nic01()], update.apply(conf).nics);
     }
     #[test]
     fn add_nics() {
         let conf = conf();
         let mut update = JailUpdate::empty();
         update.add_nics = vec![nic02()];
-        assert_eq!(vec![nic00(), nic01(), nic02()], update.apply(conf, &IdxEntry::empty()).unwrap().nics);
+        assert_eq!(vec![nic00(), nic01(), nic02()], update.apply(conf).nics);
     }
 
     #[test]
@@ -456,7 +885,7 @@ mod tests {
         let mut nic_update = NICUpdate::empty(nic01().mac.clone());
         nic_update.primary = Some(true);
         update.update_nics = vec![nic_update];
-        let conf1 = update.apply(conf.clone(), &IdxEntry::empty()).unwrap();
+        let conf1 = update.apply(conf.clone());
 
         assert_eq!(false, conf1.nics[0].primary);
         assert_eq!(true, conf1.nics[1].primary);
@@ -470,7 +899,7 @@ mod tests {
         let target = String::from("10.0.0.0/24");
         let gw = String::from("10.0.1.0");
         update.set_routes.insert(target.clone(), gw.clone());
-        let updated = update.apply(conf, &IdxEntry::empty()).unwrap();
+        let updated = update.apply(conf);
         assert!(!updated.routes.is_empty());
         assert_eq!(&gw, updated.routes.get(&target).unwrap());
     }
@@ -484,7 +913,7 @@ mod tests {
         let gw2 = String::from("10.0.2.0");
         conf.routes.insert(target.clone(), gw.clone());
         update.set_routes.insert(target.clone(), gw2.clone());
-        let updated = update.apply(conf, &IdxEntry::empty()).unwrap();
+        let updated = update.apply(conf);
         assert!(!updated.routes.is_empty());
         assert_eq!(&gw2, updated.routes.get(&target).unwrap());
     }
@@ -497,7 +926,7 @@ mod tests {
         let gw = String::from("10.0.1.0");
         conf.routes.insert(target.clone(), gw);
         update.remove_routes = vec![target];
-        assert!(update.apply(conf, &IdxEntry::empty()).unwrap().routes.is_empty());
+        assert!(update.apply(conf).routes.is_empty());
     }
     #[test]
     fn remove_routes_not_found() {
@@ -505,7 +934,60 @@ mod tests {
         let mut update = JailUpdate::empty();
         let target = String::from("10.0.0.0/24");
         update.remove_routes = vec![target];
-        assert!(update.apply(conf, &IdxEntry::empty()).unwrap().routes.is_empty());
+        assert!(update.apply(conf).routes.is_empty());
+    }
+
+    #[test]
+    fn filesystems() {
+        let mut conf = conf();
+        let certs = Filesystem {
+            source: String::from("/usr/local/etc/certs"),
+            target: String::from("/etc/certs"),
+            fs_type: String::from("nullfs"),
+            options: vec![String::from("ro")],
+        };
+        let tmp = Filesystem {
+            source: String::new(),
+            target: String::from("/tmp"),
+            fs_type: String::from("tmpfs"),
+            options: vec![],
+        };
+        conf.filesystems = vec![certs.clone()];
+        let mut update = JailUpdate::empty();
+        update.remove_filesystems = vec![String::from("/etc/certs")];
+        update.add_filesystems = vec![tmp.clone()];
+        assert_eq!(vec![tmp], update.apply(conf).filesystems);
+        assert_eq!("nullfs\t/usr/local/etc/certs\t/etc/certs\tro", certs.mount_line());
+    }
+
+    #[test]
+    fn jail_params() {
+        let mut conf = conf();
+        conf.jail_params.insert(String::from("allow.chflags"), String::from("true"));
+        let update = JailUpdate::from_reader(
+            r#"{"remove_jail_params": ["allow.chflags"], "set_jail_params": {"osrelease": "11.1-RELEASE"},
+                "allow_mount": ["nullfs"], "securelevel": 1}"#.as_bytes(),
+        ).unwrap();
+        let updated = update.apply(conf);
+        assert_eq!(Some(&String::from("11.1-RELEASE")), updated.jail_params.get("osrelease"));
+        assert_eq!(None, updated.jail_params.get("allow.chflags"));
+        assert_eq!(vec![String::from("nullfs")], updated.allow_mount);
+        assert_eq!(Some(1), updated.securelevel);
+    }
+
+    #[test]
+    fn namespaced_metadata() {
+        let mut conf = conf();
+        let update = JailUpdate::namespaced_metadata("sdc:owner", Some("ops"));
+        let updated = update.apply(conf.clone());
+        assert_eq!(Some(&String::from("ops")), updated.customer_metadata.get("sdc:owner"));
+        // the scope follows the namespaces the jail has when it is applied
+        conf.internal_metadata_namespaces = vec![String::from("sdc")];
+        let updated = update.apply(conf);
+        assert_eq!(None, updated.customer_metadata.get("sdc:owner"));
+        assert_eq!(Some(&String::from("ops")), updated.internal_metadata.get("sdc:owner"));
+        let remove = JailUpdate::namespaced_metadata("sdc:owner", None);
+        assert!(remove.apply(updated).internal_metadata.is_empty());
     }
 
     // nic update tests
@@ -534,7 +1016,9 @@ mod tests {
         let mut update = NICUpdate::empty(nic.mac.clone());
         let ip = String::from("192.168.1.254");
         update.ip = Some(ip.clone());
-        assert_eq!(ip, update.apply(nic).ip);
+        let updated = update.apply(nic.clone());
+        assert_eq!(ip, updated.ip);
+        assert!(nic != updated);
     }
     #[test]
     fn nic_gateway() {
@@ -576,6 +1060,82 @@ mod tests {
         update.mtu = Some(mtu);
         assert_eq!(mtu, update.apply(nic).mtu.unwrap());
     }
+    #[test]
+    fn nic_ips() {
+        let nic = nic01();
+        let mut update = NICUpdate::empty(nic.mac.clone());
+        let ips = vec![String::from("10.0.0.5/24"), String::from("dhcp")];
+        let gateways = vec![String::from("10.0.0.1")];
+        update.ips = Some(ips.clone());
+        update.gateways = Some(gateways.clone());
+        let nic = update.apply(nic);
+        assert_eq!(ips, nic.ips);
+        assert_eq!(gateways, nic.gateways);
+    }
+    #[test]
+    fn nic_ipv6() {
+        let nic = nic01();
+        let mut update = NICUpdate::empty(nic.mac.clone());
+        let ipv6 = String::from("2001:db8::2/64");
+        let gateway = String::from("fe80::1%net0");
+        update.ipv6 = Some(ipv6.clone());
+        update.ipv6_gateway = Some(gateway.clone());
+        update.accept_rtadv = Some(true);
+        let nic = update.apply(nic);
+        assert_eq!(Some(ipv6), nic.ipv6);
+        assert_eq!(Some(gateway), nic.ipv6_gateway);
+        assert!(nic.accept_rtadv);
+    }
+
+    #[test]
+    fn validation() {
+        let config = Config::test(Box::new(RecordingExecutor::new()));
+        let mut current = conf();
+        current.nics = vec![nic00()];
+        assert!(JailUpdate::empty().offline_errors(&config, &current).is_none());
 
+        let mut update = JailUpdate::empty();
+        let mut added = nic01();
+        added.interface = String::from("net1");
+        update.add_nics = vec![added.clone()];
+        update.update_nics = vec![NICUpdate::empty(added.mac.clone())];
+        assert!(update.offline_errors(&config, &current).is_none());
+
+        let mut update = JailUpdate::empty();
+        let mut bad_ip = NICUpdate::empty(nic00().mac);
+        bad_ip.ip = Some(String::from("192.168.254.300"));
+        update.remove_nics = vec![String::from("00:00:00:00:00:09")];
+        update.update_nics = vec![NICUpdate::empty(String::from("00:00:00:00:00:09")), bad_ip];
+        update.remove_routes = vec![String::from("10.0.0.0/8")];
+        update.remove_filesystems = vec![String::from("/tmp")];
+        update.add_filesystems = vec![Filesystem {
+            source: String::from("/var/cache/pkg"),
+            target: String::from("/var/../../cache"),
+            fs_type: String::from("nullfs"),
+            options: vec![],
+        }];
+        let errors = update.offline_errors(&config, &current).unwrap();
+        assert_eq!(
+            vec![
+                ("remove_nics.0", "unknown"),
+                ("update_nics.0.mac", "unknown"),
+                ("remove_routes.0", "unknown"),
+                ("remove_filesystems.0", "unknown"),
+                ("nics.0.ip", "invalid"),
+                ("nics.0.gateway", "unreachable"),
+                ("filesystems.0.target", "invalid"),
+            ],
+            errors.iter().map(|e| (e.field(), e.code())).collect::<Vec<(&str, &str)>>()
+        );
+
+        // errors the jail already has don't block unrelated updates
+        current.hostname = String::from("not_valid");
+        let mut update = JailUpdate::empty();
+        update.alias = Some(String::from("renamed"));
+        assert!(update.offline_errors(&config, &current).is_none());
+        update.alias = Some(String::from("not_valid"));
+        let errors = update.offline_errors(&config, &current).unwrap();
+        assert_eq!(vec!["alias"], errors.iter().map(|e| e.field()).collect::<Vec<&str>>());
+    }
 }
 
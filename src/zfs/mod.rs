@@ -2,10 +2,11 @@
 //! Wrapper around zfs commands
 
 use std::error::Error;
-use std::process::{Command, Stdio};
 use errors::GenericError;
-use std::io::Read;
-use std::io::copy;
+use std::io::{Read, Write};
+use config::Config;
+
+static ZFS: &'static str = "zfs";
 
 
 #[derive(Debug)]
@@ -37,31 +38,43 @@ pub struct ZFSEntry {
 // }
 
 
-pub fn receive<R>(dataset: &str, mut reader: &mut R) -> Result<i32, Box<Error>>
+/// receives a zfs stream into a dataset
+pub fn receive<R>(config: &Config, dataset: &str, reader: &mut R) -> Result<i32, Box<Error>>
     where
     R: Read,
 {
-    let mut rec = Command::new("zfs")
-        .args(&["receive", dataset])
-        .stdin(Stdio::piped())
-        .spawn().unwrap();
-    let mut stdin = rec.stdin.take().unwrap();
-    copy(&mut reader, &mut stdin)?;
-    Ok(0)
+    debug!("Receiving ZFS dataset"; "dataset" => dataset);
+    let output = config.executor.pipe(ZFS, &["receive", dataset], reader)?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to receive dataset"))
+    }
+}
+
+/// sends a full zfs stream of a snapshot into a writer
+pub fn send<W>(config: &Config, snapshot: &str, writer: &mut W) -> Result<i32, Box<Error>>
+    where
+    W: Write,
+{
+    debug!("Sending ZFS snapshot"; "snapshot" => snapshot);
+    let status = config.executor.stream(ZFS, &["send", snapshot], writer)?;
+    if status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to send snapshot"))
+    }
 }
 
 /// checks weather a dataset exists or not
-pub fn is_present(dataset: &str) -> bool {
-    get(dataset).is_ok()
+pub fn is_present(config: &Config, dataset: &str) -> bool {
+    get(config, dataset).is_ok()
 }
 
 /// reads the zfs datasets in a pool
-fn get(dataset: &str) -> Result<ZFSEntry, Box<Error>> {
+fn get(config: &Config, dataset: &str) -> Result<ZFSEntry, Box<Error>> {
     debug!("Reading ZFS dataset"; "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["list", "-p", "-H", dataset])
-        .output()
-        .expect("zfs list failed");
+    let output = config.executor.query(ZFS, &["list", "-p", "-H", dataset])?;
     if output.status.success() {
         let reply = String::from_utf8_lossy(&output.stdout).to_string();
         deconstruct_entry(reply.as_str())
@@ -71,12 +84,9 @@ fn get(dataset: &str) -> Result<ZFSEntry, Box<Error>> {
 }
 
 /// reads the zfs datasets in a pool
-pub fn origin(dataset: &str) -> Result<String, Box<Error>> {
+pub fn origin(config: &Config, dataset: &str) -> Result<String, Box<Error>> {
     debug!("Fetching ZFS origin"; "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["get", "-p", "-H", "origin", dataset])
-        .output()
-        .expect("zfs list failed");
+    let output = config.executor.query(ZFS, &["get", "-p", "-H", "origin", dataset])?;
     if output.status.success() {
         let out = String::from_utf8_lossy(&output.stdout).to_string();
         let mut reply = out.split('\t');
@@ -95,22 +105,60 @@ pub fn origin(dataset: &str) -> Result<String, Box<Error>> {
     }
 }
 
-// /// create a zfs datasets in a pool
-// pub fn create(dataset: &str) -> Result<i32, Box<Error>> {
-//     debug!("Creating ZFS dataset"; "dataset" => dataset);
-//     let output = Command::new("zfs")
-//         .args(&["create", dataset])
-//         .output()
-//         .expect("zfs create failed");
-//     if output.status.success() {
-//         Ok(0)
-//     } else {
-//         Err(GenericError::bx("Failed create dataset"))
-//     }
-// }
+/// names of the datasets directly below a dataset
+pub fn children(config: &Config, dataset: &str) -> Result<Vec<String>, Box<Error>> {
+    debug!("Listing ZFS datasets"; "dataset" => dataset);
+    let names = list_names(config, &["list", "-H", "-o", "name", "-d", "1", dataset])?;
+    Ok(names.into_iter().filter(|n| n != dataset).collect())
+}
+
+/// names of all snapshots of a dataset and its children
+pub fn snapshots(config: &Config, dataset: &str) -> Result<Vec<String>, Box<Error>> {
+    debug!("Listing ZFS snapshots"; "dataset" => dataset);
+    list_names(config, &["list", "-H", "-o", "name", "-t", "snapshot", "-r", dataset])
+}
+
+fn list_names(config: &Config, args: &[&str]) -> Result<Vec<String>, Box<Error>> {
+    let output = config.executor.query(ZFS, args)?;
+    if output.status.success() {
+        let reply = String::from_utf8_lossy(&output.stdout);
+        Ok(reply.lines().filter(|l| !l.is_empty()).map(String::from).collect())
+    } else {
+        Err(GenericError::bx("Failed to list datasets"))
+    }
+}
+
+/// create a zfs datasets with properties like `jailed=on`
+pub fn create(config: &Config, dataset: &str, properties: &[&str]) -> Result<i32, Box<Error>> {
+    let mut args = vec!["create"];
+    for property in properties.iter() {
+        args.push("-o");
+        args.push(property);
+    }
+    args.push(dataset);
+    debug!("Creating ZFS dataset"; "dataset" => dataset, "args" => args.clone().join(" "));
+    let output = config.executor.output(ZFS, &args)?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed create dataset"))
+    }
+}
+
+/// attaches a dataset with `jailed=on` to a running jail
+pub fn jail(config: &Config, jid: u64, dataset: &str) -> Result<i32, Box<Error>> {
+    let jid = jid.to_string();
+    debug!("Attaching ZFS dataset to jail"; "dataset" => dataset, "jid" => jid.clone());
+    let output = config.executor.output(ZFS, &["jail", jid.as_str(), dataset])?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to attach dataset to jail"))
+    }
+}
 
 /// sets the quota in gigabyte for a dataset
-pub fn quota(dataset: &str, quota: u64) -> Result<u32, Box<Error>> {
+pub fn quota(config: &Config, dataset: &str, quota: u64) -> Result<u32, Box<Error>> {
     let dataset = String::from(dataset);
     let mut set = String::from("quota=");
     set.push_str(quota.to_string().as_str());
@@ -119,9 +167,7 @@ pub fn quota(dataset: &str, quota: u64) -> Result<u32, Box<Error>> {
     let args = vec!["set", set.as_str(), dataset.as_str()];
     debug!("Setting ZFS snapshot"; "dataset" => dataset.clone(), "quota" => set.clone(),
            "args" => args.clone().join(" "));
-    let output = Command::new("zfs").args(args).output().expect(
-        "zfs set failed",
-    );
+    let output = config.executor.output(ZFS, &args)?;
     if output.status.success() {
         Ok(0)
     } else {
@@ -129,17 +175,42 @@ pub fn quota(dataset: &str, quota: u64) -> Result<u32, Box<Error>> {
     }
 }
 
+/// sets properties like `compression=lz4` on a dataset
+pub fn set(config: &Config, dataset: &str, properties: &[String]) -> Result<i32, Box<Error>> {
+    let mut args = vec!["set"];
+    for property in properties.iter() {
+        args.push(property.as_str());
+    }
+    args.push(dataset);
+    debug!("Setting ZFS properties"; "dataset" => dataset, "args" => args.clone().join(" "));
+    let output = config.executor.output(ZFS, &args)?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed set properties"))
+    }
+}
+
+/// resets a property of a dataset to the value of its parent
+pub fn inherit(config: &Config, dataset: &str, property: &str) -> Result<i32, Box<Error>> {
+    debug!("Inheriting ZFS property"; "dataset" => dataset, "property" => property);
+    let output = config.executor.output(ZFS, &["inherit", property, dataset])?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed inherit property"))
+    }
+}
+
 /// create a zfs snapshot of a dataset
-pub fn snapshot(dataset: &str, snapshot: &str) -> Result<String, Box<Error>> {
+pub fn snapshot(config: &Config, dataset: &str, snapshot: &str) -> Result<String, Box<Error>> {
     let mut snap = String::from(dataset);
     snap.push('@');
     snap.push_str(snapshot);
     let args = vec!["snapshot", snap.as_str()];
     debug!("Creating ZFS snapshot"; "dataset" => dataset, "snapshot" => snapshot,
     "args" => args.clone().join(" "));
-    let output = Command::new("zfs").args(args).output().expect(
-        "zfs snapshot failed",
-    );
+    let output = config.executor.output(ZFS, &args)?;
     if output.status.success() {
         Ok(snap.clone())
     } else {
@@ -148,12 +219,9 @@ pub fn snapshot(dataset: &str, snapshot: &str) -> Result<String, Box<Error>> {
 }
 
 /// clones a zfs snapshot
-pub fn clone(snapshot: &str, dataset: &str) -> Result<i32, Box<Error>> {
+pub fn clone(config: &Config, snapshot: &str, dataset: &str) -> Result<i32, Box<Error>> {
     debug!("Cloning ZFS snapshot"; "snapshot" => snapshot, "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["clone", snapshot, dataset])
-        .output()
-        .expect("zfs clone failed");
+    let output = config.executor.output(ZFS, &["clone", snapshot, dataset])?;
     if output.status.success() {
         Ok(0)
     } else {
@@ -161,13 +229,21 @@ pub fn clone(snapshot: &str, dataset: &str) -> Result<i32, Box<Error>> {
     }
 }
 
+/// destroy a dataset with all its children and snapshots
+pub fn destroy_all(config: &Config, dataset: &str) -> Result<i32, Box<Error>> {
+    debug!("deleteing ZFS dataset recursively"; "dataset" => dataset);
+    let output = config.executor.output(ZFS, &["destroy", "-r", dataset])?;
+    if output.status.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed destroy dataset"))
+    }
+}
+
 /// destroy the zfs datasets in a pool
-pub fn destroy(dataset: &str) -> Result<i32, Box<Error>> {
+pub fn destroy(config: &Config, dataset: &str) -> Result<i32, Box<Error>> {
     debug!("deleteing ZFS dataset"; "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["destroy", dataset])
-        .output()
-        .expect("zfs destroy failed");
+    let output = config.executor.output(ZFS, &["destroy", dataset])?;
     if output.status.success() {
         Ok(0)
     } else {